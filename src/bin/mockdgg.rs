@@ -0,0 +1,442 @@
+// A stand-in for chat.destiny.gg that runs on localhost, for testing dggtui
+// without touching the real chat. Point the client at it with
+//
+//     dggtui --ws-url ws://127.0.0.1:8181/ws \
+//            --history-url http://127.0.0.1:8181/api/chat/history \
+//            --emotes-url http://127.0.0.1:8181/emotes/emotes.json \
+//            --flairs-url http://127.0.0.1:8181/flairs/flairs.json \
+//            --cdn-url http://127.0.0.1:8181
+
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
+use websocket_codec::{ClientRequest, Message, MessageCodec, Opcode, Result};
+
+const USAGE: &str = "Usage: mockdgg [options]
+
+Options:
+    --port <port>          Port to listen on (default 8181)
+    --script <file>        JSON lines of frames to play to each client
+    --record <file>        Append what clients send to this file instead of stdout
+    --nick <nick>          Nick the client is signed in as (default tester)
+    --reject <n>           Refuse the first n websocket connections
+    --reply-err <error>    Answer every MSG with ERR <error>, e.g. throttled
+    --ping-interval <s>    Seconds between websocket pings once the script is done
+    --pong-delay <ms>      Wait before answering pings, to fake latency or a dead link
+    -h, --help             Show this message
+
+Script lines look like
+    {\"delay_ms\": 500, \"frame\": \"MSG {\\\"nick\\\": \\\"Bob\\\", \\\"data\\\": \\\"hi\\\"}\"}
+    {\"delay_ms\": 1000, \"ping\": true}
+    {\"delay_ms\": 1000, \"close\": true}";
+
+const EMOTES_JSON: &str = r#"[{"prefix":"PEPE","twitch":false,"theme":0,"image":[{"url":"/emotes/PEPE.png","name":"PEPE.png","mime":"image/png","height":32,"width":32}]}]"#;
+const EMOTES_ETAG: &str = "\"emotes-1\"";
+const FLAIRS_JSON: &str = r##"[{"label":"Subscriber","name":"subscriber","hidden":false,"priority":10,"color":"#488ce7"},{"label":"Moderator","name":"moderator","hidden":false,"priority":2,"color":"#a8e76c"}]"##;
+
+#[derive(Debug)]
+struct Options {
+    port: u16,
+    script: Option<PathBuf>,
+    record: Option<PathBuf>,
+    nick: String,
+    reject: u32,
+    reply_err: Option<String>,
+    ping_interval: u64,
+    pong_delay: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            port: 8181,
+            script: None,
+            record: None,
+            nick: "tester".to_string(),
+            reject: 0,
+            reply_err: None,
+            ping_interval: 30,
+            pong_delay: 0,
+        }
+    }
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> std::result::Result<Options, String> {
+        let mut options = Options::default();
+
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Err(String::new());
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", arg))?;
+            let invalid = |_| format!("Invalid value for {}: {}", arg, value);
+
+            match arg.as_str() {
+                "--port" => options.port = value.parse().map_err(invalid)?,
+                "--script" => options.script = Some(PathBuf::from(value)),
+                "--record" => options.record = Some(PathBuf::from(value)),
+                "--nick" => options.nick = value,
+                "--reject" => options.reject = value.parse().map_err(invalid)?,
+                "--reply-err" => options.reply_err = Some(value),
+                "--ping-interval" => options.ping_interval = value.parse().map_err(invalid)?,
+                "--pong-delay" => options.pong_delay = value.parse().map_err(invalid)?,
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/// One step of the script played to every client that connects.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Step {
+    #[serde(default)]
+    delay_ms: u64,
+    frame: Option<String>,
+    #[serde(default)]
+    ping: bool,
+    #[serde(default)]
+    close: bool,
+}
+
+struct Server {
+    options: Options,
+    script: Vec<Step>,
+    rejected: AtomicU32,
+    record: Mutex<Option<File>>,
+}
+
+impl Server {
+    /// Writes down a frame a client sent us, as a JSON line with a timestamp.
+    fn record(&self, frame: &str) {
+        let line = serde_json::json!({ "timestamp": now(), "frame": frame }).to_string();
+        match &mut *self.record.lock().unwrap() {
+            Some(file) => {
+                let _ = writeln!(file, "{}", line);
+            }
+            None => println!("{}", line),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            if e.is_empty() {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let script = match &options.script {
+        Some(path) => load_script(path)?,
+        None => default_script(),
+    };
+    let record = match &options.record {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+
+    let listener = TcpListener::bind(("127.0.0.1", options.port)).await?;
+    eprintln!("mockdgg listening on 127.0.0.1:{}", options.port);
+
+    let server = Arc::new(Server {
+        options,
+        script,
+        rejected: AtomicU32::new(0),
+        record: Mutex::new(record),
+    });
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, server).await {
+                eprintln!("{}", e);
+            }
+        });
+    }
+}
+
+fn load_script(path: &PathBuf) -> Result<Vec<Step>> {
+    let mut steps = vec![];
+    for line in fs::read_to_string(path)?.lines() {
+        if !line.trim().is_empty() {
+            steps.push(serde_json::from_str(line)?);
+        }
+    }
+    Ok(steps)
+}
+
+fn default_script() -> Vec<Step> {
+    let frame = |delay_ms: u64, frame: String| Step {
+        delay_ms,
+        frame: Some(frame),
+        ..Default::default()
+    };
+    let ts = now();
+
+    vec![
+        frame(
+            0,
+            r#"NAMES {"connectioncount":3,"users":[{"nick":"Bob","features":["subscriber"]},{"nick":"Alice","features":["moderator"]}]}"#.to_string(),
+        ),
+        frame(500, format!(r#"JOIN {{"nick":"Carol","features":[],"timestamp":{}}}"#, ts)),
+        frame(500, format!(r#"MSG {{"nick":"Bob","features":["subscriber"],"timestamp":{},"data":"hello from mockdgg PEPE"}}"#, ts)),
+        frame(500, format!(r#"MSG {{"nick":"Alice","features":["moderator"],"timestamp":{},"data":"> greentext"}}"#, ts)),
+        frame(500, format!(r#"BROADCAST {{"timestamp":{},"data":"Stream is live"}}"#, ts)),
+        frame(500, format!(r#"SUBSCRIPTION {{"nick":"Bob","timestamp":{},"data":"love the stream","tier":2,"tierlabel":"Tier II","streak":3}}"#, ts)),
+        frame(500, format!(r#"DONATION {{"nick":"Alice","timestamp":{},"data":"","amount":500}}"#, ts)),
+        frame(500, format!(r#"PRIVMSG {{"messageid":1,"nick":"Carol","timestamp":{},"data":"psst"}}"#, ts)),
+        frame(500, format!(r#"QUIT {{"nick":"Carol","features":[],"timestamp":{}}}"#, ts)),
+        frame(500, r#"ERR "throttled""#.to_string()),
+        Step {
+            delay_ms: 500,
+            ping: true,
+            ..Default::default()
+        },
+    ]
+}
+
+async fn handle_connection(mut stream: TcpStream, server: Arc<Server>) -> Result<()> {
+    let head = read_request_head(&mut stream).await?;
+    let mut lines = head.lines();
+    let path = lines
+        .next()
+        .and_then(|request_line| request_line.split(' ').nth(1))
+        .unwrap_or("/")
+        .to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let header = |name: &'static str| {
+        headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+
+    if header("Upgrade").is_none() {
+        return serve_http(&mut stream, &path, header("If-None-Match")).await;
+    }
+
+    if server.rejected.fetch_add(1, Ordering::SeqCst) < server.options.reject {
+        stream
+            .write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n")
+            .await?;
+        return Ok(());
+    }
+
+    let request = ClientRequest::parse(header)?;
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        request.ws_accept()
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    run_session(Framed::new(stream, MessageCodec::server()), server).await
+}
+
+async fn read_request_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = vec![];
+    let mut byte = [0u8; 1];
+
+    // Byte by byte so nothing after the head (websocket frames) is consumed.
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).await? == 0 {
+            return Err("Connection closed during request".into());
+        }
+        head.push(byte[0]);
+    }
+
+    Ok(String::from_utf8_lossy(&head).to_string())
+}
+
+async fn serve_http(stream: &mut TcpStream, path: &str, etag: Option<&str>) -> Result<()> {
+    let (status, content_type, body) = match path {
+        "/api/chat/history" => ("200 OK", "application/json", history().into_bytes()),
+        "/emotes/emotes.json" if etag == Some(EMOTES_ETAG) => {
+            ("304 Not Modified", "application/json", vec![])
+        }
+        "/emotes/emotes.json" => ("200 OK", "application/json", EMOTES_JSON.into()),
+        "/emotes/PEPE.png" => ("200 OK", "image/png", emote_image()),
+        "/flairs/flairs.json" => ("200 OK", "application/json", FLAIRS_JSON.into()),
+        _ => ("404 Not Found", "text/plain", vec![]),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    if path == "/emotes/emotes.json" {
+        response.push_str(&format!("ETag: {}\r\n", EMOTES_ETAG));
+    }
+    response.push_str("\r\n");
+    stream.write_all(response.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    Ok(())
+}
+
+/// A green square for PEPE.
+fn emote_image() -> Vec<u8> {
+    let image = image::RgbaImage::from_pixel(32, 32, image::Rgba([0, 160, 0, 255]));
+    let mut bytes = vec![];
+    image::DynamicImage::ImageRgba8(image)
+        .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+        .unwrap();
+    bytes
+}
+
+fn history() -> String {
+    let frames: Vec<String> = (1..=3)
+        .map(|i| {
+            format!(
+                r#"MSG {{"nick":"Bob","features":[],"timestamp":{},"data":"history message {}"}}"#,
+                now(),
+                i
+            )
+        })
+        .collect();
+    serde_json::to_string(&frames).unwrap()
+}
+
+async fn run_session(framed: Framed<TcpStream, MessageCodec>, server: Arc<Server>) -> Result<()> {
+    let (mut sink, mut stream) = framed.split();
+    let (out_tx, mut out_rx) = mpsc::channel::<Message>(64);
+
+    let writer = tokio::spawn(async move {
+        while let Some(message) = out_rx.recv().await {
+            let close = message.opcode() == Opcode::Close;
+            if sink.send(message).await.is_err() || close {
+                break;
+            }
+        }
+    });
+
+    let script_tx = out_tx.clone();
+    let script_server = server.clone();
+    let player = tokio::spawn(async move {
+        // Like the real server, say who the client is signed in as first.
+        let me = serde_json::json!({ "nick": script_server.options.nick, "features": [] });
+        if script_tx
+            .send(Message::text(format!("ME {}", me)))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        for step in script_server.script.iter() {
+            tokio::time::sleep(Duration::from_millis(step.delay_ms)).await;
+            let message = if step.close {
+                Message::close(None)
+            } else if step.ping {
+                Message::ping(now().to_string())
+            } else if let Some(frame) = &step.frame {
+                Message::text(frame.to_owned())
+            } else {
+                continue;
+            };
+            if script_tx.send(message).await.is_err() {
+                return;
+            }
+        }
+
+        let interval = Duration::from_secs(script_server.options.ping_interval.max(1));
+        loop {
+            tokio::time::sleep(interval).await;
+            if script_tx
+                .send(Message::ping(now().to_string()))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+
+    while let Some(message) = stream.next().await {
+        let message = message?;
+        match message.opcode() {
+            Opcode::Text => {
+                let frame = message.as_text().unwrap_or_default();
+                server.record(frame);
+                if let Some(reply) = reply_to(frame, &server.options) {
+                    let _ = out_tx.send(Message::text(reply)).await;
+                }
+            }
+            Opcode::Ping => {
+                let out_tx = out_tx.clone();
+                let delay = Duration::from_millis(server.options.pong_delay);
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = out_tx.send(Message::pong(message.into_data())).await;
+                });
+            }
+            Opcode::Close => break,
+            _ => {}
+        }
+    }
+
+    player.abort();
+    writer.abort();
+    Ok(())
+}
+
+/// What the real server would answer to a frame from the client.
+fn reply_to(frame: &str, options: &Options) -> Option<String> {
+    let (command, payload) = frame.split_once(' ').unwrap_or((frame, ""));
+    let payload: serde_json::Value = match serde_json::from_str(payload) {
+        Ok(payload) => payload,
+        Err(_) => return Some(r#"ERR "protocolerror""#.to_string()),
+    };
+    let data = payload["data"].as_str().unwrap_or_default();
+
+    let event = |command: &str, data: &str| {
+        let json = serde_json::json!({
+            "nick": options.nick,
+            "features": [],
+            "timestamp": now(),
+            "data": data,
+        });
+        format!("{} {}", command, json)
+    };
+
+    match command {
+        "MSG" => match &options.reply_err {
+            Some(error) => Some(format!("ERR {}", serde_json::json!(error))),
+            None => Some(event("MSG", data)),
+        },
+        "PRIVMSG" => Some(format!(r#"PRIVMSGSENT {{"timestamp":{}}}"#, now())),
+        "MUTE" | "UNMUTE" | "UNBAN" | "SUBONLY" => Some(event(command, data)),
+        "BAN" => Some(event("BAN", payload["nick"].as_str().unwrap_or_default())),
+        _ => Some(r#"ERR "protocolerror""#.to_string()),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
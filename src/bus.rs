@@ -0,0 +1,88 @@
+use std::cell::Cell;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::connection::ConnectionState;
+use crate::protocol::{Command, Event};
+
+/// Frames that can be waiting for the UI before the websocket task stops reading.
+pub const INBOUND_CAPACITY: usize = 1024;
+/// Commands that can be waiting to be sent.
+pub const OUTBOUND_CAPACITY: usize = 64;
+
+#[derive(Debug)]
+pub enum BusEvent {
+    Chat(Event),
+    Connection(ConnectionState),
+    /// Round trip time of the last ping.
+    Latency(Duration),
+    Error(String),
+    /// Commands waiting for their turn, and sent ones awaiting an answer.
+    Outbox {
+        queued: usize,
+        pending: usize,
+    },
+    /// The server echoed the message, or confirmed the whisper, with this id.
+    Delivered(u64, Event),
+    /// A command the server refused or that was lost with the connection, with why.
+    SendFailed(Outgoing, String),
+}
+
+/// A command on its way to the server, numbered so replies can be matched to it.
+#[derive(Debug, Clone)]
+pub struct Outgoing {
+    pub id: u64,
+    pub command: Command,
+}
+
+/// The UI's end of the bus.
+pub struct UiBus {
+    pub events: mpsc::Receiver<BusEvent>,
+    pub commands: mpsc::Sender<Outgoing>,
+    next_id: Cell<u64>,
+}
+
+/// The websocket task's end of the bus.
+pub struct NetBus {
+    pub events: mpsc::Sender<BusEvent>,
+    pub commands: mpsc::Receiver<Outgoing>,
+}
+
+/// Creates the bus between the websocket task and the UI loop.
+///
+/// Both directions are bounded queues, so nothing gets overwritten. When the UI
+/// falls behind, the websocket task waits for room before reading the next
+/// frame, so chat is delayed rather than dropped. When the outbound queue is
+/// full the UI is told straight away and the command is not sent, since
+/// blocking the UI loop would freeze the terminal.
+pub fn channel() -> (UiBus, NetBus) {
+    let (event_tx, event_rx) = mpsc::channel(INBOUND_CAPACITY);
+    let (command_tx, command_rx) = mpsc::channel(OUTBOUND_CAPACITY);
+
+    (
+        UiBus {
+            events: event_rx,
+            commands: command_tx,
+            next_id: Cell::new(0),
+        },
+        NetBus {
+            events: event_tx,
+            commands: command_rx,
+        },
+    )
+}
+
+impl UiBus {
+    /// Queues a command for the websocket task without blocking, returning its id.
+    pub fn send(&self, command: Command) -> Result<u64, String> {
+        let id = self.next_id.get() + 1;
+        self.commands
+            .try_send(Outgoing { id, command })
+            .map_err(|e| match e {
+                TrySendError::Full(_) => "Send queue is full, message was not sent".to_string(),
+                TrySendError::Closed(_) => "Not connected, message was not sent".to_string(),
+            })?;
+        self.next_id.set(id);
+        Ok(id)
+    }
+}
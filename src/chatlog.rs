@@ -0,0 +1,559 @@
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use crate::config::{self, LogConfig, ScrollbackConfig};
+use crate::protocol::{Event, PrivateMessage};
+use crate::types::ChatLine;
+use crate::utils;
+use crate::whispers;
+
+const MILLIS_PER_HOUR: u64 = 60 * 60 * 1000;
+const MILLIS_PER_DAY: u64 = 24 * MILLIS_PER_HOUR;
+
+/// The line the way it's written to a log file, or None for lines that only
+/// make sense on screen.
+pub fn log_line(line: &ChatLine) -> Option<String> {
+    match line {
+        ChatLine::Event(event) => event_line(event),
+        ChatLine::Filtered(message, _) => event_line(&Event::Msg(message.to_owned())),
+        _ => None,
+    }
+}
+
+/// A received event as a line of text, or None for ones not worth reading back.
+pub fn event_line(event: &Event) -> Option<String> {
+    let time = utils::format_time(event.timestamp()?);
+
+    let text = match event {
+        Event::Msg(msg) => return Some(format!("[{}] <{}> {}", time, msg.nick, msg.data)),
+        Event::Mute(mute) => format!("{} muted {}", mute.nick, mute.data),
+        Event::Unmute(unmute) => format!("{} unmuted {}", unmute.nick, unmute.data),
+        Event::Ban(ban) => format!("{} banned {}", ban.nick, ban.data),
+        Event::Unban(unban) => format!("{} unbanned {}", unban.nick, unban.data),
+        Event::SubOnly(subonly) => format!(
+            "{} turned subscriber only mode {}",
+            subonly.nick, subonly.data
+        ),
+        _ => utils::banner(event)?,
+    };
+    Some(format!("[{}] *** {}", time, text))
+}
+
+/// Saves the chat as plain text under the data directory and returns where it went.
+pub fn export(lines: &[ChatLine]) -> std::io::Result<PathBuf> {
+    let mut path = config::data_folder("exports")?;
+    path.push(format!("chat-{}.txt", utils::timestamp()));
+
+    let contents: Vec<String> = lines.iter().filter_map(log_line).collect();
+    fs::write(&path, contents.join("\n") + "\n")?;
+    Ok(path)
+}
+
+enum Entry {
+    Frame {
+        received: u64,
+        frame: String,
+        event: Event,
+    },
+    Whisper {
+        partner: String,
+        message: PrivateMessage,
+    },
+}
+
+/// Hands what we receive to a thread that writes it to disk, so the websocket
+/// task never waits on the disk.
+///
+/// Whispers are always saved to a file per partner under `whispers/`, which is
+/// where their tabs load history from. With logging on, chat also goes to
+/// `logs/chat-YYYY-MM-DD.txt` and `.jsonl`, a pair per day (UTC), and whispers
+/// get a readable `.txt` copy.
+#[derive(Clone)]
+pub struct Logger {
+    entries: mpsc::Sender<Entry>,
+    /// Whether chat is logged, not just whispers
+    logging: bool,
+}
+
+impl Logger {
+    /// Starts the writing thread. Chat is only logged when logging is on and
+    /// there's somewhere to write.
+    pub fn spawn(config: &LogConfig) -> Logger {
+        let folder = if config.enabled {
+            config::data_folder("logs").ok()
+        } else {
+            None
+        };
+        let logging = folder.is_some();
+        let retention_days = config.retention_days;
+        let (entries, rx) = mpsc::channel();
+
+        thread::spawn(move || write_entries(rx, folder, retention_days));
+        Logger { entries, logging }
+    }
+
+    /// A frame as it came from the server, and the event it was parsed into.
+    pub fn frame(&self, frame: &str, event: &Event) {
+        if !self.logging && !matches!(event, Event::PrivMsg(_)) {
+            return;
+        }
+        let _ = self.entries.send(Entry::Frame {
+            received: utils::timestamp(),
+            frame: frame.to_string(),
+            event: event.to_owned(),
+        });
+    }
+
+    /// A whisper the server confirmed we sent to `partner`.
+    pub fn whisper(&self, partner: &str, message: PrivateMessage) {
+        let _ = self.entries.send(Entry::Whisper {
+            partner: partner.to_string(),
+            message,
+        });
+    }
+}
+
+fn write_entries(
+    entries: mpsc::Receiver<Entry>,
+    folder: Option<PathBuf>,
+    retention_days: Option<u32>,
+) {
+    let mut pruned_on = String::new();
+    let text_copy = folder.is_some();
+
+    for entry in entries {
+        match entry {
+            Entry::Frame {
+                received,
+                frame,
+                event,
+            } => {
+                if let Event::PrivMsg(message) = event {
+                    let partner = message.nick.to_owned();
+                    save_whisper(&partner, &message, text_copy);
+                    continue;
+                }
+                let folder = match &folder {
+                    Some(folder) => folder,
+                    None => continue,
+                };
+
+                let date = utils::format_date(received);
+                if date != pruned_on {
+                    if let Some(days) = retention_days {
+                        prune(folder, received, days);
+                    }
+                    pruned_on = date.to_owned();
+                }
+
+                if matches!(event, Event::Names(_) | Event::Me(_) | Event::Unknown) {
+                    continue;
+                }
+
+                let (command, payload) = frame.split_once(' ').unwrap_or((&frame, ""));
+                let record = json!({
+                    "received": received,
+                    "type": command,
+                    "payload": serde_json::from_str::<Value>(payload).unwrap_or(Value::Null),
+                });
+                append(
+                    &folder.join(format!("chat-{}.jsonl", date)),
+                    &record.to_string(),
+                );
+                if let Some(line) = event_line(&event) {
+                    append(&folder.join(format!("chat-{}.txt", date)), &line);
+                }
+            }
+            Entry::Whisper { partner, message } => save_whisper(&partner, &message, text_copy),
+        }
+    }
+}
+
+fn save_whisper(partner: &str, message: &PrivateMessage, text_copy: bool) {
+    let _ = whispers::save(partner, message);
+    if !text_copy {
+        return;
+    }
+    if let Ok(path) = whispers::text_path(partner) {
+        let line = format!(
+            "[{} {}] <{}> {}",
+            utils::format_date(message.timestamp),
+            utils::format_time(message.timestamp),
+            message.nick,
+            message.data
+        );
+        append(&path, &line);
+    }
+}
+
+fn append(path: &Path, line: &str) {
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        let _ = writeln!(file, "{}", line);
+    }
+}
+
+/// Deletes daily logs from before the last `days` days.
+fn prune(folder: &Path, now: u64, days: u32) {
+    let cutoff = format!(
+        "chat-{}",
+        utils::format_date(now.saturating_sub(days as u64 * MILLIS_PER_DAY))
+    );
+    let files = match fs::read_dir(folder) {
+        Ok(files) => files,
+        Err(_) => return,
+    };
+
+    for file in files.flatten() {
+        let name = file.file_name().to_string_lossy().to_string();
+        // Names sort by date, so anything before the cutoff is too old.
+        if name.starts_with("chat-") && name.len() >= cutoff.len() && name[..cutoff.len()] < *cutoff
+        {
+            let _ = fs::remove_file(file.path());
+        }
+    }
+}
+
+/// The structured daily logs, oldest first.
+pub fn daily_logs() -> Vec<PathBuf> {
+    let folder = match config::data_folder("logs") {
+        Ok(folder) => folder,
+        Err(_) => return vec![],
+    };
+    let mut files: Vec<PathBuf> = match fs::read_dir(folder) {
+        Ok(files) => files
+            .flatten()
+            .map(|file| file.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "jsonl"))
+            .collect(),
+        Err(_) => return vec![],
+    };
+    files.sort();
+    files
+}
+
+/// Events from earlier sessions' logs that `keep` accepts, oldest first, limited
+/// to what the scrollback config asks for.
+pub fn backfill(config: &ScrollbackConfig, keep: impl Fn(&Event) -> bool) -> Vec<Event> {
+    read_back(&daily_logs(), config, utils::timestamp(), keep)
+}
+
+/// What `backfill` takes from `files` at the time `now`.
+fn read_back(
+    files: &[PathBuf],
+    config: &ScrollbackConfig,
+    now: u64,
+    keep: impl Fn(&Event) -> bool,
+) -> Vec<Event> {
+    let cutoff = config
+        .hours
+        .map(|hours| now.saturating_sub(hours * MILLIS_PER_HOUR));
+    let mut events = vec![];
+
+    // Newest file first, reading each from the end, until we have enough.
+    'files: for path in files.iter().rev() {
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        for line in contents.lines().rev() {
+            if events.len() >= config.messages {
+                break 'files;
+            }
+            let record: Value = match serde_json::from_str(line) {
+                Ok(record) => record,
+                Err(_) => continue,
+            };
+            let received = record["received"].as_u64().unwrap_or_default();
+            if cutoff.is_some_and(|cutoff| received < cutoff) {
+                break 'files;
+            }
+            let frame = format!(
+                "{} {}",
+                record["type"].as_str().unwrap_or_default(),
+                record["payload"]
+            );
+            let event = Event::parse(&frame);
+            if keep(&event) {
+                events.push(event);
+            }
+        }
+    }
+
+    events.reverse();
+    events
+}
+
+/// Puts the logged events and the server's history in one list, in order and
+/// without the messages both have, with a separator after the last logged one.
+pub fn merge(logged: Vec<Event>, history: Vec<Event>) -> Vec<ChatLine> {
+    let key = |event: &Event| event_line(event).map(|line| (event.timestamp(), line));
+    let seen: HashSet<_> = logged.iter().filter_map(key).collect();
+    let last_logged = logged.last().and_then(|event| event.timestamp());
+
+    let mut events: Vec<(bool, Event)> = logged.into_iter().map(|event| (true, event)).collect();
+    events.extend(
+        history
+            .into_iter()
+            .filter(|event| key(event).is_none_or(|key| !seen.contains(&key)))
+            .map(|event| (false, event)),
+    );
+    events.sort_by_key(|(_, event)| event.timestamp().unwrap_or_default());
+
+    let separator_at = events.iter().rposition(|(logged, _)| *logged);
+    let mut lines = vec![];
+    for (i, (_, event)) in events.into_iter().enumerate() {
+        lines.push(ChatLine::Event(event));
+        if Some(i) == separator_at {
+            lines.push(ChatLine::Separator(last_logged.unwrap_or_default()));
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01 00:00 UTC
+    const NEW_YEAR: u64 = 1_704_067_200_000;
+
+    fn temp_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("dggtui-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    fn msg_frame(timestamp: u64, data: &str) -> String {
+        format!(
+            r#"MSG {{"nick":"alice","features":[],"timestamp":{},"data":"{}"}}"#,
+            timestamp, data
+        )
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn write(folder: &Path, frames: &[(u64, String)], retention_days: Option<u32>) {
+        let (entries, rx) = mpsc::channel();
+        for (received, frame) in frames {
+            entries
+                .send(Entry::Frame {
+                    received: *received,
+                    frame: frame.to_owned(),
+                    event: Event::parse(frame),
+                })
+                .unwrap();
+        }
+        drop(entries);
+        write_entries(rx, Some(folder.to_path_buf()), retention_days);
+    }
+
+    #[test]
+    fn logs_rotate_at_midnight_utc() {
+        let folder = temp_folder("rotation");
+        let before = NEW_YEAR - 60_000;
+        let after = NEW_YEAR + 60_000;
+        write(
+            &folder,
+            &[
+                (before, msg_frame(before, "last of the year")),
+                (
+                    before,
+                    r#"NAMES {"connectioncount":0,"users":[]}"#.to_string(),
+                ),
+                (after, msg_frame(after, "first of the year")),
+            ],
+            None,
+        );
+
+        let old = lines(&folder.join("chat-2023-12-31.jsonl"));
+        assert_eq!(old.len(), 1);
+        let record: Value = serde_json::from_str(&old[0]).unwrap();
+        assert_eq!(record["type"], "MSG");
+        assert_eq!(record["received"], before);
+        assert_eq!(record["payload"]["data"], "last of the year");
+        assert_eq!(
+            lines(&folder.join("chat-2023-12-31.txt")),
+            ["[23:59:00] <alice> last of the year"]
+        );
+
+        assert_eq!(lines(&folder.join("chat-2024-01-01.jsonl")).len(), 1);
+        assert_eq!(
+            lines(&folder.join("chat-2024-01-01.txt")),
+            ["[00:01:00] <alice> first of the year"]
+        );
+        let _ = fs::remove_dir_all(&folder);
+    }
+
+    #[test]
+    fn logs_older_than_the_retention_are_pruned() {
+        let folder = temp_folder("prune");
+        for name in [
+            "chat-2023-12-20.jsonl",
+            "chat-2023-12-20.txt",
+            "chat-2023-12-26.jsonl",
+            "chat-2023-12-27.txt",
+            "notes.txt",
+        ] {
+            fs::write(folder.join(name), "").unwrap();
+        }
+
+        // The first entry of a day prunes, keeping the last 5 days.
+        write(&folder, &[(NEW_YEAR, msg_frame(NEW_YEAR, "hi"))], Some(5));
+
+        let mut left: Vec<String> = fs::read_dir(&folder)
+            .unwrap()
+            .flatten()
+            .map(|file| file.file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                "chat-2023-12-27.txt",
+                "chat-2024-01-01.jsonl",
+                "chat-2024-01-01.txt",
+                "notes.txt"
+            ]
+        );
+        let _ = fs::remove_dir_all(&folder);
+    }
+
+    fn msg(timestamp: u64, data: &str) -> Event {
+        Event::parse(&msg_frame(timestamp, data))
+    }
+
+    fn describe(lines: &[ChatLine]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| match line {
+                ChatLine::Event(Event::Msg(msg)) => msg.data.to_owned(),
+                ChatLine::Separator(timestamp) => format!("--- {}", timestamp),
+                _ => "?".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merge_drops_what_the_log_and_history_share() {
+        let logged = vec![msg(1_000, "one"), msg(2_000, "two"), msg(3_000, "three")];
+        let history = vec![
+            msg(500, "before"),
+            msg(2_000, "two"),
+            msg(3_000, "three"),
+            msg(4_000, "four"),
+            // Same time, different message: it's new, so after the separator.
+            msg(3_000, "three too"),
+        ];
+        assert_eq!(
+            describe(&merge(logged, history)),
+            [
+                "before",
+                "one",
+                "two",
+                "three",
+                "--- 3000",
+                "three too",
+                "four"
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_puts_the_separator_after_the_last_logged_event() {
+        // Nothing new since the log: the separator goes last.
+        let lines = merge(vec![msg(1_000, "one")], vec![msg(1_000, "one")]);
+        assert_eq!(describe(&lines), ["one", "--- 1000"]);
+
+        // Nothing logged: no separator.
+        let lines = merge(vec![], vec![msg(1_000, "one")]);
+        assert_eq!(describe(&lines), ["one"]);
+    }
+
+    fn log_file(folder: &Path, name: &str, events: &[(u64, &str)]) -> PathBuf {
+        let path = folder.join(name);
+        let records: Vec<String> = events
+            .iter()
+            .map(|(received, data)| {
+                let frame = msg_frame(*received, data);
+                let (command, payload) = frame.split_once(' ').unwrap();
+                json!({
+                    "received": received,
+                    "type": command,
+                    "payload": serde_json::from_str::<Value>(payload).unwrap(),
+                })
+                .to_string()
+            })
+            .collect();
+        fs::write(&path, records.join("\n") + "\nnot json\n").unwrap();
+        path
+    }
+
+    #[test]
+    fn backfill_reads_the_newest_logs_back_in_order() {
+        let folder = temp_folder("backfill");
+        let hour = MILLIS_PER_HOUR;
+        let files = [
+            log_file(
+                &folder,
+                "chat-2023-12-31.jsonl",
+                &[(NEW_YEAR - 3 * hour, "old"), (NEW_YEAR - hour, "late")],
+            ),
+            log_file(
+                &folder,
+                "chat-2024-01-01.jsonl",
+                &[(NEW_YEAR + hour, "early"), (NEW_YEAR + 2 * hour, "skip me")],
+            ),
+        ];
+        let now = NEW_YEAR + 3 * hour;
+        let keep = |event: &Event| !matches!(event, Event::Msg(msg) if msg.data == "skip me");
+        let data = |events: Vec<Event>| -> Vec<String> {
+            events
+                .into_iter()
+                .filter_map(|event| match event {
+                    Event::Msg(msg) => Some(msg.data),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let config = ScrollbackConfig {
+            messages: 10,
+            hours: None,
+            ..Default::default()
+        };
+        assert_eq!(
+            data(read_back(&files, &config, now, keep)),
+            ["old", "late", "early"]
+        );
+
+        let config = ScrollbackConfig {
+            messages: 2,
+            hours: None,
+            ..Default::default()
+        };
+        assert_eq!(
+            data(read_back(&files, &config, now, keep)),
+            ["late", "early"]
+        );
+
+        let config = ScrollbackConfig {
+            messages: 10,
+            hours: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(
+            data(read_back(&files, &config, now, keep)),
+            ["late", "early"]
+        );
+        let _ = fs::remove_dir_all(&folder);
+    }
+}
@@ -0,0 +1,212 @@
+use std::path::PathBuf;
+
+use crate::config::Config;
+
+pub const USAGE: &str = "Usage: dggtui [options]
+
+Options:
+    --ws-url <url>         Chat websocket, e.g. ws://localhost:8080/ws
+    --history-url <url>    Chat history endpoint
+    --emotes-url <url>     emotes.json location
+    --flairs-url <url>     flairs.json location
+    --cdn-url <url>        Base URL emote and flair images are served from
+    --record <file>        Write every websocket frame to a JSON lines file
+    --replay <file>        Play a recorded session instead of connecting
+    --speed <n>            Replay n times faster, 0.01 to 1000 (default 1)
+    -h, --help             Show this message";
+
+/// Replay speeds outside of this range stall the replay or overflow its timers.
+const SPEEDS: std::ops::RangeInclusive<f64> = 0.01..=1000.0;
+
+/// Command line overrides, applied on top of the config file.
+#[derive(Debug, Default)]
+pub struct Args {
+    pub help: bool,
+    pub websocket_url: Option<String>,
+    pub history_url: Option<String>,
+    pub emotes_url: Option<String>,
+    pub flairs_url: Option<String>,
+    pub cdn_url: Option<String>,
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub speed: Option<f64>,
+}
+
+impl Args {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+        let mut parsed = Args::default();
+
+        while let Some(arg) = args.next() {
+            let target = match arg.as_str() {
+                "-h" | "--help" => {
+                    parsed.help = true;
+                    continue;
+                }
+                "--ws-url" => &mut parsed.websocket_url,
+                "--history-url" => &mut parsed.history_url,
+                "--emotes-url" => &mut parsed.emotes_url,
+                "--flairs-url" => &mut parsed.flairs_url,
+                "--cdn-url" => &mut parsed.cdn_url,
+                "--record" | "--replay" | "--speed" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("{} needs a value", arg))?;
+                    match arg.as_str() {
+                        "--record" => parsed.record = Some(PathBuf::from(value)),
+                        "--replay" => parsed.replay = Some(PathBuf::from(value)),
+                        _ => match value.parse::<f64>() {
+                            Ok(speed) if SPEEDS.contains(&speed) => parsed.speed = Some(speed),
+                            _ => return Err(format!("Invalid speed {}", value)),
+                        },
+                    }
+                    continue;
+                }
+                _ => return Err(format!("Unknown argument {}", arg)),
+            };
+
+            match args.next() {
+                Some(value) => *target = Some(value),
+                None => return Err(format!("{} needs a value", arg)),
+            }
+        }
+
+        if parsed.speed.is_some() && parsed.replay.is_none() {
+            return Err("--speed only makes sense with --replay".to_string());
+        }
+
+        Ok(parsed)
+    }
+
+    pub fn apply(&self, mut config: Config) -> Config {
+        let server = &mut config.server;
+        let overrides = [
+            (&self.websocket_url, &mut server.websocket_url),
+            (&self.history_url, &mut server.history_url),
+            (&self.emotes_url, &mut server.emotes_url),
+            (&self.flairs_url, &mut server.flairs_url),
+            (&self.cdn_url, &mut server.cdn_url),
+        ];
+
+        for (value, target) in overrides {
+            if let Some(value) = value {
+                *target = value.to_owned();
+            }
+        }
+
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn speed_has_to_be_sensible() {
+        let args = parse(&["--replay", "session.jsonl", "--speed", "2.5"]).unwrap();
+        assert_eq!(args.speed, Some(2.5));
+
+        for speed in ["0", "-1", "1e-300", "1e300", "NaN", "inf", "fast"] {
+            assert!(
+                parse(&["--replay", "session.jsonl", "--speed", speed]).is_err(),
+                "{} should be rejected",
+                speed
+            );
+        }
+    }
+
+    #[test]
+    fn parses_every_flag() {
+        let args = parse(&[
+            "--ws-url",
+            "ws://localhost:8080/ws",
+            "--history-url",
+            "http://localhost:8080/history",
+            "--emotes-url",
+            "http://localhost:8080/emotes.json",
+            "--flairs-url",
+            "http://localhost:8080/flairs.json",
+            "--cdn-url",
+            "http://localhost:8080",
+            "--record",
+            "out.jsonl",
+            "--replay",
+            "in.jsonl",
+            "--speed",
+            "4",
+        ])
+        .unwrap();
+        assert!(!args.help);
+        assert_eq!(
+            args.websocket_url.as_deref(),
+            Some("ws://localhost:8080/ws")
+        );
+        assert_eq!(
+            args.history_url.as_deref(),
+            Some("http://localhost:8080/history")
+        );
+        assert_eq!(
+            args.emotes_url.as_deref(),
+            Some("http://localhost:8080/emotes.json")
+        );
+        assert_eq!(
+            args.flairs_url.as_deref(),
+            Some("http://localhost:8080/flairs.json")
+        );
+        assert_eq!(args.cdn_url.as_deref(), Some("http://localhost:8080"));
+        assert_eq!(args.record, Some(PathBuf::from("out.jsonl")));
+        assert_eq!(args.replay, Some(PathBuf::from("in.jsonl")));
+        assert_eq!(args.speed, Some(4.0));
+
+        assert!(parse(&["-h"]).unwrap().help);
+        assert!(parse(&["--help"]).unwrap().help);
+        let none = parse(&[]).unwrap();
+        assert!(none.websocket_url.is_none() && none.replay.is_none() && none.speed.is_none());
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(
+            parse(&["--verbose"]).unwrap_err(),
+            "Unknown argument --verbose"
+        );
+        assert_eq!(parse(&["--ws-url"]).unwrap_err(), "--ws-url needs a value");
+        assert_eq!(parse(&["--replay"]).unwrap_err(), "--replay needs a value");
+        assert_eq!(
+            parse(&["--speed", "2"]).unwrap_err(),
+            "--speed only makes sense with --replay"
+        );
+    }
+
+    #[test]
+    fn cli_beats_config_beats_defaults() {
+        let file = ::config::File::from_str(
+            r#"
+            [server]
+            websocket_url = "ws://config/ws"
+            history_url = "http://config/history"
+            "#,
+            ::config::FileFormat::Toml,
+        );
+        let config: Config = ::config::Config::builder()
+            .add_source(file)
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .unwrap();
+        let defaults = Config::default().server;
+        assert_eq!(config.server.websocket_url, "ws://config/ws");
+        assert_eq!(config.server.emotes_url, defaults.emotes_url);
+
+        let args = parse(&["--ws-url", "ws://cli/ws", "--cdn-url", "http://cli"]).unwrap();
+        let server = args.apply(config).server;
+        assert_eq!(server.websocket_url, "ws://cli/ws");
+        assert_eq!(server.history_url, "http://config/history");
+        assert_eq!(server.cdn_url, "http://cli");
+        assert_eq!(server.emotes_url, defaults.emotes_url);
+        assert_eq!(server.flairs_url, defaults.flairs_url);
+    }
+}
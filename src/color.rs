@@ -0,0 +1,195 @@
+use std::env;
+use std::sync::OnceLock;
+use tui::style::Color;
+
+/// How many colors the terminal can show.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSupport {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+    /// `NO_COLOR` is set
+    NoColor,
+}
+
+impl ColorSupport {
+    /// Guesses from `NO_COLOR`, `COLORTERM` and `TERM`, once per run.
+    pub fn detect() -> ColorSupport {
+        static SUPPORT: OnceLock<ColorSupport> = OnceLock::new();
+        *SUPPORT.get_or_init(|| {
+            let colorterm = env::var("COLORTERM").unwrap_or_default();
+            let term = env::var("TERM").unwrap_or_default();
+            if env::var("NO_COLOR").is_ok_and(|value| !value.is_empty()) {
+                ColorSupport::NoColor
+            } else if colorterm == "truecolor" || colorterm == "24bit" {
+                ColorSupport::TrueColor
+            } else if term.contains("256color") {
+                ColorSupport::Ansi256
+            } else {
+                ColorSupport::Ansi16
+            }
+        })
+    }
+}
+
+/// The 16 basic colors with the RGB values terminals usually give them.
+const ANSI_16: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+/// Levels of each channel in the 6x6x6 cube of the 256 color palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// Turns a true color into the closest one the terminal can show, or no color
+/// at all for `NO_COLOR`.
+pub fn adapt(color: Color) -> Color {
+    adapt_to(color, ColorSupport::detect())
+}
+
+/// Like `adapt`, for a terminal with the given support.
+pub fn adapt_to(color: Color, support: ColorSupport) -> Color {
+    match (color, support) {
+        (_, ColorSupport::NoColor) => Color::Reset,
+        (Color::Rgb(r, g, b), ColorSupport::Ansi256) => Color::Indexed(to_256(r, g, b)),
+        (Color::Rgb(r, g, b), ColorSupport::Ansi16) => to_16(r, g, b),
+        (color, _) => color,
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+fn to_256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |value: u8| {
+        (0..CUBE_LEVELS.len())
+            .min_by_key(|&i| (CUBE_LEVELS[i] as i32 - value as i32).abs())
+            .unwrap_or_default()
+    };
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let cube = (CUBE_LEVELS[ri], CUBE_LEVELS[gi], CUBE_LEVELS[bi]);
+
+    // The grayscale ramp runs from 8 to 238 in steps of 10.
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray_index = (average.saturating_sub(3) / 10).min(23) as u8;
+    let gray_level = 8 + gray_index * 10;
+
+    if distance((r, g, b), (gray_level, gray_level, gray_level)) < distance((r, g, b), cube) {
+        232 + gray_index
+    } else {
+        16 + 36 * ri as u8 + 6 * gi as u8 + bi as u8
+    }
+}
+
+fn to_16(r: u8, g: u8, b: u8) -> Color {
+    ANSI_16
+        .iter()
+        .min_by_key(|(_, rgb)| distance((r, g, b), *rgb))
+        .map_or(Color::White, |(color, _)| *color)
+}
+
+/// Reads a color name like `lightblue`, a `#rrggbb` hex code, or a 256 color
+/// index, adapted to what the terminal supports.
+pub fn parse_color(input: &str) -> Result<Color, String> {
+    let name = input.trim().to_lowercase().replace(['_', '-', ' '], "");
+    let color = match name.as_str() {
+        "reset" => Color::Reset,
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "darkgray" | "darkgrey" => Color::DarkGray,
+        "lightred" => Color::LightRed,
+        "lightgreen" => Color::LightGreen,
+        "lightyellow" => Color::LightYellow,
+        "lightblue" => Color::LightBlue,
+        "lightmagenta" => Color::LightMagenta,
+        "lightcyan" => Color::LightCyan,
+        "white" => Color::White,
+        _ => {
+            if let Some(hex) = name.strip_prefix('#') {
+                let rgb = u32::from_str_radix(hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 6)
+                    .ok_or_else(|| format!("Invalid color {}", input))?;
+                Color::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+            } else {
+                let index = name
+                    .parse()
+                    .map_err(|_| format!("Invalid color {}", input))?;
+                Color::Indexed(index)
+            }
+        }
+    };
+    Ok(adapt(color))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn true_color_terminals_get_the_color_as_is() {
+        let color = Color::Rgb(12, 34, 56);
+        assert_eq!(adapt_to(color, ColorSupport::TrueColor), color);
+    }
+
+    #[test]
+    fn degrades_to_the_256_color_palette() {
+        let adapt = |r, g, b| adapt_to(Color::Rgb(r, g, b), ColorSupport::Ansi256);
+        assert_eq!(adapt(255, 0, 0), Color::Indexed(196));
+        assert_eq!(adapt(0, 0, 0), Color::Indexed(16));
+        assert_eq!(adapt(255, 255, 255), Color::Indexed(231));
+        assert_eq!(adapt(95, 135, 175), Color::Indexed(67));
+        // Grays closer to the ramp than to the cube use the ramp.
+        assert_eq!(adapt(128, 128, 128), Color::Indexed(244));
+        assert_eq!(adapt(8, 8, 8), Color::Indexed(232));
+    }
+
+    #[test]
+    fn degrades_to_the_16_basic_colors() {
+        let adapt = |r, g, b| adapt_to(Color::Rgb(r, g, b), ColorSupport::Ansi16);
+        assert_eq!(adapt(250, 10, 10), Color::LightRed);
+        assert_eq!(adapt(190, 0, 0), Color::Red);
+        assert_eq!(adapt(0, 0, 200), Color::Blue);
+        assert_eq!(adapt(120, 120, 120), Color::DarkGray);
+        assert_eq!(adapt(230, 230, 230), Color::Gray);
+        assert_eq!(adapt(250, 250, 250), Color::White);
+    }
+
+    #[test]
+    fn named_colors_stay_and_no_color_means_none() {
+        for support in [
+            ColorSupport::TrueColor,
+            ColorSupport::Ansi256,
+            ColorSupport::Ansi16,
+        ] {
+            assert_eq!(adapt_to(Color::LightBlue, support), Color::LightBlue);
+        }
+        assert_eq!(
+            adapt_to(Color::Rgb(255, 0, 0), ColorSupport::NoColor),
+            Color::Reset
+        );
+        assert_eq!(adapt_to(Color::Red, ColorSupport::NoColor), Color::Reset);
+    }
+}
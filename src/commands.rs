@@ -0,0 +1,248 @@
+use crate::protocol::{BanRequest, Command, Data, MuteRequest, Whisper};
+
+/// Longest message the server accepts.
+const MAX_MESSAGE_LENGTH: usize = 512;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+const COMMANDS: [&str; 7] = [
+    "/me",
+    "/w",
+    "/r",
+    "/export",
+    "/ignore",
+    "/unignore",
+    "/quit",
+];
+const MODERATOR_COMMANDS: [&str; 6] = ["/mute", "/unmute", "/ban", "/ipban", "/unban", "/subonly"];
+
+/// Commands worth suggesting to the user, moderation ones only for moderators.
+pub fn available(moderator: bool) -> Vec<&'static str> {
+    let mut commands = COMMANDS.to_vec();
+    if moderator {
+        commands.extend(MODERATOR_COMMANDS);
+    }
+    commands
+}
+
+/// What the user asked for with a line of input.
+#[derive(Debug, Clone)]
+pub enum Action {
+    Send(Command),
+    /// `/r <message>`, a whisper to whoever whispered us last.
+    Reply(String),
+    /// `/w <nick>` without a message just opens the conversation.
+    OpenWhisper(String),
+    /// `/export`, save the chat to a text file.
+    Export,
+    /// `/ignore <nick>`, hide their messages and whispers.
+    Ignore(String),
+    Unignore(String),
+    /// `/quit`, close the connection and exit.
+    Quit,
+}
+
+/// Turns what the user typed into what we should do, or a reason why we won't.
+pub fn parse_input(input: &str) -> Result<Action, String> {
+    let input = input.trim();
+
+    if input.is_empty() {
+        return Err("Nothing to send".to_string());
+    }
+
+    if !input.starts_with('/') || input.starts_with("/me ") {
+        return message(input).map(Action::Send);
+    }
+
+    let (command, args) = match input.split_once(' ') {
+        Some((command, args)) => (command, args.trim()),
+        None => (input, ""),
+    };
+
+    let command = match command.to_lowercase().as_str() {
+        "/me" => Err("Usage: /me <action>".to_string()),
+        "/w" | "/whisper" | "/msg" | "/tell" | "/notify" => {
+            let (nick, data) = nick_and_rest(args, "/w <nick> [message]")?;
+            if data.is_empty() {
+                return Ok(Action::OpenWhisper(nick.to_string()));
+            }
+            whisper(nick, data)
+        }
+        "/r" | "/reply" => {
+            if args.is_empty() {
+                return Err("Usage: /r <message>".to_string());
+            }
+            return Ok(Action::Reply(args.to_string()));
+        }
+        "/export" => return Ok(Action::Export),
+        "/quit" | "/exit" => return Ok(Action::Quit),
+        "/ignore" => {
+            let (nick, _) = nick_and_rest(args, "/ignore <nick>")?;
+            return Ok(Action::Ignore(nick.to_string()));
+        }
+        "/unignore" => {
+            let (nick, _) = nick_and_rest(args, "/unignore <nick>")?;
+            return Ok(Action::Unignore(nick.to_string()));
+        }
+        "/mute" => {
+            let (nick, rest) = nick_and_rest(args, "/mute <nick> [duration]")?;
+            let duration = match rest {
+                "" => None,
+                "perm" => return Err("Mutes can't be permanent, use /ban".to_string()),
+                _ => Some(parse_duration(rest)?),
+            };
+            Ok(Command::Mute(MuteRequest {
+                data: nick.to_string(),
+                duration,
+            }))
+        }
+        "/unmute" => {
+            let (nick, _) = nick_and_rest(args, "/unmute <nick>")?;
+            Ok(Command::Unmute(Data {
+                data: nick.to_string(),
+            }))
+        }
+        "/ban" | "/ipban" => {
+            let banip = command.eq_ignore_ascii_case("/ipban");
+            let usage = format!("{} <nick> <duration|perm> <reason>", command);
+            let (nick, rest) = nick_and_rest(args, &usage)?;
+            let (duration, reason) = match rest.split_once(' ') {
+                Some((duration, reason)) => (duration, reason.trim()),
+                None => return Err(format!("Usage: {}", usage)),
+            };
+            let ispermanent = duration == "perm";
+            Ok(Command::Ban(BanRequest {
+                nick: nick.to_string(),
+                reason: reason.to_string(),
+                duration: if ispermanent {
+                    None
+                } else {
+                    Some(parse_duration(duration)?)
+                },
+                ispermanent,
+                banip,
+            }))
+        }
+        "/unban" => {
+            let (nick, _) = nick_and_rest(args, "/unban <nick>")?;
+            Ok(Command::Unban(Data {
+                data: nick.to_string(),
+            }))
+        }
+        "/subonly" => match args {
+            "on" | "off" => Ok(Command::SubOnly(Data {
+                data: args.to_string(),
+            })),
+            _ => Err("Usage: /subonly on|off".to_string()),
+        },
+        _ => Err(format!("Unknown command {}", command)),
+    };
+
+    command.map(Action::Send)
+}
+
+pub fn whisper(nick: &str, data: &str) -> Result<Command, String> {
+    check_length(data)?;
+    Ok(Command::PrivMsg(Whisper {
+        nick: nick.to_string(),
+        data: data.to_string(),
+    }))
+}
+
+fn message(data: &str) -> Result<Command, String> {
+    check_length(data)?;
+    Ok(Command::Msg(Data {
+        data: data.to_string(),
+    }))
+}
+
+fn check_length(data: &str) -> Result<(), String> {
+    if data.chars().count() > MAX_MESSAGE_LENGTH {
+        Err(format!(
+            "Message is longer than {} characters",
+            MAX_MESSAGE_LENGTH
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn nick_and_rest<'a>(args: &'a str, usage: &str) -> Result<(&'a str, &'a str), String> {
+    let (nick, rest) = match args.split_once(' ') {
+        Some((nick, rest)) => (nick, rest.trim()),
+        None => (args, ""),
+    };
+    if nick.is_empty() {
+        Err(format!("Usage: {}", usage))
+    } else {
+        Ok((nick, rest))
+    }
+}
+
+/// Parses `30s`, `10m`, `1h`, `2d` or a bare number of minutes into nanoseconds.
+pub fn parse_duration(input: &str) -> Result<u64, String> {
+    let input = input.trim().to_lowercase();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration {}", input))?;
+    let seconds = match unit {
+        "s" | "sec" | "secs" => 1,
+        "" | "m" | "min" | "mins" => 60,
+        "h" | "hr" | "hrs" => 60 * 60,
+        "d" | "day" | "days" => 60 * 60 * 24,
+        _ => return Err(format!("Invalid duration {}", input)),
+    };
+
+    number
+        .checked_mul(seconds * NANOS_PER_SECOND)
+        .ok_or_else(|| format!("Invalid duration {}", input))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30s"), Ok(30 * NANOS_PER_SECOND));
+        assert_eq!(parse_duration("10"), Ok(600 * NANOS_PER_SECOND));
+        assert_eq!(parse_duration("2D"), Ok(2 * 86400 * NANOS_PER_SECOND));
+        assert!(parse_duration("10w").is_err());
+        assert!(parse_duration("d").is_err());
+    }
+
+    #[test]
+    fn rejects_durations_that_overflow() {
+        assert_eq!(
+            parse_duration("99999999999d"),
+            Err("Invalid duration 99999999999d".to_string())
+        );
+        assert!(parse_duration("99999999999999999999s").is_err());
+    }
+
+    #[test]
+    fn mute_with_huge_duration_is_an_error() {
+        assert!(parse_input("/mute bob 99999999999d").is_err());
+    }
+
+    #[test]
+    fn me_without_text_shows_usage() {
+        assert_eq!(
+            parse_input("/me").unwrap_err(),
+            "Usage: /me <action>".to_string()
+        );
+        assert_eq!(
+            parse_input("/me   ").unwrap_err(),
+            "Usage: /me <action>".to_string()
+        );
+        assert!(matches!(
+            parse_input("/me waves"),
+            Ok(Action::Send(Command::Msg(_)))
+        ));
+    }
+}
@@ -1,48 +1,233 @@
-use config::{Config as Config_c, ConfigError, Environment, File};
-use dirs;
+use crate::filter::FilterAction;
+use crate::graphics::Protocol;
+use crate::protocol::Event;
+use crate::theme::ThemeConfig;
+use config::Config as Config_c;
 use serde::Deserialize;
 use std::fs;
 use std::path::PathBuf;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 #[serde(default)]
 pub struct Config {
     pub username: Option<String>,
     pub token: Option<String>,
     pub emotes: bool,
+    /// How emote images are drawn
+    pub graphics: Protocol,
     pub autocomplete: bool,
+    /// Strike out lines from users once they are muted or banned
+    pub strike_moderated: bool,
+    pub reconnect: ReconnectConfig,
+    pub keepalive: KeepaliveConfig,
+    pub rate_limit: RateLimitConfig,
+    pub server: ServerConfig,
+    pub hide: HideConfig,
+    pub log: LogConfig,
+    pub scrollback: ScrollbackConfig,
+    /// Nicks whose messages and whispers are hidden
+    pub ignore: Vec<String>,
+    pub filters: Vec<FilterConfig>,
+    pub highlight: HighlightConfig,
+    pub theme: ThemeConfig,
+}
+
+/// Colors for a kind of line. Colors are names, `#rrggbb` or a 256 color index.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct StyleConfig {
+    pub fg: Option<String>,
+    pub bg: Option<String>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underlined: bool,
+    pub dim: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HighlightConfig {
+    /// Background of our own messages, instead of the theme's
+    pub own_background: Option<String>,
+    /// Messages mentioning our username, instead of the theme's style
+    pub mention: Option<StyleConfig>,
+    pub rules: Vec<HighlightRule>,
+}
+
+/// Messages containing any of `words`, sent by any of `nicks`, or matching
+/// `pattern`, get `style`. The first matching rule wins.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HighlightRule {
+    pub words: Vec<String>,
+    pub nicks: Vec<String>,
+    pub pattern: Option<String>,
+    pub style: StyleConfig,
+}
+
+/// A regex hiding or collapsing the chat messages it matches.
+#[derive(Debug, Deserialize, Clone)]
+pub struct FilterConfig {
+    pub pattern: String,
+    #[serde(default)]
+    pub action: FilterAction,
+}
+
+/// What to load from the chat log into the message list at startup.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ScrollbackConfig {
+    pub from_log: bool,
+    /// At most this many messages
+    pub messages: usize,
+    /// Only messages from the last this many hours. Unset takes any age.
+    pub hours: Option<u64>,
+}
+
+impl Default for ScrollbackConfig {
+    fn default() -> Self {
+        Self {
+            from_log: false,
+            messages: 200,
+            hours: None,
+        }
+    }
+}
+
+/// The chat log kept under the data directory.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LogConfig {
+    pub enabled: bool,
+    /// Delete daily logs older than this. Unset keeps them forever.
+    pub retention_days: Option<u32>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_days: Some(30),
+        }
+    }
+}
+
+/// Announcements to keep out of the chat.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HideConfig {
+    pub broadcasts: bool,
+    pub subscriptions: bool,
+    pub gift_subs: bool,
+    pub mass_gifts: bool,
+    pub donations: bool,
+}
+
+impl HideConfig {
+    pub fn hides(&self, event: &Event) -> bool {
+        match event {
+            Event::Broadcast(_) => self.broadcasts,
+            Event::Subscription(_) => self.subscriptions,
+            Event::GiftSub(_) => self.gift_subs,
+            Event::MassGift(_) => self.mass_gifts,
+            Event::Donation(_) => self.donations,
+            _ => false,
+        }
+    }
+}
+
+/// Where to find the chat and its assets. Defaults to destiny.gg.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub websocket_url: String,
+    pub history_url: String,
+    pub emotes_url: String,
+    pub flairs_url: String,
+    pub cdn_url: String,
 }
 
-impl Default for Config {
+impl Default for ServerConfig {
     fn default() -> Self {
         Self {
-            username: None,
-            token: None,
-            emotes: false,
-            autocomplete: false,
+            websocket_url: "wss://chat.destiny.gg/ws".to_string(),
+            history_url: "https://www.destiny.gg/api/chat/history".to_string(),
+            emotes_url: "https://cdn.destiny.gg/emotes/emotes.json".to_string(),
+            flairs_url: "https://cdn.destiny.gg/flairs/flairs.json".to_string(),
+            cdn_url: "https://cdn.destiny.gg".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Give up after this many failed attempts in a row. Unset retries forever.
+    pub max_retries: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 500,
+            max_delay_ms: 30_000,
+            max_retries: Some(20),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct KeepaliveConfig {
+    /// Time between our pings
+    pub interval_ms: u64,
+    /// How long to wait for a pong before reconnecting
+    pub timeout_ms: u64,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 15_000,
+            timeout_ms: 10_000,
+        }
+    }
+}
+
+/// How fast commands are sent: `burst` at once, then one every `interval_ms`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub burst: u32,
+    pub interval_ms: u64,
+    /// Pause after a throttled or duplicate error, doubled each time it happens again.
+    pub throttle_backoff_ms: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            burst: 3,
+            interval_ms: 500,
+            throttle_backoff_ms: 1_000,
         }
     }
 }
 
 impl Config {
-    pub async fn init() -> Result<Self, &'static str> {
-        if make_config_folder().is_ok() {
-            if config_exists() {
-                let path: PathBuf = config_path().unwrap();
-                let conf: Self = Config_c::builder()
-                    .add_source(config::File::with_name(path.to_str().unwrap()))
-                    .build()
-                    .unwrap()
-                    .try_deserialize()
-                    .unwrap();
-                Ok(conf)
-            } else {
-                Ok(Self {
-                    ..Default::default()
-                })
-            }
-        } else {
-            Err("Something went wrong")
+    pub async fn init() -> Result<Self, String> {
+        make_config_folder().map_err(|e| format!("Could not create the config folder: {}", e))?;
+        match config_path() {
+            Some(path) if config_exists() => Config_c::builder()
+                .add_source(config::File::from(path.as_path()))
+                .build()
+                .and_then(|conf| conf.try_deserialize())
+                .map_err(|e| format!("Could not read {}: {}", path.display(), e)),
+            _ => Ok(Self {
+                ..Default::default()
+            }),
         }
     }
 }
@@ -86,6 +271,15 @@ fn config_path() -> Option<PathBuf> {
     None
 }
 
+/// Where theme files go, ~/.config/dggtui/themes.
+pub fn themes_folder() -> Option<PathBuf> {
+    let mut folder = dirs::home_dir()?;
+    folder.push(".config");
+    folder.push("dggtui");
+    folder.push("themes");
+    Some(folder)
+}
+
 fn config_exists() -> bool {
     if cfg!(windows) {
         println!("this is windows");
@@ -100,3 +294,24 @@ fn config_exists() -> bool {
     }
     false
 }
+
+/// A folder under the XDG data directory (e.g. ~/.local/share/dggtui/whispers),
+/// created if it doesn't exist yet.
+pub fn data_folder(name: &str) -> std::io::Result<PathBuf> {
+    let mut folder = dirs::data_dir()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No data directory"))?;
+    folder.push("dggtui");
+    folder.push(name);
+    fs::create_dir_all(&folder)?;
+    Ok(folder)
+}
+
+/// The dggtui folder under the XDG cache directory (e.g. ~/.cache/dggtui),
+/// created if it doesn't exist yet.
+pub fn cache_folder() -> std::io::Result<PathBuf> {
+    let mut folder = dirs::cache_dir()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No cache directory"))?;
+    folder.push("dggtui");
+    fs::create_dir_all(&folder)?;
+    Ok(folder)
+}
@@ -0,0 +1,263 @@
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+use crate::config::{KeepaliveConfig, ReconnectConfig};
+
+/// State of the websocket connection, as shown in the status bar.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    /// Attempt number and how long until it is made.
+    Reconnecting(u32, Duration),
+    /// Gave up reconnecting, with the last error.
+    Failed(String),
+}
+
+impl std::fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::Connecting => write!(f, "Connecting"),
+            ConnectionState::Connected => write!(f, "Connected"),
+            ConnectionState::Reconnecting(attempt, next_in) => write!(
+                f,
+                "Reconnecting (attempt {}) in {:.1}s",
+                attempt,
+                next_in.as_secs_f32()
+            ),
+            ConnectionState::Failed(reason) => write!(f, "Disconnected: {}", reason),
+        }
+    }
+}
+
+/// Exponential backoff with jitter between reconnect attempts.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    max_retries: Option<u32>,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(config: &ReconnectConfig) -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(config.initial_delay_ms),
+            max: Duration::from_millis(config.max_delay_ms),
+            max_retries: config.max_retries,
+            attempt: 0,
+        }
+    }
+
+    /// Delay before the next attempt, or None once we've used up our retries.
+    ///
+    /// The delay doubles each attempt up to `max`, and a random amount of up to
+    /// half of it is taken off so clients don't all reconnect at once.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if let Some(max_retries) = self.max_retries {
+            if self.attempt >= max_retries {
+                return None;
+            }
+        }
+
+        let exponent = self.attempt.min(16);
+        self.attempt += 1;
+
+        let delay = self.initial.saturating_mul(1 << exponent).min(self.max);
+        let jitter = rand::thread_rng().gen_range(0.0..0.5);
+        Some(delay.mul_f64(1.0 - jitter))
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Pings the server every `interval` and measures how long the pong takes. A
+/// pong that doesn't come within `timeout` means the connection is dead.
+pub struct Keepalive {
+    interval: Duration,
+    timeout: Duration,
+    last_ping: Instant,
+    /// Payload of the ping we're waiting on
+    waiting: Option<u64>,
+    count: u64,
+}
+
+impl Keepalive {
+    pub fn new(config: &KeepaliveConfig) -> Keepalive {
+        Keepalive {
+            interval: Duration::from_millis(config.interval_ms),
+            timeout: Duration::from_millis(config.timeout_ms),
+            last_ping: Instant::now(),
+            waiting: None,
+            count: 0,
+        }
+    }
+
+    /// When the next ping is due, or when the one in flight times out.
+    pub fn next_wakeup(&self) -> Instant {
+        match self.waiting {
+            Some(_) => self.last_ping + self.timeout,
+            None => self.last_ping + self.interval,
+        }
+    }
+
+    /// Payload for a ping if one is due.
+    pub fn ping(&mut self, now: Instant) -> Option<String> {
+        if self.waiting.is_some() || now < self.last_ping + self.interval {
+            return None;
+        }
+        self.count += 1;
+        self.waiting = Some(self.count);
+        self.last_ping = now;
+        Some(self.count.to_string())
+    }
+
+    /// Round trip time, if `data` answers the ping in flight.
+    pub fn pong(&mut self, data: &[u8], now: Instant) -> Option<Duration> {
+        let waiting = self.waiting?;
+        if data != waiting.to_string().as_bytes() {
+            return None;
+        }
+        self.waiting = None;
+        Some(now.saturating_duration_since(self.last_ping))
+    }
+
+    pub fn timed_out(&self, now: Instant) -> bool {
+        self.waiting.is_some() && now >= self.last_ping + self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(max_retries: Option<u32>) -> Backoff {
+        Backoff::new(&ReconnectConfig {
+            initial_delay_ms: 100,
+            max_delay_ms: 1_000,
+            max_retries,
+        })
+    }
+
+    /// Whether `delay` is `full` with at most half of it taken off as jitter.
+    fn jittered(delay: Duration, full: u64) -> bool {
+        let full = Duration::from_millis(full);
+        delay > full / 2 && delay <= full
+    }
+
+    #[test]
+    fn delays_double_up_to_the_max() {
+        let mut backoff = backoff(None);
+        for full in [100, 200, 400, 800, 1_000, 1_000] {
+            let delay = backoff.next_delay().unwrap();
+            assert!(jittered(delay, full), "{:?} isn't about {}ms", delay, full);
+        }
+        assert_eq!(backoff.attempt(), 6);
+    }
+
+    #[test]
+    fn jitter_takes_off_at_most_half() {
+        for _ in 0..1_000 {
+            let delay = backoff(None).next_delay().unwrap();
+            assert!(jittered(delay, 100), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn long_outages_dont_overflow() {
+        let mut backoff = backoff(None);
+        for _ in 0..100 {
+            assert!(backoff.next_delay().unwrap() <= Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let mut backoff = backoff(Some(3));
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.attempt(), 3);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = backoff(Some(3));
+        while backoff.next_delay().is_some() {}
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        let delay = backoff.next_delay().unwrap();
+        assert!(jittered(delay, 100), "{:?}", delay);
+    }
+
+    fn keepalive() -> (Keepalive, Instant) {
+        let keepalive = Keepalive::new(&KeepaliveConfig {
+            interval_ms: 15_000,
+            timeout_ms: 10_000,
+        });
+        let start = keepalive.last_ping;
+        (keepalive, start)
+    }
+
+    #[test]
+    fn pings_every_interval() {
+        let (mut keepalive, start) = keepalive();
+        assert_eq!(keepalive.next_wakeup(), start + Duration::from_secs(15));
+        assert_eq!(keepalive.ping(start + Duration::from_secs(14)), None);
+
+        let first = start + Duration::from_secs(15);
+        assert_eq!(keepalive.ping(first).as_deref(), Some("1"));
+        // One ping at a time.
+        assert_eq!(keepalive.ping(first + Duration::from_secs(20)), None);
+
+        assert!(keepalive.pong(b"1", first).is_some());
+        assert_eq!(keepalive.next_wakeup(), first + Duration::from_secs(15));
+        assert_eq!(keepalive.ping(first + Duration::from_secs(14)), None);
+        assert_eq!(
+            keepalive.ping(first + Duration::from_secs(15)).as_deref(),
+            Some("2")
+        );
+    }
+
+    #[test]
+    fn only_the_matching_pong_counts() {
+        let (mut keepalive, start) = keepalive();
+        assert_eq!(keepalive.pong(b"1", start), None);
+
+        let sent = start + Duration::from_secs(15);
+        let payload = keepalive.ping(sent).unwrap();
+        assert_eq!(keepalive.pong(b"7", sent + Duration::from_millis(10)), None);
+        assert_eq!(
+            keepalive.pong(payload.as_bytes(), sent + Duration::from_millis(80)),
+            Some(Duration::from_millis(80))
+        );
+        // Answered already.
+        assert_eq!(
+            keepalive.pong(payload.as_bytes(), sent + Duration::from_millis(90)),
+            None
+        );
+    }
+
+    #[test]
+    fn a_missing_pong_times_out() {
+        let (mut keepalive, start) = keepalive();
+        assert!(!keepalive.timed_out(start + Duration::from_secs(60)));
+
+        let sent = start + Duration::from_secs(15);
+        keepalive.ping(sent).unwrap();
+        assert_eq!(keepalive.next_wakeup(), sent + Duration::from_secs(10));
+        assert!(!keepalive.timed_out(sent + Duration::from_millis(9_999)));
+        assert!(keepalive.timed_out(sent + Duration::from_secs(10)));
+
+        // A pong in time means there's nothing to time out.
+        keepalive.pong(b"1", sent + Duration::from_secs(9));
+        assert!(!keepalive.timed_out(sent + Duration::from_secs(60)));
+    }
+}
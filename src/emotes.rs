@@ -0,0 +1,376 @@
+use futures::stream::{self, StreamExt};
+use hyper::header::{ETAG, IF_NONE_MATCH};
+use hyper::{body, Body, Client, Request, StatusCode};
+use hyper_tls::HttpsConnector;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::config;
+use crate::images::{self, CellSize};
+use crate::types::Emote;
+
+/// How long to wait for emotes.json before falling back to the cached copy.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many images are downloaded at the same time.
+const PARALLEL_DOWNLOADS: usize = 8;
+
+/// An emote, and where its image is kept in the cache.
+#[derive(Debug, Clone)]
+pub struct EmoteInfo {
+    /// Doesn't exist until the image has been downloaded
+    pub path: PathBuf,
+    url: String,
+    /// Size of the original image in pixels
+    pub width: u16,
+    pub height: u16,
+}
+
+/// The emotes from emotes.json.
+#[derive(Default)]
+pub struct Emotes {
+    emotes: HashMap<String, EmoteInfo>,
+    /// Sorted, for autocomplete
+    names: Vec<String>,
+    /// The cache folder
+    folder: PathBuf,
+    /// Set once images are shown
+    cell: Option<CellSize>,
+    /// Scaled images that have been made, so drawing never has to look
+    ready: HashSet<PathBuf>,
+    /// Scaled images as the background task finds or makes them
+    landed: Option<mpsc::Receiver<PathBuf>>,
+}
+
+impl Emotes {
+    fn new(emotes: Vec<Emote>, cdn_url: &str, folder: PathBuf) -> Emotes {
+        let images = folder.join("images");
+        let emotes: HashMap<String, EmoteInfo> = emotes
+            .into_iter()
+            .filter_map(|emote| {
+                let image = emote.image.into_iter().next()?;
+                // The file name changes with every new version of an image.
+                let file = Path::new(&image.url).file_name()?.to_owned();
+                let url = if image.url.starts_with("http") {
+                    image.url
+                } else {
+                    format!(
+                        "{}/{}",
+                        cdn_url.trim_end_matches('/'),
+                        image.url.trim_start_matches('/')
+                    )
+                };
+                let info = EmoteInfo {
+                    path: images.join(file),
+                    url,
+                    width: image.width,
+                    height: image.height,
+                };
+                Some((emote.name, info))
+            })
+            .collect();
+
+        let mut names: Vec<String> = emotes.keys().cloned().collect();
+        names.sort();
+        Emotes {
+            emotes,
+            names,
+            folder,
+            cell: None,
+            ready: HashSet::new(),
+            landed: None,
+        }
+    }
+
+    /// Downloads emotes.json unless the cached copy is still current, going by
+    /// its ETag. When the server can't be reached the cached copy is used.
+    pub async fn load(url: &str, cdn_url: &str) -> Emotes {
+        let folder = match emotes_folder() {
+            Ok(folder) => folder,
+            Err(_) => return Emotes::default(),
+        };
+        let cached = load_cached(&folder);
+        let etag = match cached {
+            Some(_) => fs::read_to_string(folder.join("emotes.json.etag")).ok(),
+            None => None,
+        };
+
+        let emotes = match fetch(url, etag.as_deref()).await {
+            Ok(Fetched::NotModified) => cached,
+            Ok(Fetched::New(bytes, etag)) => match serde_json::from_slice::<Vec<Emote>>(&bytes) {
+                Ok(emotes) => {
+                    let _ = fs::write(folder.join("emotes.json"), &bytes);
+                    let _ = match etag {
+                        Some(etag) => fs::write(folder.join("emotes.json.etag"), etag),
+                        None => fs::remove_file(folder.join("emotes.json.etag")),
+                    };
+                    Some(emotes)
+                }
+                Err(_) => cached,
+            },
+            Err(_) => cached,
+        };
+
+        Emotes::new(emotes.unwrap_or_default(), cdn_url, folder)
+    }
+
+    /// Names of all emotes, sorted.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn get(&self, name: &str) -> Option<&EmoteInfo> {
+        self.emotes.get(name)
+    }
+
+    /// The emote a word of a message shows. Modifiers like `PEPE:wide` count
+    /// as the emote.
+    pub fn word(&self, word: &str) -> Option<&EmoteInfo> {
+        self.get(word.split(':').next().unwrap_or(word))
+    }
+
+    /// Columns the emote's image covers, once images are shown.
+    pub fn columns(&self, emote: &EmoteInfo) -> Option<u16> {
+        self.cell
+            .map(|cell| cell.columns(emote.width, emote.height))
+    }
+
+    /// The emote's image scaled to one row, once images are shown. It's always
+    /// a PNG, whatever the original is. Like the original it doesn't exist
+    /// until it has been made.
+    pub fn scaled(&self, emote: &EmoteInfo) -> Option<PathBuf> {
+        let cell = self.cell?;
+        let file = Path::new(emote.path.file_name()?).with_extension("png");
+        Some(scaled_folder(&self.folder, cell).join(file))
+    }
+
+    /// The emote's scaled image, once it has been made.
+    pub fn image(&self, emote: &EmoteInfo) -> Option<PathBuf> {
+        self.scaled(emote).filter(|path| self.ready.contains(path))
+    }
+
+    /// Picks up the scaled images made since the last call. True when there
+    /// are new ones, and messages with their emotes need laying out again.
+    pub fn poll_images(&mut self) -> bool {
+        let landed = match &self.landed {
+            Some(landed) => landed,
+            None => return false,
+        };
+        let count = self.ready.len();
+        self.ready.extend(landed.try_iter());
+        self.ready.len() > count
+    }
+
+    /// Shows images from now on, sized for `cell`. The images that aren't
+    /// cached yet are downloaded and scaled in the background, and the ones no
+    /// emote uses anymore are removed.
+    pub fn fetch_images(&mut self, cell: CellSize) {
+        self.cell = Some(cell);
+        let emotes: Vec<(EmoteInfo, PathBuf)> = self
+            .emotes
+            .values()
+            .filter_map(|emote| Some((emote.clone(), self.scaled(emote)?)))
+            .collect();
+        let images = self.folder.join("images");
+        let scaled = scaled_folder(&self.folder, cell);
+        let (ready, landed) = mpsc::channel();
+        self.landed = Some(landed);
+
+        tokio::spawn(async move {
+            if fs::create_dir_all(&images).is_err() {
+                return;
+            }
+            prune(
+                &images,
+                emotes.iter().map(|(emote, _)| emote.path.as_path()),
+            );
+            prune(&scaled, emotes.iter().map(|(_, scaled)| scaled.as_path()));
+
+            let https = HttpsConnector::new();
+            let client = Client::builder().build::<_, Body>(https);
+            let (cached, missing): (Vec<_>, Vec<_>) =
+                emotes.into_iter().partition(|(_, scaled)| scaled.exists());
+            for (_, scaled) in cached {
+                let _ = ready.send(scaled);
+            }
+            stream::iter(missing)
+                .for_each_concurrent(PARALLEL_DOWNLOADS, |(emote, scaled)| {
+                    let client = client.clone();
+                    let ready = ready.clone();
+                    async move {
+                        if !emote.path.exists() && download(&client, &emote).await.is_err() {
+                            return;
+                        }
+                        let made = tokio::task::spawn_blocking(move || {
+                            images::scale(&emote.path, &scaled, emote.width, emote.height, cell)
+                                .map(|_| scaled)
+                        })
+                        .await;
+                        if let Ok(Ok(scaled)) = made {
+                            let _ = ready.send(scaled);
+                        }
+                    }
+                })
+                .await;
+        });
+    }
+
+    /// Marks the scaled image of `name` as made.
+    #[cfg(test)]
+    pub fn mark_ready(&mut self, name: &str) {
+        let scaled = self.scaled(&self.emotes[name]).unwrap();
+        self.ready.insert(scaled);
+    }
+
+    /// Square 32 pixel emotes named `names` with their files under `folder`,
+    /// shown as images sized for `cell` without fetching anything.
+    #[cfg(test)]
+    pub fn for_tests(names: &[&str], folder: &Path, cell: CellSize) -> Emotes {
+        use crate::types::EmoteImage;
+
+        let emotes = names
+            .iter()
+            .map(|name| Emote {
+                name: name.to_string(),
+                twitch: false,
+                theme: 0,
+                image: vec![EmoteImage {
+                    url: format!("/emotes/{}.1a2b3c.gif", name),
+                    name: name.to_string(),
+                    mime: "image/gif".to_string(),
+                    height: 32,
+                    width: 32,
+                }],
+            })
+            .collect();
+        Emotes {
+            cell: Some(cell),
+            ..Emotes::new(emotes, "https://cdn", folder.to_path_buf())
+        }
+    }
+}
+
+/// Images scaled for one cell size are kept apart from those for others.
+fn scaled_folder(folder: &Path, cell: CellSize) -> PathBuf {
+    folder
+        .join("scaled")
+        .join(format!("{}x{}", cell.width, cell.height))
+}
+
+fn emotes_folder() -> std::io::Result<PathBuf> {
+    let folder = config::cache_folder()?.join("emotes");
+    fs::create_dir_all(&folder)?;
+    Ok(folder)
+}
+
+fn load_cached(folder: &Path) -> Option<Vec<Emote>> {
+    let contents = fs::read_to_string(folder.join("emotes.json")).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// Removes images of old versions of emotes and of emotes that are gone.
+fn prune<'a>(folder: &Path, current: impl Iterator<Item = &'a Path>) {
+    let current: HashSet<&Path> = current.collect();
+    if let Ok(entries) = fs::read_dir(folder) {
+        for entry in entries.flatten() {
+            if !current.contains(entry.path().as_path()) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+enum Fetched {
+    NotModified,
+    /// The body and its ETag
+    New(body::Bytes, Option<String>),
+}
+
+type FetchError = Box<dyn std::error::Error + Send + Sync>;
+
+async fn fetch(url: &str, etag: Option<&str>) -> Result<Fetched, FetchError> {
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, Body>(https);
+
+    let mut request = Request::get(url);
+    if let Some(etag) = etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    let resp =
+        tokio::time::timeout(FETCH_TIMEOUT, client.request(request.body(Body::empty())?)).await??;
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        return Ok(Fetched::NotModified);
+    }
+    if !resp.status().is_success() {
+        return Err(format!("{} answered {}", url, resp.status()).into());
+    }
+    let etag = resp
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(|etag| etag.to_string());
+    Ok(Fetched::New(body::to_bytes(resp.into_body()).await?, etag))
+}
+
+async fn download<C>(client: &Client<C>, emote: &EmoteInfo) -> Result<(), FetchError>
+where
+    C: hyper::client::connect::Connect + Clone + Send + Sync + 'static,
+{
+    let resp = client.get(emote.url.parse()?).await?;
+    if !resp.status().is_success() {
+        return Err(format!("{} answered {}", emote.url, resp.status()).into());
+    }
+    let bytes = body::to_bytes(resp.into_body()).await?;
+
+    // Written next to it first so a half downloaded image is never read.
+    let partial = emote.path.with_extension("part");
+    fs::write(&partial, &bytes)?;
+    fs::rename(&partial, &emote.path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaled_images_are_png() {
+        let cell = CellSize {
+            width: 8,
+            height: 16,
+        };
+        let emotes = Emotes::for_tests(&["PepoTurkey"], Path::new("/cache"), cell);
+        let info = emotes.get("PepoTurkey").unwrap();
+        assert_eq!(info.path, Path::new("/cache/images/PepoTurkey.1a2b3c.gif"));
+        assert_eq!(
+            emotes.scaled(info).unwrap(),
+            Path::new("/cache/scaled/8x16/PepoTurkey.1a2b3c.png")
+        );
+    }
+
+    #[test]
+    fn images_are_ready_once_they_land() {
+        let cell = CellSize {
+            width: 8,
+            height: 16,
+        };
+        let mut emotes = Emotes::for_tests(&["AYAYA", "PepoTurkey"], Path::new("/cache"), cell);
+        let ayaya = emotes.get("AYAYA").unwrap().clone();
+        let turkey = emotes.get("PepoTurkey").unwrap().clone();
+        assert!(!emotes.poll_images());
+
+        let (ready, landed) = mpsc::channel();
+        emotes.landed = Some(landed);
+        assert!(!emotes.poll_images());
+        assert_eq!(emotes.image(&ayaya), None);
+
+        ready.send(emotes.scaled(&ayaya).unwrap()).unwrap();
+        assert!(emotes.poll_images());
+        assert_eq!(emotes.image(&ayaya), emotes.scaled(&ayaya));
+        assert_eq!(emotes.image(&turkey), None);
+        assert!(!emotes.poll_images());
+    }
+}
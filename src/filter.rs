@@ -0,0 +1,227 @@
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::{self, Config};
+use crate::protocol::{Event, ParsedMessage};
+use crate::types::ChatLine;
+use crate::utils;
+
+/// What happens to a message a filter matches.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    /// Leave nothing in the chat
+    #[default]
+    Hide,
+    /// Replace the message with a one line note
+    Collapse,
+}
+
+struct Rule {
+    pattern: Regex,
+    action: FilterAction,
+}
+
+/// Ignored users and content filters, applied to messages as they arrive.
+#[derive(Default)]
+pub struct Filters {
+    /// Lowercased nicks
+    ignored: HashSet<String>,
+    rules: Vec<Rule>,
+}
+
+impl Filters {
+    /// Builds the filters from the config, plus the nicks ignored with `/ignore`.
+    /// Also returns why any of the configured patterns were left out.
+    pub fn new(config: &Config) -> (Filters, Vec<String>) {
+        let mut errors = vec![];
+        let rules = config
+            .filters
+            .iter()
+            .filter_map(|filter| {
+                match RegexBuilder::new(&filter.pattern)
+                    .case_insensitive(true)
+                    .build()
+                {
+                    Ok(pattern) => Some(Rule {
+                        pattern,
+                        action: filter.action,
+                    }),
+                    Err(e) => {
+                        errors.push(format!(
+                            "Invalid filter {}: {}",
+                            filter.pattern,
+                            utils::regex_error(&e)
+                        ));
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        let ignored = config
+            .ignore
+            .iter()
+            .chain(load_ignored().iter())
+            .map(|nick| nick.to_lowercase())
+            .collect();
+
+        (Filters { ignored, rules }, errors)
+    }
+
+    pub fn ignores(&self, nick: &str) -> bool {
+        self.ignored.contains(&nick.to_lowercase())
+    }
+
+    /// Starts ignoring `nick`, returning false if we already were.
+    pub fn ignore(&mut self, nick: &str) -> bool {
+        let added = self.ignored.insert(nick.to_lowercase());
+        if added {
+            save_ignored(nick, true);
+        }
+        added
+    }
+
+    /// Stops ignoring `nick`, returning false if we weren't.
+    pub fn unignore(&mut self, nick: &str) -> bool {
+        let removed = self.ignored.remove(&nick.to_lowercase());
+        if removed {
+            save_ignored(nick, false);
+        }
+        removed
+    }
+
+    /// Whether to keep `message` out of the chat, and how.
+    pub fn check(&self, message: &ParsedMessage) -> Option<FilterAction> {
+        if self.ignores(&message.nick) {
+            return Some(FilterAction::Hide);
+        }
+        self.rules
+            .iter()
+            .find(|rule| rule.pattern.is_match(&message.data))
+            .map(|rule| rule.action)
+    }
+
+    /// The line a message goes into the chat as.
+    pub fn line(&self, message: ParsedMessage) -> ChatLine {
+        match self.check(&message) {
+            Some(action) => ChatLine::Filtered(message, action),
+            None => ChatLine::Event(Event::Msg(message)),
+        }
+    }
+}
+
+/// Nicks ignored with `/ignore` are kept in the data directory, since the
+/// config file is the user's to edit.
+fn ignored_path() -> std::io::Result<PathBuf> {
+    Ok(config::data_folder("")?.join("ignored.json"))
+}
+
+fn load_ignored() -> Vec<String> {
+    ignored_path()
+        .and_then(fs::read_to_string)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn save_ignored(nick: &str, ignored: bool) {
+    let mut nicks = load_ignored();
+    nicks.retain(|other| !other.eq_ignore_ascii_case(nick));
+    if ignored {
+        nicks.push(nick.to_string());
+    }
+    if let (Ok(path), Ok(contents)) = (ignored_path(), serde_json::to_string(&nicks)) {
+        let _ = fs::write(path, contents);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FilterConfig;
+
+    fn message(nick: &str, data: &str) -> ParsedMessage {
+        ParsedMessage {
+            nick: nick.to_string(),
+            features: vec![],
+            timestamp: 0,
+            data: data.to_string(),
+        }
+    }
+
+    fn filters(rules: &str) -> (Filters, Vec<String>) {
+        let config = Config {
+            filters: serde_json::from_str(rules).unwrap(),
+            ..Default::default()
+        };
+        Filters::new(&config)
+    }
+
+    #[test]
+    fn rules_hide_unless_told_otherwise() {
+        let rules: Vec<FilterConfig> = serde_json::from_str(
+            r#"[{"pattern": "spoiler"}, {"pattern": "nsfw", "action": "collapse"}]"#,
+        )
+        .unwrap();
+        assert_eq!(rules[0].action, FilterAction::Hide);
+        assert_eq!(rules[1].action, FilterAction::Collapse);
+
+        assert!(
+            serde_json::from_str::<FilterConfig>(r#"{"pattern": "x", "action": "dim"}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn broken_patterns_are_reported_and_left_out() {
+        let (filters, errors) = filters(r#"[{"pattern": "(unclosed"}, {"pattern": "fine"}]"#);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("Invalid filter (unclosed"));
+        assert_eq!(filters.rules.len(), 1);
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let (filters, _) =
+            filters(r#"[{"pattern": "spoiler", "action": "collapse"}, {"pattern": "^!\\w+"}]"#);
+        assert_eq!(
+            filters.check(&message("alice", "SPOILER: it was him")),
+            Some(FilterAction::Collapse)
+        );
+        assert_eq!(
+            filters.check(&message("alice", "!spoiler")),
+            Some(FilterAction::Collapse)
+        );
+        assert_eq!(
+            filters.check(&message("alice", "!commands")),
+            Some(FilterAction::Hide)
+        );
+        assert_eq!(filters.check(&message("alice", "hi !commands")), None);
+
+        assert!(matches!(
+            filters.line(message("alice", "hello")),
+            ChatLine::Event(Event::Msg(_))
+        ));
+        assert!(matches!(
+            filters.line(message("alice", "spoiler")),
+            ChatLine::Filtered(_, FilterAction::Collapse)
+        ));
+    }
+
+    #[test]
+    fn ignored_nicks_are_hidden_whatever_they_say() {
+        let config = Config {
+            ignore: vec!["Test-Ignored-Nick".to_string()],
+            ..Default::default()
+        };
+        let (filters, _) = Filters::new(&config);
+        assert!(filters.ignores("test-ignored-nick"));
+        assert_eq!(
+            filters.check(&message("TEST-IGNORED-NICK", "hello")),
+            Some(FilterAction::Hide)
+        );
+    }
+}
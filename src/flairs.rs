@@ -0,0 +1,188 @@
+use hyper::{body, Client};
+use hyper_tls::HttpsConnector;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use tui::style::Color;
+
+use crate::color::parse_color;
+use crate::config;
+
+/// How long to wait for flairs.json before falling back to the cached copy.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Used until flairs.json has been downloaded once, in the colors nicks had
+/// before flairs were loaded.
+const FALLBACK_FLAIRS: &str = r#"[
+    {"name": "admin", "label": "Admin", "color": "yellow", "priority": 1},
+    {"name": "flair8", "label": "Tier IV", "color": "magenta", "priority": 2},
+    {"name": "flair1", "label": "Tier III", "color": "cyan", "priority": 3},
+    {"name": "flair3", "label": "Tier II", "color": "green", "priority": 4},
+    {"name": "subscriber", "label": "Subscriber", "color": "blue", "priority": 5}
+]"#;
+
+/// A flair as described by flairs.json.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Flair {
+    pub name: String,
+    pub label: String,
+    /// Hex color nicks with this flair get, or empty for none
+    pub color: String,
+    /// Lower comes first
+    pub priority: i32,
+    /// Not shown next to nicks
+    pub hidden: bool,
+}
+
+/// The known flairs, by priority.
+pub struct Flairs {
+    flairs: Vec<Flair>,
+    colors: HashMap<String, Color>,
+}
+
+impl Default for Flairs {
+    fn default() -> Flairs {
+        Flairs::new(serde_json::from_str(FALLBACK_FLAIRS).unwrap_or_default())
+    }
+}
+
+impl Flairs {
+    fn new(mut flairs: Vec<Flair>) -> Flairs {
+        flairs.sort_by_key(|flair| flair.priority);
+        let colors = flairs
+            .iter()
+            .filter_map(|flair| {
+                let color = parse_color(&flair.color).ok()?;
+                Some((flair.name.to_owned(), color))
+            })
+            .collect();
+        Flairs { flairs, colors }
+    }
+
+    /// Downloads flairs.json, keeping a copy in the cache. When that fails the
+    /// cached copy is used, or the built in flairs if there's none.
+    pub async fn load(url: &str) -> Flairs {
+        let flairs = match fetch(url).await {
+            Ok(bytes) => match serde_json::from_slice::<Vec<Flair>>(&bytes) {
+                Ok(flairs) => {
+                    if let Ok(path) = cache_path() {
+                        let _ = fs::write(path, &bytes);
+                    }
+                    Some(flairs)
+                }
+                Err(_) => None,
+            },
+            Err(_) => None,
+        };
+
+        match flairs.or_else(load_cached) {
+            Some(flairs) => Flairs::new(flairs),
+            None => Flairs::default(),
+        }
+    }
+
+    /// Color of the first flair by priority that has one.
+    pub fn color(&self, features: &[String]) -> Option<Color> {
+        self.flairs
+            .iter()
+            .filter(|flair| features.contains(&flair.name))
+            .find_map(|flair| self.colors.get(&flair.name).copied())
+    }
+
+    /// Labels of the visible flairs among `features`, by priority.
+    pub fn labels(&self, features: &[String]) -> Vec<&str> {
+        self.flairs
+            .iter()
+            .filter(|flair| !flair.hidden && features.contains(&flair.name))
+            .map(|flair| flair.label.as_str())
+            .collect()
+    }
+}
+
+fn cache_path() -> std::io::Result<PathBuf> {
+    Ok(config::cache_folder()?.join("flairs.json"))
+}
+
+fn load_cached() -> Option<Vec<Flair>> {
+    let contents = cache_path().and_then(fs::read_to_string).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+async fn fetch(url: &str) -> Result<body::Bytes, Box<dyn std::error::Error + Send + Sync>> {
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+
+    let resp = tokio::time::timeout(FETCH_TIMEOUT, client.get(url.parse()?)).await??;
+    if !resp.status().is_success() {
+        return Err(format!("{} answered {}", url, resp.status()).into());
+    }
+    Ok(body::to_bytes(resp.into_body()).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flair(name: &str, color: &str, priority: i32, hidden: bool) -> Flair {
+        Flair {
+            name: name.to_string(),
+            label: name.to_uppercase(),
+            color: color.to_string(),
+            priority,
+            hidden,
+        }
+    }
+
+    fn features(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn the_highest_priority_flair_with_a_color_wins() {
+        let flairs = Flairs::new(vec![
+            flair("subscriber", "blue", 5, false),
+            flair("bot", "", 0, false),
+            flair("tier2", "green", 4, false),
+            flair("admin", "yellow", 1, true),
+        ]);
+
+        assert_eq!(
+            flairs.color(&features(&["subscriber", "tier2"])),
+            parse_color("green").ok()
+        );
+        assert_eq!(
+            flairs.color(&features(&["subscriber", "admin", "tier2"])),
+            parse_color("yellow").ok()
+        );
+        // A flair without a color doesn't hide the ones after it.
+        assert_eq!(
+            flairs.color(&features(&["bot", "subscriber"])),
+            parse_color("blue").ok()
+        );
+        assert_eq!(flairs.color(&features(&["bot", "unknown"])), None);
+        assert_eq!(flairs.color(&[]), None);
+    }
+
+    #[test]
+    fn labels_go_by_priority_leaving_out_hidden_ones() {
+        let flairs = Flairs::new(vec![
+            flair("subscriber", "blue", 5, false),
+            flair("bot", "", 0, false),
+            flair("admin", "yellow", 1, true),
+        ]);
+        assert_eq!(
+            flairs.labels(&features(&["subscriber", "admin", "bot"])),
+            ["BOT", "SUBSCRIBER"]
+        );
+    }
+
+    #[test]
+    fn fallback_flairs_parse() {
+        let flairs = Flairs::default();
+        assert_eq!(flairs.flairs.len(), 5);
+        assert_eq!(flairs.flairs[0].name, "admin");
+    }
+}
@@ -0,0 +1,619 @@
+use image::imageops::{self, FilterType};
+use image::{ImageResult, Rgba, RgbaImage};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tui::style::Color;
+
+use crate::color::{self, ColorSupport};
+
+/// How long to wait for the terminal to answer the capability queries.
+const QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Asks whether kitty graphics work, with a 1x1 image that is never stored,
+/// then asks for the device attributes. Every terminal answers the latter, so
+/// its answer marks the end of the reply.
+const QUERY: &[u8] = b"\x1b_Gi=31,s=1,v=1,a=q,t=d,f=24;AAAA\x1b\\\x1b[c";
+
+/// Kitty sends base64 in chunks of at most this many bytes.
+const KITTY_CHUNK: usize = 4096;
+
+/// How inline images are drawn, from the `graphics` setting.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protocol {
+    /// The best one the terminal supports
+    #[default]
+    Auto,
+    Kitty,
+    Sixel,
+    Iterm2,
+    HalfBlocks,
+    Braille,
+}
+
+/// What the terminal said it can draw.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Capabilities {
+    pub kitty: bool,
+    pub sixel: bool,
+    pub iterm2: bool,
+}
+
+impl Capabilities {
+    /// Queries the terminal, which has to be in raw mode, and looks at
+    /// `TERM_PROGRAM`.
+    pub fn detect() -> Capabilities {
+        let mut stdout = io::stdout();
+        let reply = match stdout.write_all(QUERY).and_then(|_| stdout.flush()) {
+            Ok(_) => read_reply(QUERY_TIMEOUT),
+            Err(_) => vec![],
+        };
+        let term_program = env::var("TERM_PROGRAM")
+            .or_else(|_| env::var("LC_TERMINAL"))
+            .unwrap_or_default();
+        Capabilities::parse(&reply, &term_program)
+    }
+
+    /// Reads the terminal's reply to `QUERY`.
+    pub fn parse(reply: &[u8], term_program: &str) -> Capabilities {
+        let kitty = reply
+            .windows(b"\x1b_Gi=31;OK".len())
+            .any(|window| window == b"\x1b_Gi=31;OK");
+        // The device attributes look like ESC [ ? 62 ; 4 ; 22 c, where 4 is sixel.
+        let sixel = device_attributes(reply)
+            .is_some_and(|attributes| attributes.split(|&b| b == b';').skip(1).any(|a| a == b"4"));
+        let iterm2 = matches!(term_program, "iTerm.app" | "iTerm2" | "WezTerm");
+        Capabilities {
+            kitty,
+            sixel,
+            iterm2,
+        }
+    }
+
+    /// The protocol `Auto` picks: a real image protocol if there is one,
+    /// braille for `NO_COLOR` since it shows shapes without color, and half
+    /// blocks otherwise.
+    pub fn best(&self, colors: ColorSupport) -> Protocol {
+        if self.kitty {
+            Protocol::Kitty
+        } else if self.iterm2 {
+            Protocol::Iterm2
+        } else if self.sixel {
+            Protocol::Sixel
+        } else if colors == ColorSupport::NoColor {
+            Protocol::Braille
+        } else {
+            Protocol::HalfBlocks
+        }
+    }
+}
+
+/// The parameters of the device attributes in `reply`, if it has them.
+fn device_attributes(reply: &[u8]) -> Option<&[u8]> {
+    let start = reply.windows(3).position(|window| window == b"\x1b[?")? + 3;
+    let length = reply[start..]
+        .iter()
+        .position(|&b| !(b.is_ascii_digit() || b == b';'))?;
+    (reply[start + length] == b'c').then(|| &reply[start..start + length])
+}
+
+#[cfg(unix)]
+fn read_reply(timeout: Duration) -> Vec<u8> {
+    let deadline = Instant::now() + timeout;
+    let mut reply = vec![];
+    while device_attributes(&reply).is_none() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        let mut poll = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: poll and read only touch the buffers they're given.
+        if unsafe { libc::poll(&mut poll, 1, left.as_millis() as i32) } <= 0 {
+            break;
+        }
+        let mut buffer = [0u8; 256];
+        let read = unsafe {
+            libc::read(
+                libc::STDIN_FILENO,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+            )
+        };
+        if read <= 0 {
+            break;
+        }
+        reply.extend_from_slice(&buffer[..read as usize]);
+    }
+    reply
+}
+
+#[cfg(not(unix))]
+fn read_reply(_timeout: Duration) -> Vec<u8> {
+    vec![]
+}
+
+/// An image to draw, both as the PNG it was loaded from and decoded.
+pub struct Image {
+    /// Tells images apart for protocols that keep them around, like kitty
+    pub id: u32,
+    pub png: Vec<u8>,
+    pub pixels: RgbaImage,
+}
+
+impl Image {
+    pub fn from_png(id: u32, png: Vec<u8>) -> ImageResult<Image> {
+        let pixels = image::load_from_memory(&png)?.to_rgba8();
+        Ok(Image { id, png, pixels })
+    }
+
+    pub fn load(id: u32, path: &Path) -> ImageResult<Image> {
+        Image::from_png(id, fs::read(path)?)
+    }
+}
+
+/// A way of drawing images in the terminal. Backends only write escapes to
+/// `out`, so what they send can be checked byte for byte.
+pub trait GraphicsBackend {
+    /// Draws `image` at the cursor, over `columns` by `rows` cells.
+    fn draw(&mut self, out: &mut Vec<u8>, image: &Image, columns: u16, rows: u16);
+
+    /// Removes images the terminal keeps on screen on its own. Images made of
+    /// text are overwritten by the next redraw instead.
+    fn clear(&mut self, _out: &mut Vec<u8>) {}
+}
+
+/// The backend for `protocol`, or the best one the terminal supports.
+pub fn backend(
+    protocol: Protocol,
+    capabilities: Capabilities,
+    colors: ColorSupport,
+) -> Box<dyn GraphicsBackend> {
+    let protocol = match protocol {
+        Protocol::Auto => capabilities.best(colors),
+        protocol => protocol,
+    };
+    match protocol {
+        Protocol::Kitty => Box::new(Kitty::default()),
+        Protocol::Sixel => Box::new(Sixel),
+        Protocol::Iterm2 => Box::new(Iterm2),
+        Protocol::Auto | Protocol::HalfBlocks => Box::new(HalfBlocks { colors }),
+        Protocol::Braille => Box::new(Braille { colors }),
+    }
+}
+
+/// The kitty graphics protocol. Each image is sent once under its own id, and
+/// placed wherever it shows up after that.
+#[derive(Default)]
+pub struct Kitty {
+    sent: HashSet<u32>,
+}
+
+impl GraphicsBackend for Kitty {
+    fn draw(&mut self, out: &mut Vec<u8>, image: &Image, columns: u16, rows: u16) {
+        if self.sent.insert(image.id) {
+            let data = base64::encode(&image.png);
+            let chunks: Vec<&[u8]> = data.as_bytes().chunks(KITTY_CHUNK).collect();
+            for (i, chunk) in chunks.iter().enumerate() {
+                let more = (i + 1 < chunks.len()) as u8;
+                if i == 0 {
+                    let _ = write!(out, "\x1b_Ga=t,f=100,i={},q=2,m={};", image.id, more);
+                } else {
+                    let _ = write!(out, "\x1b_Gm={};", more);
+                }
+                out.extend_from_slice(chunk);
+                out.extend_from_slice(b"\x1b\\");
+            }
+        }
+        let _ = write!(
+            out,
+            "\x1b_Ga=p,i={},c={},r={},C=1,q=2\x1b\\",
+            image.id, columns, rows
+        );
+    }
+
+    fn clear(&mut self, out: &mut Vec<u8>) {
+        // Lowercase d keeps the images for the next placement.
+        out.extend_from_slice(b"\x1b_Ga=d,d=a,q=2\x1b\\");
+    }
+}
+
+/// Inline images as iTerm2 and WezTerm support them.
+pub struct Iterm2;
+
+impl GraphicsBackend for Iterm2 {
+    fn draw(&mut self, out: &mut Vec<u8>, image: &Image, columns: u16, rows: u16) {
+        let _ = write!(
+            out,
+            "\x1b]1337;File=inline=1;size={};width={};height={};preserveAspectRatio=0:{}\x07",
+            image.png.len(),
+            columns,
+            rows,
+            base64::encode(&image.png)
+        );
+    }
+}
+
+/// Sixel graphics, with colors from the 6x6x6 cube of the 256 color palette.
+/// Mostly transparent pixels are left out.
+pub struct Sixel;
+
+impl Sixel {
+    fn level(value: u8) -> usize {
+        (value as usize * 5 + 127) / 255
+    }
+
+    fn color(pixel: &Rgba<u8>) -> Option<usize> {
+        let [r, g, b, a] = pixel.0;
+        (a >= 128).then(|| Sixel::level(r) * 36 + Sixel::level(g) * 6 + Sixel::level(b))
+    }
+
+    /// Writes `byte` `count` times, run length encoded.
+    fn repeat(out: &mut Vec<u8>, byte: u8, count: usize) {
+        if count > 3 {
+            let _ = write!(out, "!{}", count);
+            out.push(byte);
+        } else {
+            out.extend(std::iter::repeat_n(byte, count));
+        }
+    }
+}
+
+impl GraphicsBackend for Sixel {
+    fn draw(&mut self, out: &mut Vec<u8>, image: &Image, _columns: u16, _rows: u16) {
+        let pixels = &image.pixels;
+        let (width, height) = pixels.dimensions();
+        let colors: BTreeSet<usize> = pixels.pixels().filter_map(Sixel::color).collect();
+
+        // P2 = 1 leaves the pixels that aren't set as they were.
+        let _ = write!(out, "\x1bP0;1;0q\"1;1;{};{}", width, height);
+        for &color in &colors {
+            let percent = |level: usize| level * 100 / 5;
+            let _ = write!(
+                out,
+                "#{};2;{};{};{}",
+                color,
+                percent(color / 36),
+                percent(color / 6 % 6),
+                percent(color % 6)
+            );
+        }
+
+        for top in (0..height).step_by(6) {
+            let mut first = true;
+            for &color in &colors {
+                let sixels: Vec<u8> = (0..width)
+                    .map(|x| {
+                        (0..6)
+                            .filter(|dy| top + dy < height)
+                            .filter(|dy| Sixel::color(pixels.get_pixel(x, top + dy)) == Some(color))
+                            .fold(0, |bits, dy| bits | 1 << dy)
+                    })
+                    .collect();
+                if sixels.iter().all(|&bits| bits == 0) {
+                    continue;
+                }
+                if !first {
+                    out.push(b'$');
+                }
+                first = false;
+                let _ = write!(out, "#{}", color);
+
+                let mut run = (sixels[0], 0);
+                for &bits in &sixels {
+                    if bits != run.0 {
+                        Sixel::repeat(out, 63 + run.0, run.1);
+                        run = (bits, 0);
+                    }
+                    run.1 += 1;
+                }
+                Sixel::repeat(out, 63 + run.0, run.1);
+            }
+            out.push(b'-');
+        }
+        out.extend_from_slice(b"\x1b\\");
+    }
+}
+
+/// Select Graphic Rendition parameters for a color, or None for no color.
+fn sgr(color: Color, background: bool) -> Option<String> {
+    let base = if background { 40 } else { 30 };
+    let code = match color {
+        Color::Reset => return None,
+        Color::Rgb(r, g, b) => return Some(format!("{};2;{};{};{}", base + 8, r, g, b)),
+        Color::Indexed(index) => return Some(format!("{};5;{}", base + 8, index)),
+        Color::Black => base,
+        Color::Red => base + 1,
+        Color::Green => base + 2,
+        Color::Yellow => base + 3,
+        Color::Blue => base + 4,
+        Color::Magenta => base + 5,
+        Color::Cyan => base + 6,
+        Color::Gray => base + 7,
+        Color::DarkGray => base + 60,
+        Color::LightRed => base + 61,
+        Color::LightGreen => base + 62,
+        Color::LightYellow => base + 63,
+        Color::LightBlue => base + 64,
+        Color::LightMagenta => base + 65,
+        Color::LightCyan => base + 66,
+        Color::White => base + 67,
+    };
+    Some(code.to_string())
+}
+
+/// Writes `text` in the given colors, then resets them.
+fn colored(out: &mut Vec<u8>, text: char, fg: Option<Color>, bg: Option<Color>) {
+    let codes: Vec<String> = [
+        fg.and_then(|fg| sgr(fg, false)),
+        bg.and_then(|bg| sgr(bg, true)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if codes.is_empty() {
+        let _ = write!(out, "{}", text);
+    } else {
+        let _ = write!(out, "\x1b[{}m{}\x1b[0m", codes.join(";"), text);
+    }
+}
+
+fn opaque(pixel: &Rgba<u8>, colors: ColorSupport) -> Option<Color> {
+    let [r, g, b, a] = pixel.0;
+    (a >= 128).then(|| color::adapt_to(Color::Rgb(r, g, b), colors))
+}
+
+/// Two pixels per cell, the top one in the foreground of ▀ and the bottom one
+/// in its background. Works in any terminal with colors.
+pub struct HalfBlocks {
+    pub colors: ColorSupport,
+}
+
+impl GraphicsBackend for HalfBlocks {
+    fn draw(&mut self, out: &mut Vec<u8>, image: &Image, columns: u16, rows: u16) {
+        let pixels = imageops::resize(
+            &image.pixels,
+            columns as u32,
+            rows as u32 * 2,
+            FilterType::Triangle,
+        );
+        for row in 0..rows as u32 {
+            if row > 0 {
+                let _ = write!(out, "\x1b[1B\x1b[{}D", columns);
+            }
+            for x in 0..columns as u32 {
+                let top = opaque(pixels.get_pixel(x, row * 2), self.colors);
+                let bottom = opaque(pixels.get_pixel(x, row * 2 + 1), self.colors);
+                match (top, bottom) {
+                    (None, None) => out.extend_from_slice(b"\x1b[1C"),
+                    (top, None) => colored(out, '▀', top, None),
+                    (None, bottom) => colored(out, '▄', bottom, None),
+                    (top, bottom) => colored(out, '▀', top, bottom),
+                }
+            }
+        }
+    }
+}
+
+/// Braille dots, eight per cell, set where the image is brighter than
+/// average. Each cell takes the average color of its dots when there are
+/// colors, so shapes still show with `NO_COLOR`.
+pub struct Braille {
+    pub colors: ColorSupport,
+}
+
+impl Braille {
+    /// Bits of the dots in a braille cell, by column and row.
+    const DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+
+    fn luma(pixel: &Rgba<u8>) -> u32 {
+        let [r, g, b, _] = pixel.0;
+        (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000
+    }
+}
+
+impl GraphicsBackend for Braille {
+    fn draw(&mut self, out: &mut Vec<u8>, image: &Image, columns: u16, rows: u16) {
+        let pixels = imageops::resize(
+            &image.pixels,
+            columns as u32 * 2,
+            rows as u32 * 4,
+            FilterType::Triangle,
+        );
+        let visible: Vec<&Rgba<u8>> = pixels.pixels().filter(|pixel| pixel[3] >= 128).collect();
+        let average = match visible.len() {
+            0 => 0,
+            count => {
+                visible
+                    .iter()
+                    .map(|pixel| Braille::luma(pixel))
+                    .sum::<u32>()
+                    / count as u32
+            }
+        };
+
+        for row in 0..rows as u32 {
+            if row > 0 {
+                let _ = write!(out, "\x1b[1B\x1b[{}D", columns);
+            }
+            for column in 0..columns as u32 {
+                let mut bits = 0;
+                let mut sum = [0u32; 3];
+                for (dx, dots) in Braille::DOTS.iter().enumerate() {
+                    for (dy, dot) in dots.iter().enumerate() {
+                        let pixel = pixels.get_pixel(column * 2 + dx as u32, row * 4 + dy as u32);
+                        if pixel[3] >= 128 && Braille::luma(pixel) >= average {
+                            bits |= dot;
+                            for (total, channel) in sum.iter_mut().zip(pixel.0) {
+                                *total += channel as u32;
+                            }
+                        }
+                    }
+                }
+                if bits == 0 {
+                    out.extend_from_slice(b"\x1b[1C");
+                    continue;
+                }
+                let count = bits.count_ones();
+                let [r, g, b] = sum.map(|total| (total / count) as u8);
+                let fg = color::adapt_to(Color::Rgb(r, g, b), self.colors);
+                let dots = char::from_u32(0x2800 + bits).unwrap_or(' ');
+                colored(out, dots, Some(fg), None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const GREEN: [u8; 4] = [0, 255, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+
+    /// An image made of `rows` of pixels, with `png` standing in for the
+    /// encoded file.
+    fn image(id: u32, png: &[u8], rows: &[&[[u8; 4]]]) -> Image {
+        let (width, height) = (rows[0].len() as u32, rows.len() as u32);
+        let pixels = RgbaImage::from_fn(width, height, |x, y| Rgba(rows[y as usize][x as usize]));
+        Image {
+            id,
+            png: png.to_vec(),
+            pixels,
+        }
+    }
+
+    fn drawn(backend: &mut dyn GraphicsBackend, image: &Image, columns: u16, rows: u16) -> String {
+        let mut out = vec![];
+        backend.draw(&mut out, image, columns, rows);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn kitty_sends_an_image_once_then_places_it() {
+        let mut kitty = Kitty::default();
+        let image = image(7, b"hello", &[&[RED]]);
+
+        assert_eq!(
+            drawn(&mut kitty, &image, 2, 1),
+            "\x1b_Ga=t,f=100,i=7,q=2,m=0;aGVsbG8=\x1b\\\x1b_Ga=p,i=7,c=2,r=1,C=1,q=2\x1b\\"
+        );
+        assert_eq!(
+            drawn(&mut kitty, &image, 3, 1),
+            "\x1b_Ga=p,i=7,c=3,r=1,C=1,q=2\x1b\\"
+        );
+
+        let mut out = vec![];
+        kitty.clear(&mut out);
+        assert_eq!(out, b"\x1b_Ga=d,d=a,q=2\x1b\\");
+    }
+
+    #[test]
+    fn kitty_splits_large_images_into_chunks() {
+        let mut kitty = Kitty::default();
+        // 3073 zeros are 4096 As and then AA== in base64.
+        let image = image(1, &[0; 3073], &[&[RED]]);
+
+        assert_eq!(
+            drawn(&mut kitty, &image, 1, 1),
+            format!(
+                "\x1b_Ga=t,f=100,i=1,q=2,m=1;{}\x1b\\\x1b_Gm=0;AA==\x1b\\\x1b_Ga=p,i=1,c=1,r=1,C=1,q=2\x1b\\",
+                "A".repeat(KITTY_CHUNK)
+            )
+        );
+    }
+
+    #[test]
+    fn iterm2_sends_the_file_inline() {
+        let image = image(1, b"hello", &[&[RED]]);
+        assert_eq!(
+            drawn(&mut Iterm2, &image, 2, 1),
+            "\x1b]1337;File=inline=1;size=5;width=2;height=1;preserveAspectRatio=0:aGVsbG8=\x07"
+        );
+    }
+
+    #[test]
+    fn sixel_draws_each_color_and_skips_transparent_pixels() {
+        let image = image(1, b"", &[&[RED, CLEAR], &[BLUE, RED]]);
+        // Blue is 5 and red 180 in the color cube. Red is the top left and
+        // bottom right pixel, @ and A, and blue the bottom left, A.
+        assert_eq!(
+            drawn(&mut Sixel, &image, 1, 1),
+            "\x1bP0;1;0q\"1;1;2;2#5;2;0;0;100#180;2;100;0;0#5A?$#180@A-\x1b\\"
+        );
+    }
+
+    #[test]
+    fn sixel_run_length_encodes_repeats() {
+        let image = image(1, b"", &[&[RED; 5]]);
+        assert_eq!(
+            drawn(&mut Sixel, &image, 1, 1),
+            "\x1bP0;1;0q\"1;1;5;1#180;2;100;0;0#180!5@-\x1b\\"
+        );
+    }
+
+    #[test]
+    fn half_blocks_color_the_top_and_bottom_of_each_cell() {
+        let mut half_blocks = HalfBlocks {
+            colors: ColorSupport::TrueColor,
+        };
+        let image = image(
+            1,
+            b"",
+            &[
+                &[RED, CLEAR],
+                &[BLUE, GREEN],
+                &[CLEAR, CLEAR],
+                &[CLEAR, WHITE],
+            ],
+        );
+        assert_eq!(
+            drawn(&mut half_blocks, &image, 2, 2),
+            concat!(
+                "\x1b[38;2;255;0;0;48;2;0;0;255m▀\x1b[0m",
+                "\x1b[38;2;0;255;0m▄\x1b[0m",
+                "\x1b[1B\x1b[2D",
+                "\x1b[1C",
+                "\x1b[38;2;255;255;255m▄\x1b[0m",
+            )
+        );
+    }
+
+    #[test]
+    fn braille_sets_the_dots_brighter_than_average() {
+        let image = image(
+            1,
+            b"",
+            &[
+                &[WHITE, BLACK],
+                &[WHITE, BLACK],
+                &[WHITE, BLACK],
+                &[WHITE, BLACK],
+            ],
+        );
+        let mut braille = Braille {
+            colors: ColorSupport::TrueColor,
+        };
+        assert_eq!(
+            drawn(&mut braille, &image, 1, 1),
+            "\x1b[38;2;255;255;255m⡇\x1b[0m"
+        );
+
+        let mut braille = Braille {
+            colors: ColorSupport::NoColor,
+        };
+        assert_eq!(drawn(&mut braille, &image, 1, 1), "⡇");
+    }
+}
@@ -0,0 +1,230 @@
+use regex::{Regex, RegexBuilder};
+use std::collections::HashSet;
+use tui::style::Style;
+
+use crate::color::parse_color;
+use crate::config::Config;
+use crate::protocol::ParsedMessage;
+use crate::theme::{self, Theme};
+use crate::utils;
+
+/// What a highlight rule looks for. A message matches if any part does.
+struct Rule {
+    /// Any of the rule's words, as whole words
+    words: Option<Regex>,
+    /// Lowercased nicks whose messages match
+    nicks: HashSet<String>,
+    pattern: Option<Regex>,
+    style: Style,
+}
+
+impl Rule {
+    fn matches(&self, message: &ParsedMessage) -> bool {
+        self.nicks.contains(&message.nick.to_lowercase())
+            || self
+                .words
+                .as_ref()
+                .is_some_and(|words| words.is_match(&message.data))
+            || self
+                .pattern
+                .as_ref()
+                .is_some_and(|pattern| pattern.is_match(&message.data))
+    }
+}
+
+/// Styles messages by who sent them and what they say.
+#[derive(Default)]
+pub struct Highlighter {
+    /// Lowercased
+    own_nick: Option<String>,
+    own_style: Style,
+    /// Mentions of our username first, then the configured rules in order
+    rules: Vec<Rule>,
+}
+
+impl Highlighter {
+    /// Builds the rules from the config, and returns why any were left out.
+    pub fn new(config: &Config, theme: &Theme) -> (Highlighter, Vec<String>) {
+        let highlight = &config.highlight;
+        let mut errors = vec![];
+        let mut rules = vec![];
+
+        if let Some(nick) = &config.username {
+            let mention = match &highlight.mention {
+                Some(mention) => theme::style(mention),
+                None => Ok(theme.mention),
+            };
+            match mention {
+                Ok(style) => rules.push(Rule {
+                    words: words_regex(std::slice::from_ref(nick)),
+                    nicks: HashSet::new(),
+                    pattern: None,
+                    style,
+                }),
+                Err(e) => errors.push(format!("Invalid mention style: {}", e)),
+            }
+        }
+
+        for rule in &highlight.rules {
+            let pattern = rule
+                .pattern
+                .as_ref()
+                .map(|pattern| {
+                    RegexBuilder::new(pattern)
+                        .case_insensitive(true)
+                        .build()
+                        .map_err(|e| {
+                            format!("Invalid highlight {}: {}", pattern, utils::regex_error(&e))
+                        })
+                })
+                .transpose();
+            let style =
+                theme::style(&rule.style).map_err(|e| format!("Invalid highlight style: {}", e));
+            match (pattern, style) {
+                (Ok(pattern), Ok(style)) => rules.push(Rule {
+                    words: words_regex(&rule.words),
+                    nicks: rule.nicks.iter().map(|nick| nick.to_lowercase()).collect(),
+                    pattern,
+                    style,
+                }),
+                (Err(e), _) | (_, Err(e)) => errors.push(e),
+            }
+        }
+
+        let own_style = match highlight.own_background.as_deref().map(parse_color) {
+            Some(Ok(color)) => Style::default().bg(color),
+            Some(Err(e)) => {
+                errors.push(format!("Invalid own_background: {}", e));
+                Style::default()
+            }
+            None => theme.own_message,
+        };
+
+        let highlighter = Highlighter {
+            own_nick: config.username.as_ref().map(|nick| nick.to_lowercase()),
+            own_style,
+            rules,
+        };
+        (highlighter, errors)
+    }
+
+    pub fn is_own(&self, nick: &str) -> bool {
+        self.own_nick
+            .as_ref()
+            .is_some_and(|own| own.eq_ignore_ascii_case(nick))
+    }
+
+    /// Style for our own lines.
+    pub fn own_style(&self) -> Style {
+        self.own_style
+    }
+
+    /// The style for our own messages, or of the first rule matching someone else's.
+    pub fn style(&self, message: &ParsedMessage) -> Option<Style> {
+        if self.is_own(&message.nick) {
+            return Some(self.own_style);
+        }
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(message))
+            .map(|rule| rule.style)
+    }
+
+    /// Whether a message from someone else matches any of the rules.
+    pub fn mentions(&self, message: &ParsedMessage) -> bool {
+        !self.is_own(&message.nick) && self.rules.iter().any(|rule| rule.matches(message))
+    }
+}
+
+/// Matches any of `words` on its own, not as part of a longer word.
+fn words_regex(words: &[String]) -> Option<Regex> {
+    if words.is_empty() {
+        return None;
+    }
+    let alternatives: Vec<String> = words.iter().map(|word| regex::escape(word)).collect();
+    RegexBuilder::new(&format!(r"\b(?:{})\b", alternatives.join("|")))
+        .case_insensitive(true)
+        .build()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HighlightConfig, HighlightRule, StyleConfig};
+    use tui::style::Color;
+
+    fn message(nick: &str, data: &str) -> ParsedMessage {
+        ParsedMessage {
+            nick: nick.to_string(),
+            features: vec![],
+            timestamp: 0,
+            data: data.to_string(),
+        }
+    }
+
+    fn fg(color: &str) -> StyleConfig {
+        StyleConfig {
+            fg: Some(color.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn words_only_match_whole_words() {
+        let regex = words_regex(&["bob".to_string(), "a.b".to_string()]).unwrap();
+        assert!(regex.is_match("@bob hi"));
+        assert!(regex.is_match("hi bob, how are you"));
+        assert!(regex.is_match("BOB"));
+        assert!(!regex.is_match("bobby"));
+        assert!(!regex.is_match("kebob"));
+        // Words are taken literally.
+        assert!(regex.is_match("a.b"));
+        assert!(!regex.is_match("acb"));
+
+        assert!(words_regex(&[]).is_none());
+    }
+
+    #[test]
+    fn mentions_come_first_then_rules_in_order() {
+        let config = Config {
+            username: Some("Me".to_string()),
+            highlight: HighlightConfig {
+                mention: Some(fg("red")),
+                rules: vec![
+                    HighlightRule {
+                        words: vec!["rust".to_string()],
+                        style: fg("green"),
+                        ..Default::default()
+                    },
+                    HighlightRule {
+                        nicks: vec!["Bob".to_string()],
+                        style: fg("blue"),
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (highlighter, errors) = Highlighter::new(&config, &Theme::default());
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let red = Some(Style::default().fg(Color::Red));
+        let green = Some(Style::default().fg(Color::Green));
+        let blue = Some(Style::default().fg(Color::Blue));
+        assert_eq!(highlighter.style(&message("bob", "me too, rust")), red);
+        assert_eq!(highlighter.style(&message("bob", "rust")), green);
+        assert_eq!(highlighter.style(&message("bob", "hello")), blue);
+        assert_eq!(highlighter.style(&message("alice", "hello")), None);
+        assert!(highlighter.mentions(&message("alice", "hi me")));
+        assert!(!highlighter.mentions(&message("alice", "meme")));
+
+        // Our own messages aren't highlighted, however they match.
+        assert_eq!(
+            highlighter.style(&message("ME", "rust")),
+            Some(highlighter.own_style())
+        );
+        assert!(!highlighter.mentions(&message("me", "me")));
+    }
+}
@@ -0,0 +1,120 @@
+use image::imageops::FilterType;
+use image::{ImageFormat, ImageResult};
+use std::fs;
+use std::path::Path;
+
+/// Size of a terminal cell in pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellSize {
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Default for CellSize {
+    /// What most terminal fonts come close to, for terminals that don't say.
+    fn default() -> CellSize {
+        CellSize {
+            width: 8,
+            height: 16,
+        }
+    }
+}
+
+impl CellSize {
+    /// Asks the terminal with TIOCGWINSZ. Not every terminal fills in the
+    /// pixel size, so this falls back to the default.
+    #[cfg(unix)]
+    pub fn detect() -> CellSize {
+        // SAFETY: TIOCGWINSZ only writes a winsize to the pointer it's given.
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+        if !ok || size.ws_col == 0 || size.ws_row == 0 || size.ws_xpixel == 0 || size.ws_ypixel == 0
+        {
+            return CellSize::default();
+        }
+        CellSize {
+            width: (size.ws_xpixel / size.ws_col).max(1),
+            height: (size.ws_ypixel / size.ws_row).max(1),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn detect() -> CellSize {
+        CellSize::default()
+    }
+
+    /// Pixel size of a `width` by `height` image scaled to one row, keeping
+    /// its aspect ratio.
+    pub fn fit(&self, width: u16, height: u16) -> (u32, u32) {
+        let row = self.height as u32;
+        let scaled = width as u32 * row / height.max(1) as u32;
+        (scaled.max(1), row)
+    }
+
+    /// How many columns a `width` by `height` image covers once scaled to
+    /// one row.
+    pub fn columns(&self, width: u16, height: u16) -> u16 {
+        let (scaled, _) = self.fit(width, height);
+        let cell = self.width.max(1) as u32;
+        scaled.div_ceil(cell).max(1) as u16
+    }
+}
+
+/// Decodes the image at `original`, which is `width` by `height` according to
+/// emotes.json, and saves it scaled to one row of `cell` at `scaled` as a PNG.
+/// Animated images keep their first frame.
+pub fn scale(
+    original: &Path,
+    scaled: &Path,
+    width: u16,
+    height: u16,
+    cell: CellSize,
+) -> ImageResult<()> {
+    let (width, height) = cell.fit(width, height);
+    let image = image::open(original)?.resize_exact(width, height, FilterType::Lanczos3);
+
+    if let Some(folder) = scaled.parent() {
+        fs::create_dir_all(folder)?;
+    }
+    // Saved next to it first so a half written image is never read.
+    let partial = scaled.with_extension("part");
+    image.save_with_format(&partial, ImageFormat::Png)?;
+    fs::rename(&partial, scaled)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CELL: CellSize = CellSize {
+        width: 8,
+        height: 16,
+    };
+
+    #[test]
+    fn fit_scales_to_one_row_keeping_the_aspect_ratio() {
+        assert_eq!(CELL.fit(32, 32), (16, 16));
+        assert_eq!(CELL.fit(112, 32), (56, 16));
+        assert_eq!(CELL.fit(8, 64), (2, 16));
+    }
+
+    #[test]
+    fn fit_survives_degenerate_sizes() {
+        assert_eq!(CELL.fit(1, 1000), (1, 16));
+        assert_eq!(CELL.fit(10, 0), (160, 16));
+        assert_eq!(CELL.fit(0, 10), (1, 16));
+    }
+
+    #[test]
+    fn columns_round_up_to_whole_cells() {
+        assert_eq!(CELL.columns(32, 32), 2);
+        assert_eq!(CELL.columns(34, 32), 3);
+        assert_eq!(CELL.columns(1, 1000), 1);
+        let zero = CellSize {
+            width: 0,
+            height: 16,
+        };
+        assert_eq!(zero.columns(32, 32), 16);
+    }
+}
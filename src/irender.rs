@@ -1,80 +1,314 @@
-use crossterm::cursor::MoveTo;
-use crossterm::execute;
-use std::io::stdout;
+use std::collections::HashMap;
+use std::io::{self, stdout, Write};
+use std::path::PathBuf;
 
-// use serde::{Deserialize, Serialize};
-use serde_json::Result as JSON_Result;
+use tui::layout::Rect;
 
-use crate::types;
-use crate::utils;
-use types::{EmoteData, ParsedMessage};
+use crate::emotes::{EmoteInfo, Emotes};
+use crate::graphics::{GraphicsBackend, Image};
 
-pub fn clear_all() {
-    print!("\x1b_Ga=d\x1b\\")
+/// An image on screen: column, row, columns wide, and the image.
+type Placement = (u16, u16, u16, PathBuf);
+
+/// Draws emote images with the backend picked for the terminal.
+pub struct Renderer {
+    backend: Box<dyn GraphicsBackend>,
+    /// Decoded images by path, None for ones that couldn't be read
+    images: HashMap<PathBuf, Option<Image>>,
+    /// What's on screen since the last draw
+    shown: Vec<Placement>,
+    /// Where the escapes go, the terminal outside of tests
+    output: Box<dyn Write>,
+}
+
+impl Renderer {
+    pub fn new(backend: Box<dyn GraphicsBackend>) -> Renderer {
+        Renderer::with_output(backend, Box::new(stdout()))
+    }
+
+    fn with_output(backend: Box<dyn GraphicsBackend>, output: Box<dyn Write>) -> Renderer {
+        Renderer {
+            backend,
+            images: HashMap::new(),
+            shown: vec![],
+            output,
+        }
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> {
+        let mut out = vec![];
+        self.backend.clear(&mut out);
+        self.shown.clear();
+        self.write_out(&out)
+    }
+
+    /// Brings the images on screen in line with the chat drawn with `layout`,
+    /// or removes them when the chat isn't shown. Nothing is sent when they're
+    /// already where they belong, unless `force`d because the terminal drew
+    /// over them.
+    pub fn update(
+        &mut self,
+        layout: Option<&ChatLayout>,
+        emotes: &Emotes,
+        force: bool,
+    ) -> io::Result<()> {
+        let placements = match layout {
+            Some(layout) => placements(layout, emotes),
+            None if self.shown.is_empty() => return Ok(()),
+            None => return self.clear(),
+        };
+        if force || placements != self.shown {
+            self.draw(placements)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the images on screen with `columns` wide, one row high images
+    /// at `x`, `y`.
+    fn draw(&mut self, placements: Vec<Placement>) -> io::Result<()> {
+        let mut out = vec![];
+        self.backend.clear(&mut out);
+        for (x, y, columns, path) in &placements {
+            let id = self.images.len() as u32 + 1;
+            let image = self
+                .images
+                .entry(path.to_owned())
+                .or_insert_with_key(|path| Image::load(id, path).ok());
+            if let Some(image) = image {
+                // Saves the cursor tui left behind, and puts it back after.
+                let _ = write!(out, "\x1b7\x1b[{};{}H", y + 1, x + 1);
+                self.backend.draw(&mut out, image, *columns, 1);
+                out.extend_from_slice(b"\x1b8");
+            }
+        }
+        self.shown = placements;
+        self.write_out(&out)
+    }
+
+    fn write_out(&mut self, out: &[u8]) -> io::Result<()> {
+        if out.is_empty() {
+            return Ok(());
+        }
+        self.output.write_all(out)?;
+        self.output.flush()
+    }
 }
 
-pub fn print_emote(x: u16, y: u16) -> Result<(), crossterm::ErrorKind> {
-    print!("\x1b_Ga=d,y={}\x1b\\", y + 1);
-    execute!(stdout(), MoveTo(x, y + 2))?;
-    print!("\x1b_Ga=p,i={}\x1b\\", 1);
+/// An emote image in a chat message: the line it's on, the column it starts
+/// at, and the emote.
+pub type EmoteCell = (usize, u16, EmoteInfo);
 
-    Ok(())
+/// Where the chat messages were put on the last draw, for the emote images to
+/// go over the cells left for them.
+#[derive(Debug, Default)]
+pub struct ChatLayout {
+    /// The message list inside its border
+    pub area: Rect,
+    /// The first message shown, and the one after the last
+    pub start: usize,
+    pub end: usize,
+    /// Columns the highlight symbol takes in front of every line
+    pub indent: u16,
+    /// Rows each message takes
+    pub heights: Vec<usize>,
+    /// The emote images of each message
+    pub emotes: Vec<Vec<EmoteCell>>,
 }
 
-pub fn print_one(_path: &str) -> Result<(), crossterm::ErrorKind> {
-    execute!(stdout(), MoveTo(10, 10))?;
-    print!("\x1b_Ga=p,i={}\x1b\\", 1);
-    Ok(())
+/// Where the images of the emotes on screen go.
+fn placements(layout: &ChatLayout, emotes: &Emotes) -> Vec<Placement> {
+    let area = layout.area;
+    let mut placed = vec![];
+    let mut top = area.y as usize;
+    for i in layout.start..layout.end {
+        for (line, column, emote) in layout.emotes.get(i).into_iter().flatten() {
+            let (columns, path) = match (emotes.columns(emote), emotes.image(emote)) {
+                (Some(columns), Some(path)) => (columns, path),
+                _ => continue,
+            };
+            let (x, y) = (area.x + layout.indent + column, top + line);
+            if y < area.bottom() as usize && x + columns <= area.right() {
+                placed.push((x, y as u16, columns, path));
+            }
+        }
+        top += layout.heights.get(i).copied().unwrap_or(0);
+    }
+    placed
 }
 
-pub fn emote_meme(res: EmoteData) -> Result<(), crossterm::ErrorKind> {
-    let max: isize = res.term_size as isize - 9;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterAction;
+    use crate::graphics::Kitty;
+    use crate::images::CellSize;
+    use crate::protocol::{Event, ParsedMessage};
+    use crate::types::{App, ChatLine, LocalMessage};
+    use crate::ui::ui;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::path::Path;
+    use std::rc::Rc;
+    use tui::{backend::TestBackend, Terminal};
 
-    let floor = |_: usize| -> usize {
-        if res.message_pos as isize - max <= 0 {
-            0
-        } else {
-            (res.message_pos as isize - max) as usize
+    /// Collects what the renderer sends to the terminal.
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Output {
+        fn take(&self) -> String {
+            String::from_utf8(self.0.take()).unwrap()
         }
-    };
-
-    let mut final_emotes: Vec<(u16, u16, String)> = Vec::new();
-
-    for (i, message) in res.messages[floor(0)..res.message_pos + 1]
-        .iter()
-        .enumerate()
-    {
-        let emote_names: Vec<String> = utils::get_emotenames();
-        let mut emote_pos: Vec<(usize, &str)> = vec![];
-
-        let parsed_output: JSON_Result<ParsedMessage>;
-        parsed_output = utils::parse_message(&message.0.as_str()[4..]);
-        let msg: ParsedMessage = parsed_output.unwrap();
-
-        for name in emote_names {
-            let mut pos: Vec<_> = msg
-                .data
-                .match_indices(&format!("{}", &name).to_string())
-                .collect();
-            emote_pos.append(&mut pos);
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
         }
 
-        if emote_pos.len() > 0 {
-            for pos in emote_pos.to_owned() {
-                final_emotes.push((
-                    i as u16,
-                    pos.0 as u16 + msg.nick.len() as u16 + 3,
-                    pos.1.to_string(),
-                ));
-            }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
         }
     }
 
-    if final_emotes.len() > 0 {
-        for pos in final_emotes.to_owned() {
-            utils::print_emote(pos.0, pos.1, pos.2.as_str());
+    fn message(nick: &str, data: &str) -> ParsedMessage {
+        ParsedMessage {
+            nick: nick.to_string(),
+            data: data.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// A chat with every kind of line, where AYAYA is shown as an image two
+    /// columns wide.
+    fn chat(folder: &Path) -> App<'static> {
+        let cell = CellSize {
+            width: 8,
+            height: 16,
+        };
+        let mut emotes = Emotes::for_tests(&["AYAYA"], folder, cell);
+        emotes.mark_ready("AYAYA");
+        let scaled = emotes.scaled(emotes.get("AYAYA").unwrap()).unwrap();
+        fs::create_dir_all(scaled.parent().unwrap()).unwrap();
+        image::RgbaImage::new(16, 16)
+            .save_with_format(&scaled, image::ImageFormat::Png)
+            .unwrap();
+
+        let mut app = App {
+            emotes,
+            ..Default::default()
+        };
+        app.message_list.items = vec![
+            ChatLine::Event(Event::Msg(message("Bob", "first AYAYA"))),
+            ChatLine::Event(Event::parse(
+                r#"SUBSCRIPTION {"nick":"Carol","data":"a message long enough to wrap the banner","tier":1}"#,
+            )),
+            ChatLine::Info("Connected".to_string()),
+            ChatLine::Error("Something went wrong".to_string()),
+            ChatLine::Local(LocalMessage {
+                id: 1,
+                message: message("me", "a pending message that wraps AYAYA over a few lines"),
+                failed: Some("You are muted".to_string()),
+            }),
+            ChatLine::Filtered(message("Dave", "AYAYA spam"), FilterAction::Hide),
+            ChatLine::Event(Event::Msg(message("Alice", "AYAYA AYAYA"))),
+        ];
+        app.message_list.bottom();
+        app
+    }
+
+    /// Draws the chat and returns where the emote images go, checking that
+    /// each one covers exactly the cells left for it.
+    fn drawn_placements(app: &mut App, height: u16) -> Vec<(u16, u16)> {
+        let mut terminal = Terminal::new(TestBackend::new(40, height)).unwrap();
+        terminal.draw(|f| ui(f, app)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let is_cell = |x, y| buffer.get(x, y).symbol == "\u{a0}";
+
+        let placed = placements(&app.chat_layout, &app.emotes);
+        for (x, y, columns, _) in &placed {
+            assert!(
+                (*x..x + columns).all(|x| is_cell(x, *y)),
+                "image at {}, {} isn't over an emote",
+                x,
+                y
+            );
         }
+        // Every emote on screen gets an image.
+        let area = app.chat_layout.area;
+        let starts = (area.top()..area.bottom())
+            .flat_map(|y| (area.left()..area.right()).map(move |x| (x, y)))
+            .filter(|&(x, y)| is_cell(x, y) && !(x > 0 && is_cell(x - 1, y)))
+            .count();
+        assert_eq!(starts, placed.len());
+
+        placed.into_iter().map(|(x, y, _, _)| (x, y)).collect()
+    }
+
+    #[test]
+    fn images_go_where_ui_left_room_for_them() {
+        let folder = std::env::temp_dir().join(format!("dggtui-layout-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        let mut app = chat(&folder);
+
+        // Everything fits, from the top of the list.
+        let placed = drawn_placements(&mut app, 30);
+        assert_eq!(placed, [(13, 4), (35, 10), (9, 13), (12, 13)]);
+
+        // Scrolled to the bottom with only the newest lines in view.
+        let placed = drawn_placements(&mut app, 16);
+        assert_eq!(placed, [(35, 6), (9, 9), (12, 9)]);
+
+        // The highlight symbol pushes every line over.
+        app.hold_scroll = true;
+        app.relayout_chat();
+        let placed = drawn_placements(&mut app, 16);
+        assert_eq!(placed, [(37, 6), (11, 9), (14, 9)]);
+        let _ = fs::remove_dir_all(&folder);
     }
 
-    Ok(())
+    #[test]
+    fn images_leave_with_the_chat_and_come_back_with_it() {
+        let folder = std::env::temp_dir().join(format!("dggtui-tabs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        let mut app = chat(&folder);
+        let output = Output::default();
+        let mut renderer =
+            Renderer::with_output(Box::new(Kitty::default()), Box::new(output.clone()));
+        let mut terminal = Terminal::new(TestBackend::new(40, 30)).unwrap();
+        let placements = "\x1b_Ga=p,";
+        let clear = "\x1b_Ga=d,d=a,q=2\x1b\\";
+
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        renderer
+            .update(Some(&app.chat_layout), &app.emotes, false)
+            .unwrap();
+        assert_eq!(output.take().matches(placements).count(), 4);
+
+        // Nothing moved, so nothing is sent.
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        renderer
+            .update(Some(&app.chat_layout), &app.emotes, false)
+            .unwrap();
+        assert_eq!(output.take(), "");
+
+        app.next_tab();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        renderer.update(None, &app.emotes, false).unwrap();
+        assert_eq!(output.take(), clear);
+        assert!(renderer.shown.is_empty());
+        renderer.update(None, &app.emotes, false).unwrap();
+        assert_eq!(output.take(), "");
+
+        app.select_tab(0);
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        renderer
+            .update(Some(&app.chat_layout), &app.emotes, false)
+            .unwrap();
+        let sent = output.take();
+        assert!(sent.starts_with(clear));
+        assert_eq!(sent.matches(placements).count(), 4);
+        let _ = fs::remove_dir_all(&folder);
+    }
 }
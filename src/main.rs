@@ -1,4 +1,3 @@
-use bytes::Bytes;
 use crossterm::{
     event::{DisableMouseCapture, EnableMouseCapture},
     execute,
@@ -8,23 +7,70 @@ use std::{io, time::Duration};
 
 use tui::{backend::CrosstermBackend, Terminal};
 
-use serde_json::Result as JSON_Result;
-
 use websocket_lite::Result;
 
-use tokio::sync::watch;
-
+mod bus;
+mod chatlog;
+mod cli;
+mod color;
+mod commands;
 mod config;
+mod connection;
+mod emotes;
+mod filter;
+mod flairs;
+mod graphics;
+mod highlight;
+mod images;
 mod irender;
+mod mentions;
+mod outbox;
+mod protocol;
+mod search;
+mod session;
+mod theme;
 mod threads;
 mod types;
 mod ui;
 mod utils;
+mod whispers;
 use crate::config::Config;
-use types::{EmoteData, InternalMessage, InternalMessageType};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = match cli::Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+
+    let opened = args
+        .replay
+        .as_deref()
+        .map(session::load)
+        .transpose()
+        .and_then(|replay| {
+            let recorder = args
+                .record
+                .as_deref()
+                .map(session::Recorder::create)
+                .transpose()?;
+            Ok((replay, recorder))
+        });
+    let (replay, recorder) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -32,51 +78,126 @@ async fn main() -> Result<()> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let conf = Config::init().await;
-    let mut app = types::App::default();
-
-    if conf.is_ok() {
-        app.config = conf.to_owned().unwrap();
-    }
-
-    let (tx, rx) = watch::channel("".to_string());
-    let (etx, erx) = watch::channel(EmoteData {
-        term_size: 0,
-        messages: vec![],
-        message_pos: 0,
-    });
-    let (mtx, mrx) = watch::channel("".to_string());
-    let (itx, irx) = watch::channel(InternalMessage {
-        message_type: InternalMessageType::COMMAND,
-        message: "Initialize".to_string(),
-        data: Bytes::new(),
-    });
-
-    tokio::spawn(async move {
-        threads::run_ws2(tx, mrx, itx, irx, conf.to_owned().unwrap())
-            .await
-            .unwrap_or_else(|e| {
-                eprintln!("{}", e);
-            })
-    });
-
-    // Emote thread
+    let (conf, config_error) = match Config::init().await {
+        Ok(conf) => (conf, None),
+        Err(e) => (
+            Config::default(),
+            Some(format!("{}, using the defaults", e)),
+        ),
+    };
+    let config = args.apply(conf);
+    let (flairs, emotes) = tokio::join!(
+        flairs::Flairs::load(&config.server.flairs_url),
+        emotes::Emotes::load(&config.server.emotes_url, &config.server.cdn_url),
+    );
+    let mut app = types::App {
+        config,
+        flairs,
+        emotes,
+        ..Default::default()
+    };
     if app.config.emotes {
-        tokio::spawn(async move {
-            threads::run_emotes(erx).await.unwrap_or_else(|e| {
-                eprintln!("{}", e);
-            })
-        });
+        app.emotes.fetch_images(images::CellSize::detect());
+        let capabilities = graphics::Capabilities::detect();
+        let backend = graphics::backend(
+            app.config.graphics,
+            capabilities,
+            color::ColorSupport::detect(),
+        );
+        app.renderer = Some(irender::Renderer::new(backend));
     }
-
-    for msg in utils::get_history().await? {
-        app.message_list.items.push((msg.to_owned(), 1));
+    let (filters, filter_errors) = filter::Filters::new(&app.config);
+    app.filters = filters;
+    let mut config_errors: Vec<String> = config_error.into_iter().collect();
+    config_errors.extend(filter_errors);
+    let (theme, theme_error) = theme::Theme::load(&app.config.theme);
+    app.theme = theme;
+    config_errors.extend(theme_error);
+    let (highlighter, highlight_errors) = highlight::Highlighter::new(&app.config, &app.theme);
+    app.highlighter = highlighter;
+    config_errors.extend(highlight_errors);
+
+    let (ui_bus, net_bus) = bus::channel();
+
+    let mut history_error = None;
+    let history: Vec<String> = match replay {
+        Some(frames) => {
+            let history = frames
+                .iter()
+                .filter(|frame| frame.direction == session::Direction::History)
+                .map(|frame| frame.frame.to_owned())
+                .collect();
+            let speed = args.speed.unwrap_or(1.0);
+            tokio::spawn(session::replay(net_bus, frames, speed));
+            history
+        }
+        None => {
+            let ws_recorder = recorder.to_owned();
+            let ws_config = app.config.clone();
+            tokio::spawn(async move {
+                threads::run_ws2(net_bus, ws_config, ws_recorder)
+                    .await
+                    .unwrap_or_else(|e| {
+                        eprintln!("{}", e);
+                    })
+            });
+
+            let history = match utils::get_history(&app.config.server.history_url).await {
+                Ok(history) => history,
+                Err(e) => {
+                    history_error = Some(format!("Could not load chat history: {}", e));
+                    vec![]
+                }
+            };
+            if let Some(recorder) = &recorder {
+                for frame in &history {
+                    recorder.record(session::Direction::History, frame);
+                }
+            }
+            history
+        }
+    };
+
+    let history = history
+        .iter()
+        .map(|frame| protocol::Event::parse(frame))
+        .filter(|event| app.shows(event))
+        .collect();
+    let logged = if app.config.scrollback.from_log && args.replay.is_none() {
+        chatlog::backfill(&app.config.scrollback, |event| app.shows(event))
+    } else {
+        vec![]
+    };
+    app.message_list
+        .items
+        .extend(chatlog::merge(logged, history));
+    app.refilter();
+    if args.replay.is_none() {
+        app.mentions = types::Mentions::load();
+    }
+    let history_messages: Vec<_> = app
+        .message_list
+        .items
+        .iter()
+        .filter_map(|line| match line {
+            types::ChatLine::Event(protocol::Event::Msg(message)) => Some(message.to_owned()),
+            _ => None,
+        })
+        .collect();
+    for message in &history_messages {
+        app.add_mention(message);
+    }
+    for reason in config_errors {
+        app.message_list.items.push(types::ChatLine::Error(reason));
+    }
+    if let Some(reason) = history_error {
+        app.message_list.items.push(types::ChatLine::Error(reason));
     }
 
     let tick_rate = Duration::from_millis(5);
 
     // create app and run it
-    let res = threads::run_app(&mut terminal, app, rx, etx, mtx, tick_rate);
+    let res = threads::run_app(&mut terminal, app, ui_bus, tick_rate);
 
     // restore terminal
     disable_raw_mode()?;
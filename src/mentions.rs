@@ -0,0 +1,53 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::config;
+use crate::protocol::ParsedMessage;
+
+/// How many of the saved mentions are loaded at startup.
+const HISTORY_LENGTH: usize = 500;
+
+fn mentions_path() -> std::io::Result<PathBuf> {
+    Ok(config::data_folder("mentions")?.join("mentions.jsonl"))
+}
+
+/// Holds the timestamp of the newest mention that has been seen.
+fn read_path() -> std::io::Result<PathBuf> {
+    Ok(config::data_folder("mentions")?.join("read"))
+}
+
+/// Mentions from earlier sessions, oldest first.
+pub fn load() -> Vec<ParsedMessage> {
+    let contents = match mentions_path().and_then(fs::read_to_string) {
+        Ok(contents) => contents,
+        Err(_) => return vec![],
+    };
+
+    let messages: Vec<ParsedMessage> = contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    let skip = messages.len().saturating_sub(HISTORY_LENGTH);
+    messages.into_iter().skip(skip).collect()
+}
+
+pub fn save(message: &ParsedMessage) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(mentions_path()?)?;
+    writeln!(file, "{}", serde_json::to_string(message)?)
+}
+
+pub fn load_read() -> u64 {
+    read_path()
+        .and_then(fs::read_to_string)
+        .ok()
+        .and_then(|contents| contents.trim().parse().ok())
+        .unwrap_or_default()
+}
+
+pub fn save_read(timestamp: u64) -> std::io::Result<()> {
+    fs::write(read_path()?, timestamp.to_string())
+}
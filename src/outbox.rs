@@ -0,0 +1,433 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::bus::Outgoing;
+use crate::config::RateLimitConfig;
+use crate::protocol::{ChatError, Command, Event};
+
+/// How often a throttled command is put back in the queue before giving up on it.
+const MAX_ATTEMPTS: u32 = 3;
+/// Longest we pause sending after the server complains.
+const MAX_PAUSE: Duration = Duration::from_secs(10);
+/// How long we wait for the server to echo or otherwise answer a command.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Allows `capacity` commands in a burst, refilling one every `interval`.
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    interval: Duration,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, interval: Duration) -> TokenBucket {
+        TokenBucket {
+            capacity: capacity.max(1) as f64,
+            tokens: capacity.max(1) as f64,
+            interval: interval.max(Duration::from_millis(1)),
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() / self.interval.as_secs_f64()).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// When the next token will be available.
+    fn ready_at(&self) -> Instant {
+        let missing = (1.0 - self.tokens).max(0.0);
+        self.last_refill + self.interval.mul_f64(missing)
+    }
+}
+
+struct Queued {
+    outgoing: Outgoing,
+    attempts: u32,
+}
+
+struct Pending {
+    queued: Queued,
+    sent_at: Instant,
+}
+
+/// What an ERR from the server meant for the commands we sent.
+pub enum Rejection {
+    /// Nothing waiting for an answer could have caused it, so the error is about
+    /// something else.
+    NotOurs,
+    /// The command was throttled and will be sent again.
+    Retrying,
+    /// `certain` is false when the error could have been about any command and
+    /// more than one was waiting, so the oldest was picked.
+    Failed { outgoing: Outgoing, certain: bool },
+}
+
+/// Commands waiting to go out, and the ones sent that the server hasn't answered yet.
+pub struct Outbox {
+    queue: VecDeque<Queued>,
+    pending: VecDeque<Pending>,
+    bucket: TokenBucket,
+    paused_until: Option<Instant>,
+    base_pause: Duration,
+    pause: Duration,
+    /// Our nick, from the config or once the server has told us
+    nick: Option<String>,
+}
+
+impl Outbox {
+    pub fn new(config: &RateLimitConfig, nick: Option<String>) -> Outbox {
+        let base_pause = Duration::from_millis(config.throttle_backoff_ms);
+        Outbox {
+            queue: VecDeque::new(),
+            pending: VecDeque::new(),
+            bucket: TokenBucket::new(config.burst, Duration::from_millis(config.interval_ms)),
+            paused_until: None,
+            base_pause,
+            pause: base_pause,
+            nick,
+        }
+    }
+
+    pub fn nick(&self) -> Option<&str> {
+        self.nick.as_deref()
+    }
+
+    /// Takes `nick` as ours, unless we already know it.
+    pub fn learn_nick(&mut self, nick: &str) {
+        if self.nick.is_none() && !nick.is_empty() {
+            self.nick = Some(nick.to_string());
+        }
+    }
+
+    pub fn push(&mut self, outgoing: Outgoing) {
+        self.queue.push_back(Queued {
+            outgoing,
+            attempts: 0,
+        });
+    }
+
+    /// The next command that may be sent right now. It is kept as pending until
+    /// the server answers it.
+    pub fn next_ready(&mut self, now: Instant) -> Option<Outgoing> {
+        if self.queue.is_empty() || self.paused_until.is_some_and(|until| now < until) {
+            return None;
+        }
+        if !self.bucket.take(now) {
+            return None;
+        }
+
+        let mut queued = self.queue.pop_front()?;
+        queued.attempts += 1;
+        let outgoing = queued.outgoing.clone();
+        self.pending.push_back(Pending {
+            queued,
+            sent_at: now,
+        });
+        Some(outgoing)
+    }
+
+    /// When there'll next be something to do, if anything.
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        let send = (!self.queue.is_empty()).then(|| {
+            let ready = self.bucket.ready_at();
+            self.paused_until.map_or(ready, |until| until.max(ready))
+        });
+        let expiry = self
+            .pending
+            .front()
+            .map(|pending| pending.sent_at + PENDING_TIMEOUT);
+
+        match (send, expiry) {
+            (Some(send), Some(expiry)) => Some(send.min(expiry)),
+            (send, expiry) => send.or(expiry),
+        }
+    }
+
+    /// Checks an event against what we're waiting for. The server answers in
+    /// order, so the first pending command that matches is the one confirmed.
+    /// Until we know our nick, an echo of the same text in order is taken as
+    /// ours, and tells us the nick.
+    pub fn confirm(&mut self, event: &Event) -> Option<Outgoing> {
+        let nick = self.nick.as_deref();
+        let i = self
+            .pending
+            .iter()
+            .position(|pending| answers(event, &pending.queued.outgoing.command, nick))?;
+        self.pause = self.base_pause;
+        if let Event::Msg(msg) = event {
+            self.learn_nick(&msg.nick);
+        }
+        self.pending
+            .remove(i)
+            .map(|pending| pending.queued.outgoing)
+    }
+
+    /// Attributes an ERR to the oldest pending command that could have caused
+    /// it. Throttled commands go back to the front of the queue, and sending
+    /// pauses a while on throttled and duplicate errors.
+    pub fn reject(&mut self, error: &ChatError, now: Instant) -> Rejection {
+        let code = error.description.as_str();
+        let candidates = self
+            .pending
+            .iter()
+            .filter(|pending| could_cause(code, &pending.queued.outgoing.command))
+            .count();
+        let i = self
+            .pending
+            .iter()
+            .position(|pending| could_cause(code, &pending.queued.outgoing.command));
+        let pending = match i.and_then(|i| self.pending.remove(i)) {
+            Some(pending) => pending,
+            None => return Rejection::NotOurs,
+        };
+        let certain = candidates == 1 || specific(code);
+
+        if code == "throttled" || code == "duplicate" {
+            self.paused_until = Some(now + self.pause);
+            self.pause = (self.pause * 2).min(MAX_PAUSE);
+        }
+
+        if code == "throttled" && pending.queued.attempts < MAX_ATTEMPTS {
+            self.queue.push_front(pending.queued);
+            Rejection::Retrying
+        } else {
+            Rejection::Failed {
+                outgoing: pending.queued.outgoing,
+                certain,
+            }
+        }
+    }
+
+    /// Stops waiting for an answer to the command with `id`.
+    pub fn forget(&mut self, id: u64) {
+        self.pending
+            .retain(|pending| pending.queued.outgoing.id != id);
+    }
+
+    /// Gives up on pending commands nobody answered in time.
+    pub fn expire(&mut self, now: Instant) -> Vec<Outgoing> {
+        let mut expired = vec![];
+        while let Some(pending) = self.pending.front() {
+            if now.saturating_duration_since(pending.sent_at) < PENDING_TIMEOUT {
+                break;
+            }
+            if let Some(pending) = self.pending.pop_front() {
+                expired.push(pending.queued.outgoing);
+            }
+        }
+        expired
+    }
+
+    /// The connection is gone, so nothing pending will be answered.
+    pub fn disconnected(&mut self) -> Vec<Outgoing> {
+        self.pending
+            .drain(..)
+            .map(|pending| pending.queued.outgoing)
+            .collect()
+    }
+
+    /// Number of queued and pending commands.
+    pub fn counts(&self) -> (usize, usize) {
+        (self.queue.len(), self.pending.len())
+    }
+}
+
+/// Whether `event` is the server carrying out `command`.
+fn answers(event: &Event, command: &Command, own_nick: Option<&str>) -> bool {
+    match (event, command) {
+        (Event::Msg(msg), Command::Msg(sent)) => {
+            own_nick.is_none_or(|nick| msg.nick.eq_ignore_ascii_case(nick))
+                && msg.data.trim() == sent.data.trim()
+        }
+        (Event::PrivMsgSent, Command::PrivMsg(_)) => true,
+        (Event::Mute(event), Command::Mute(mute)) => event.data.eq_ignore_ascii_case(&mute.data),
+        (Event::Unmute(event), Command::Unmute(unmute)) => {
+            event.data.eq_ignore_ascii_case(&unmute.data)
+        }
+        (Event::Ban(event), Command::Ban(ban)) => event.data.eq_ignore_ascii_case(&ban.nick),
+        (Event::Unban(event), Command::Unban(unban)) => {
+            event.data.eq_ignore_ascii_case(&unban.data)
+        }
+        (Event::SubOnly(_), Command::SubOnly(_)) => true,
+        _ => false,
+    }
+}
+
+/// Whether the server answers `command` with the ERR `code` when it refuses it.
+/// Codes we don't know could be about anything.
+fn could_cause(code: &str, command: &Command) -> bool {
+    match code {
+        "duplicate" | "submode" => matches!(command, Command::Msg(_)),
+        "throttled" | "muted" | "invalidmsg" => {
+            matches!(command, Command::Msg(_) | Command::PrivMsg(_))
+        }
+        "privmsgbanned" | "privmsgaccounttooyoung" => matches!(command, Command::PrivMsg(_)),
+        "notfound" => !matches!(command, Command::Msg(_)),
+        "nopermission" => command.is_moderation(),
+        "toomanyconnections" => false,
+        _ => true,
+    }
+}
+
+/// Whether the ERR `code` only ever answers one kind of command.
+fn specific(code: &str) -> bool {
+    matches!(
+        code,
+        "duplicate" | "submode" | "privmsgbanned" | "privmsgaccounttooyoung" | "nopermission"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Data, ParsedMessage, Whisper};
+
+    fn msg(id: u64, data: &str) -> Outgoing {
+        Outgoing {
+            id,
+            command: Command::Msg(Data {
+                data: data.to_string(),
+            }),
+        }
+    }
+
+    fn whisper(id: u64, nick: &str) -> Outgoing {
+        Outgoing {
+            id,
+            command: Command::PrivMsg(Whisper {
+                nick: nick.to_string(),
+                data: "psst".to_string(),
+            }),
+        }
+    }
+
+    fn echo(nick: &str, data: &str) -> Event {
+        Event::Msg(ParsedMessage {
+            nick: nick.to_string(),
+            data: data.to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn error(code: &str) -> ChatError {
+        ChatError {
+            description: code.to_string(),
+        }
+    }
+
+    /// An outbox with everything in `sent` sent and waiting for an answer.
+    fn sent(nick: Option<&str>, sent: Vec<Outgoing>) -> Outbox {
+        let config = RateLimitConfig {
+            burst: 10,
+            ..Default::default()
+        };
+        let mut outbox = Outbox::new(&config, nick.map(str::to_string));
+        let now = Instant::now();
+        for outgoing in sent {
+            outbox.push(outgoing);
+            outbox.next_ready(now);
+        }
+        outbox
+    }
+
+    #[test]
+    fn confirms_by_text_and_learns_the_nick() {
+        let mut outbox = sent(None, vec![msg(1, "hello"), msg(2, "again")]);
+
+        assert!(outbox.confirm(&echo("bob", "something else")).is_none());
+        assert_eq!(outbox.nick(), None);
+
+        assert_eq!(
+            outbox.confirm(&echo("tester", "hello")).map(|o| o.id),
+            Some(1)
+        );
+        assert_eq!(outbox.nick(), Some("tester"));
+
+        // Once the nick is known, someone else saying the same isn't our echo.
+        assert!(outbox.confirm(&echo("bob", "again")).is_none());
+        assert_eq!(
+            outbox.confirm(&echo("Tester", "again")).map(|o| o.id),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn configured_nick_is_not_replaced() {
+        let mut outbox = sent(Some("tester"), vec![msg(1, "hello")]);
+        outbox.learn_nick("someone");
+        assert!(outbox.confirm(&echo("someone", "hello")).is_none());
+        assert_eq!(outbox.nick(), Some("tester"));
+    }
+
+    #[test]
+    fn errors_go_to_the_command_that_caused_them() {
+        let mut outbox = sent(Some("tester"), vec![msg(1, "hello"), whisper(2, "bob")]);
+
+        match outbox.reject(&error("privmsgbanned"), Instant::now()) {
+            Rejection::Failed { outgoing, certain } => {
+                assert_eq!(outgoing.id, 2);
+                assert!(certain);
+            }
+            _ => panic!("the whisper should have failed"),
+        }
+        assert_eq!(outbox.counts(), (0, 1));
+    }
+
+    #[test]
+    fn errors_no_pending_command_causes_are_not_ours() {
+        let mut outbox = sent(Some("tester"), vec![msg(1, "hello")]);
+        assert!(matches!(
+            outbox.reject(&error("toomanyconnections"), Instant::now()),
+            Rejection::NotOurs
+        ));
+        assert!(matches!(
+            outbox.reject(&error("privmsgbanned"), Instant::now()),
+            Rejection::NotOurs
+        ));
+        assert_eq!(outbox.counts(), (0, 1));
+    }
+
+    #[test]
+    fn vague_errors_with_several_candidates_are_uncertain() {
+        let mut outbox = sent(Some("tester"), vec![msg(1, "hello"), whisper(2, "bob")]);
+        match outbox.reject(&error("protocolerror"), Instant::now()) {
+            Rejection::Failed { outgoing, certain } => {
+                assert_eq!(outgoing.id, 1);
+                assert!(!certain);
+            }
+            _ => panic!("the oldest command should have failed"),
+        }
+
+        match outbox.reject(&error("protocolerror"), Instant::now()) {
+            Rejection::Failed { outgoing, certain } => {
+                assert_eq!(outgoing.id, 2);
+                assert!(certain);
+            }
+            _ => panic!("the whisper should have failed"),
+        }
+    }
+
+    #[test]
+    fn throttled_commands_are_retried() {
+        let mut outbox = sent(Some("tester"), vec![msg(1, "hello")]);
+        assert!(matches!(
+            outbox.reject(&error("throttled"), Instant::now()),
+            Rejection::Retrying
+        ));
+        assert_eq!(outbox.counts(), (1, 0));
+    }
+}
@@ -0,0 +1,560 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// A single frame received from the chat websocket, e.g. `MSG {"nick": ...}`.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Msg(ParsedMessage),
+    Names(Users),
+    Join(Presence),
+    Quit(Presence),
+    PrivMsg(PrivateMessage),
+    /// Acknowledgement that a whisper we sent was delivered.
+    PrivMsgSent,
+    Mute(Moderation),
+    Unmute(Moderation),
+    Ban(Moderation),
+    Unban(Moderation),
+    SubOnly(SubOnly),
+    Broadcast(Broadcast),
+    Subscription(Subscription),
+    GiftSub(GiftSub),
+    MassGift(MassGift),
+    Donation(Donation),
+    Err(ChatError),
+    Refresh(User),
+    /// Who we're signed in as, sent on connect. None when signed out.
+    Me(Option<User>),
+    /// Anything we don't know how to parse.
+    Unknown,
+}
+
+impl Event {
+    pub fn parse(frame: &str) -> Event {
+        let (command, payload) = match frame.split_once(' ') {
+            Some(split) => split,
+            None => (frame, ""),
+        };
+
+        let event = match command {
+            "MSG" => parse_payload(payload).map(Event::Msg),
+            "NAMES" => parse_payload(payload).map(Event::Names),
+            "JOIN" => parse_payload(payload).map(Event::Join),
+            "QUIT" => parse_payload(payload).map(Event::Quit),
+            "PRIVMSG" => parse_payload(payload).map(Event::PrivMsg),
+            "PRIVMSGSENT" => Some(Event::PrivMsgSent),
+            "MUTE" => parse_payload(payload).map(Event::Mute),
+            "UNMUTE" => parse_payload(payload).map(Event::Unmute),
+            "BAN" => parse_payload(payload).map(Event::Ban),
+            "UNBAN" => parse_payload(payload).map(Event::Unban),
+            "SUBONLY" => parse_payload(payload).map(Event::SubOnly),
+            "BROADCAST" => parse_payload(payload).map(Event::Broadcast),
+            "SUBSCRIPTION" => parse_payload(payload).map(Event::Subscription),
+            "GIFTSUB" => parse_payload(payload).map(Event::GiftSub),
+            "MASSGIFT" => parse_payload(payload).map(Event::MassGift),
+            "DONATION" => parse_payload(payload).map(Event::Donation),
+            "ERR" => ChatError::parse(payload).map(Event::Err),
+            "REFRESH" => parse_payload(payload).map(Event::Refresh),
+            "ME" => parse_payload(payload).map(Event::Me),
+            _ => None,
+        };
+
+        event.unwrap_or(Event::Unknown)
+    }
+
+    /// When the server says this happened, in milliseconds.
+    pub fn timestamp(&self) -> Option<u64> {
+        match self {
+            Event::Msg(msg) => Some(msg.timestamp),
+            Event::Join(presence) | Event::Quit(presence) => Some(presence.timestamp),
+            Event::PrivMsg(whisper) => Some(whisper.timestamp),
+            Event::Mute(moderation)
+            | Event::Unmute(moderation)
+            | Event::Ban(moderation)
+            | Event::Unban(moderation) => Some(moderation.timestamp),
+            Event::SubOnly(subonly) => Some(subonly.timestamp),
+            Event::Broadcast(broadcast) => Some(broadcast.timestamp),
+            Event::Subscription(sub) => Some(sub.timestamp),
+            Event::GiftSub(gift) => Some(gift.timestamp),
+            Event::MassGift(gift) => Some(gift.timestamp),
+            Event::Donation(donation) => Some(donation.timestamp),
+            _ => None,
+        }
+    }
+
+    /// Broadcasts and paid announcements, shown as banners in the chat.
+    pub fn is_banner(&self) -> bool {
+        matches!(
+            self,
+            Event::Broadcast(_)
+                | Event::Subscription(_)
+                | Event::GiftSub(_)
+                | Event::MassGift(_)
+                | Event::Donation(_)
+        )
+    }
+}
+
+fn parse_payload<T: DeserializeOwned>(payload: &str) -> Option<T> {
+    serde_json::from_str(payload).ok()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ParsedMessage {
+    pub nick: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub timestamp: u64,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct User {
+    pub nick: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Users {
+    pub connectioncount: u16,
+    pub users: Vec<User>,
+}
+
+/// Payload of JOIN and QUIT.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Presence {
+    pub nick: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct PrivateMessage {
+    #[serde(default)]
+    pub messageid: u64,
+    pub nick: String,
+    #[serde(default)]
+    pub timestamp: u64,
+    pub data: String,
+}
+
+/// Payload of MUTE, UNMUTE, BAN and UNBAN. `nick` is the moderator, `data` the target.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Moderation {
+    pub nick: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub timestamp: u64,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SubOnly {
+    pub nick: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub timestamp: u64,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Broadcast {
+    #[serde(default)]
+    pub timestamp: u64,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Subscription {
+    pub nick: String,
+    #[serde(default)]
+    pub timestamp: u64,
+    /// The message the subscriber attached, may be empty
+    #[serde(default)]
+    pub data: String,
+    #[serde(default)]
+    pub tier: u8,
+    #[serde(default)]
+    pub tierlabel: String,
+    /// Months in a row
+    #[serde(default)]
+    pub streak: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct GiftSub {
+    /// Who paid for the gift
+    pub nick: String,
+    pub giftee: String,
+    #[serde(default)]
+    pub timestamp: u64,
+    #[serde(default)]
+    pub data: String,
+    #[serde(default)]
+    pub tier: u8,
+    #[serde(default)]
+    pub tierlabel: String,
+    /// Part of a MASSGIFT, which is announced on its own
+    #[serde(default)]
+    pub frommassgift: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct MassGift {
+    pub nick: String,
+    #[serde(default)]
+    pub timestamp: u64,
+    #[serde(default)]
+    pub data: String,
+    #[serde(default)]
+    pub tier: u8,
+    #[serde(default)]
+    pub tierlabel: String,
+    pub quantity: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Donation {
+    pub nick: String,
+    #[serde(default)]
+    pub timestamp: u64,
+    #[serde(default)]
+    pub data: String,
+    /// In cents
+    pub amount: u64,
+}
+
+/// ERR payloads are either a bare JSON string (`ERR "throttled"`) or an object
+/// with a `description` field, depending on the server version.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ChatError {
+    pub description: String,
+}
+
+impl ChatError {
+    /// What the error code means, in words.
+    pub fn message(&self) -> String {
+        let message = match self.description.as_str() {
+            "needlogin" => "You have to be signed in to do that",
+            "nopermission" => "You don't have permission to do that",
+            "protocolerror" => "The server didn't understand that",
+            "throttled" => "Throttled! You're sending messages too fast",
+            "duplicate" => "That message is identical to your last one",
+            "muted" => "You are muted",
+            "banned" => "You are banned",
+            "notfound" => "User not found",
+            "invalidmsg" => "That message isn't valid",
+            "submode" => "Chat is in subscriber only mode",
+            "privmsgbanned" => "You can't send whispers",
+            "privmsgaccounttooyoung" => "Your account is too new to send whispers",
+            "toomanyconnections" => "Too many connections from your account",
+            other => return format!("Server error: {}", other),
+        };
+        message.to_string()
+    }
+
+    fn parse(payload: &str) -> Option<ChatError> {
+        if let Ok(description) = serde_json::from_str::<String>(payload) {
+            return Some(ChatError { description });
+        }
+        parse_payload(payload)
+    }
+}
+
+/// A frame we send to the chat server.
+#[derive(Debug, Clone)]
+pub enum Command {
+    Msg(Data),
+    PrivMsg(Whisper),
+    Mute(MuteRequest),
+    Unmute(Data),
+    Ban(BanRequest),
+    Unban(Data),
+    SubOnly(Data),
+}
+
+impl Command {
+    /// Whether only moderators are allowed to send this.
+    pub fn is_moderation(&self) -> bool {
+        !matches!(self, Command::Msg(_) | Command::PrivMsg(_))
+    }
+
+    /// Short description for feedback lines, e.g. `mute Bob`.
+    pub fn describe(&self) -> String {
+        match self {
+            Command::Msg(_) => "send message".to_string(),
+            Command::PrivMsg(whisper) => format!("whisper {}", whisper.nick),
+            Command::Mute(mute) => format!("mute {}", mute.data),
+            Command::Unmute(data) => format!("unmute {}", data.data),
+            Command::Ban(ban) if ban.banip => format!("IP ban {}", ban.nick),
+            Command::Ban(ban) => format!("ban {}", ban.nick),
+            Command::Unban(data) => format!("unban {}", data.data),
+            Command::SubOnly(data) => format!("turn subscriber only mode {}", data.data),
+        }
+    }
+
+    pub fn to_frame(&self) -> serde_json::Result<String> {
+        let (command, payload) = match self {
+            Command::Msg(data) => ("MSG", serde_json::to_string(data)?),
+            Command::PrivMsg(whisper) => ("PRIVMSG", serde_json::to_string(whisper)?),
+            Command::Mute(mute) => ("MUTE", serde_json::to_string(mute)?),
+            Command::Unmute(data) => ("UNMUTE", serde_json::to_string(data)?),
+            Command::Ban(ban) => ("BAN", serde_json::to_string(ban)?),
+            Command::Unban(data) => ("UNBAN", serde_json::to_string(data)?),
+            Command::SubOnly(data) => ("SUBONLY", serde_json::to_string(data)?),
+        };
+        Ok(format!("{} {}", command, payload))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Data {
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Whisper {
+    pub nick: String,
+    pub data: String,
+}
+
+/// `data` is the nick to mute. Durations are in nanoseconds, like the server expects.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct MuteRequest {
+    pub data: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct BanRequest {
+    pub nick: String,
+    pub reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    pub ispermanent: bool,
+    pub banip: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_msg() {
+        let frame = r#"MSG {"nick":"Bob","features":["subscriber"],"timestamp":1650000000000,"data":"hello PEPE"}"#;
+        match Event::parse(frame) {
+            Event::Msg(msg) => {
+                assert_eq!(msg.nick, "Bob");
+                assert_eq!(msg.features, vec!["subscriber"]);
+                assert_eq!(msg.timestamp, 1650000000000);
+                assert_eq!(msg.data, "hello PEPE");
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_names() {
+        let frame = r#"NAMES {"connectioncount":3,"users":[{"nick":"Bob","features":[]},{"nick":"Alice","features":["moderator"]}]}"#;
+        match Event::parse(frame) {
+            Event::Names(names) => {
+                assert_eq!(names.connectioncount, 3);
+                assert_eq!(names.users.len(), 2);
+                assert_eq!(names.users[1].nick, "Alice");
+                assert_eq!(names.users[1].features, vec!["moderator"]);
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_join_and_quit() {
+        let join = r#"JOIN {"nick":"Bob","features":["subscriber"],"timestamp":1}"#;
+        let quit = r#"QUIT {"nick":"Bob","features":[],"timestamp":2}"#;
+        match Event::parse(join) {
+            Event::Join(presence) => {
+                assert_eq!(presence.nick, "Bob");
+                assert_eq!(presence.timestamp, 1);
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+        match Event::parse(quit) {
+            Event::Quit(presence) => {
+                assert_eq!(presence.nick, "Bob");
+                assert_eq!(presence.timestamp, 2);
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_whispers() {
+        let frame = r#"PRIVMSG {"messageid":42,"nick":"Carol","timestamp":3,"data":"psst"}"#;
+        match Event::parse(frame) {
+            Event::PrivMsg(whisper) => {
+                assert_eq!(whisper.messageid, 42);
+                assert_eq!(whisper.nick, "Carol");
+                assert_eq!(whisper.data, "psst");
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+        assert!(matches!(
+            Event::parse(r#"PRIVMSGSENT """#),
+            Event::PrivMsgSent
+        ));
+        assert!(matches!(Event::parse("PRIVMSGSENT"), Event::PrivMsgSent));
+    }
+
+    #[test]
+    fn parses_moderation() {
+        let payload = r#"{"nick":"Alice","features":["moderator"],"timestamp":4,"data":"Bob"}"#;
+        for command in ["MUTE", "UNMUTE", "BAN", "UNBAN"] {
+            let event = Event::parse(&format!("{} {}", command, payload));
+            let moderation = match (command, event) {
+                ("MUTE", Event::Mute(moderation))
+                | ("UNMUTE", Event::Unmute(moderation))
+                | ("BAN", Event::Ban(moderation))
+                | ("UNBAN", Event::Unban(moderation)) => moderation,
+                (command, other) => panic!("{} parsed as {:?}", command, other),
+            };
+            assert_eq!(moderation.nick, "Alice");
+            assert_eq!(moderation.data, "Bob");
+            assert_eq!(moderation.timestamp, 4);
+        }
+    }
+
+    #[test]
+    fn parses_subonly() {
+        let frame = r#"SUBONLY {"nick":"Alice","features":[],"timestamp":5,"data":"on"}"#;
+        match Event::parse(frame) {
+            Event::SubOnly(subonly) => {
+                assert_eq!(subonly.nick, "Alice");
+                assert_eq!(subonly.data, "on");
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_banners() {
+        let broadcast = Event::parse(r#"BROADCAST {"timestamp":6,"data":"Stream is live"}"#);
+        match &broadcast {
+            Event::Broadcast(broadcast) => assert_eq!(broadcast.data, "Stream is live"),
+            other => panic!("parsed as {:?}", other),
+        }
+        assert!(broadcast.is_banner());
+        assert_eq!(broadcast.timestamp(), Some(6));
+
+        let frame = r#"SUBSCRIPTION {"nick":"Bob","timestamp":7,"data":"hi","tier":2,"tierlabel":"Tier II","streak":5}"#;
+        match Event::parse(frame) {
+            Event::Subscription(sub) => {
+                assert_eq!(sub.nick, "Bob");
+                assert_eq!(sub.tier, 2);
+                assert_eq!(sub.tierlabel, "Tier II");
+                assert_eq!(sub.streak, 5);
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+
+        let frame = r#"GIFTSUB {"nick":"Bob","giftee":"Carol","timestamp":8,"tier":1,"tierlabel":"Tier I","frommassgift":true}"#;
+        match Event::parse(frame) {
+            Event::GiftSub(gift) => {
+                assert_eq!(gift.nick, "Bob");
+                assert_eq!(gift.giftee, "Carol");
+                assert!(gift.frommassgift);
+                assert_eq!(gift.data, "");
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+
+        let frame = r#"MASSGIFT {"nick":"Bob","timestamp":9,"tier":3,"tierlabel":"Tier III","quantity":10}"#;
+        match Event::parse(frame) {
+            Event::MassGift(gift) => {
+                assert_eq!(gift.quantity, 10);
+                assert_eq!(gift.tier, 3);
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+
+        let frame = r#"DONATION {"nick":"Bob","timestamp":10,"data":"keep it up","amount":500}"#;
+        match Event::parse(frame) {
+            Event::Donation(donation) => {
+                assert_eq!(donation.amount, 500);
+                assert_eq!(donation.data, "keep it up");
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_errors_in_both_forms() {
+        match Event::parse(r#"ERR "throttled""#) {
+            Event::Err(error) => assert_eq!(error.description, "throttled"),
+            other => panic!("parsed as {:?}", other),
+        }
+        match Event::parse(r#"ERR {"description":"muted"}"#) {
+            Event::Err(error) => assert_eq!(error.message(), "You are muted"),
+            other => panic!("parsed as {:?}", other),
+        }
+        match Event::parse(r#"ERR "somethingnew""#) {
+            Event::Err(error) => assert_eq!(error.message(), "Server error: somethingnew"),
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_refresh() {
+        match Event::parse(r#"REFRESH {"nick":"Bob","features":["flair3"]}"#) {
+            Event::Refresh(user) => {
+                assert_eq!(user.nick, "Bob");
+                assert_eq!(user.features, vec!["flair3"]);
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_me() {
+        match Event::parse(r#"ME {"nick":"tester","features":["subscriber"]}"#) {
+            Event::Me(Some(user)) => assert_eq!(user.nick, "tester"),
+            other => panic!("parsed as {:?}", other),
+        }
+        assert!(matches!(Event::parse("ME null"), Event::Me(None)));
+    }
+
+    #[test]
+    fn malformed_frames_are_unknown() {
+        for frame in [
+            "",
+            "MSG",
+            "MSG not json",
+            r#"MSG {"nick":"Bob""#,
+            r#"MSG {"data":"no nick"}"#,
+            r#"NAMES {"users":"nope"}"#,
+            r#"MASSGIFT {"nick":"Bob"}"#,
+            "ERR 42",
+        ] {
+            assert!(
+                matches!(Event::parse(frame), Event::Unknown),
+                "{:?} should be unknown",
+                frame
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_commands_are_unknown() {
+        assert!(matches!(Event::parse(r#"PING {"data":1}"#), Event::Unknown));
+        assert!(matches!(
+            Event::parse(r#"msg {"nick":"Bob","data":"lowercase"}"#),
+            Event::Unknown
+        ));
+    }
+}
@@ -0,0 +1,342 @@
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+use std::fs;
+use std::ops::Range;
+use tui::style::Style;
+use tui::text::{Span, Spans};
+
+use crate::chatlog;
+use crate::protocol::{Event, ParsedMessage};
+use crate::types::ChatLine;
+use crate::utils;
+
+/// Most results a search of the chat log returns, newest kept.
+const MAX_LOG_RESULTS: usize = 500;
+
+enum Pattern {
+    Any,
+    /// Lowercased, matched anywhere in the message, and its words to highlight
+    Text(String, Regex),
+    Regex(Regex),
+}
+
+/// What the user typed after `/`: words to look for, or a `/regex/`, narrowed
+/// down with `from:nick`. `in:log` searches the chat log on disk too.
+pub struct Query {
+    pub text: String,
+    nick: Option<String>,
+    pattern: Pattern,
+    pub in_log: bool,
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Query, String> {
+        let mut nick = None;
+        let mut in_log = false;
+        let mut words = vec![];
+
+        for word in input.split_whitespace() {
+            if let Some(from) = word.strip_prefix("from:") {
+                nick = Some(from.to_lowercase());
+            } else if word == "in:log" {
+                in_log = true;
+            } else {
+                words.push(word);
+            }
+        }
+
+        let rest = words.join(" ");
+        let pattern = if rest.is_empty() {
+            Pattern::Any
+        } else if rest.len() > 1 && rest.starts_with('/') && rest.ends_with('/') {
+            let regex = RegexBuilder::new(&rest[1..rest.len() - 1])
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| format!("Invalid regex: {}", utils::regex_error(&e)))?;
+            Pattern::Regex(regex)
+        } else {
+            let words: Vec<String> = words.iter().map(|word| regex::escape(word)).collect();
+            let highlight = RegexBuilder::new(&words.join("|"))
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| format!("Invalid search: {}", utils::regex_error(&e)))?;
+            Pattern::Text(rest.to_lowercase(), highlight)
+        };
+
+        if nick.is_none() && matches!(pattern, Pattern::Any) {
+            return Err("Nothing to search for".to_string());
+        }
+
+        Ok(Query {
+            text: input.trim().to_string(),
+            nick,
+            pattern,
+            in_log,
+        })
+    }
+
+    pub fn matches(&self, message: &ParsedMessage) -> bool {
+        if let Some(nick) = &self.nick {
+            if message.nick.to_lowercase() != *nick {
+                return false;
+            }
+        }
+
+        match &self.pattern {
+            Pattern::Any => true,
+            Pattern::Text(text, _) => message.data.to_lowercase().contains(text),
+            Pattern::Regex(regex) => regex.is_match(&message.data),
+        }
+    }
+
+    pub fn matches_line(&self, line: &ChatLine) -> bool {
+        match line {
+            ChatLine::Event(Event::Msg(message)) => self.matches(message),
+            ChatLine::Local(local) => self.matches(&local.message),
+            _ => false,
+        }
+    }
+
+    /// Where the search matched in the text of a message, in order and not overlapping.
+    fn match_ranges(&self, data: &str) -> Vec<Range<usize>> {
+        let regex = match &self.pattern {
+            Pattern::Any => return vec![],
+            Pattern::Text(_, regex) | Pattern::Regex(regex) => regex,
+        };
+        let mut ranges: Vec<Range<usize>> = vec![];
+        for found in regex
+            .find_iter(data)
+            .filter(|found| !found.range().is_empty())
+        {
+            match ranges.last_mut() {
+                Some(last) if found.start() <= last.end => last.end = last.end.max(found.end()),
+                _ => ranges.push(found.range()),
+            }
+        }
+        ranges
+    }
+
+    /// Marks what matched in the lines `format_message` built for `message`,
+    /// splitting words the match starts or ends inside of.
+    pub fn highlight(&self, message: &ParsedMessage, lines: &mut [Spans], style: Style) {
+        let ranges = self.match_ranges(&message.data);
+        if ranges.is_empty() {
+            return;
+        }
+
+        // Find each span in the message text, carrying on from the one before.
+        let mut cursor = 0;
+        for (i, line) in lines.iter_mut().enumerate() {
+            // The nick comes first on the first line.
+            let skip = if i == 0 { 1 } else { 0 };
+            let mut spans = line.0.split_off(skip.min(line.0.len()));
+            for span in spans.drain(..) {
+                let start = match message.data[cursor..].find(span.content.as_ref()) {
+                    Some(found) if !span.content.is_empty() => cursor + found,
+                    // Emotes drawn as images aren't in the text.
+                    _ => {
+                        line.0.push(span);
+                        continue;
+                    }
+                };
+                cursor = start + span.content.len();
+                line.0.extend(split_span(span, start, &ranges, style));
+            }
+        }
+    }
+}
+
+/// Cuts a span found at `start` of the message text where the matched `ranges`
+/// begin and end, styling the matched parts.
+fn split_span<'a>(
+    span: Span<'a>,
+    start: usize,
+    ranges: &[Range<usize>],
+    style: Style,
+) -> Vec<Span<'a>> {
+    let end = start + span.content.len();
+    let mut pieces = vec![];
+    let mut at = start;
+    for range in ranges
+        .iter()
+        .filter(|range| range.start < end && range.end > start)
+    {
+        let from = range.start.max(start);
+        let to = range.end.min(end);
+        if from > at {
+            pieces.push(Span::styled(
+                span.content[at - start..from - start].to_string(),
+                span.style,
+            ));
+        }
+        pieces.push(Span::styled(
+            span.content[from - start..to - start].to_string(),
+            style,
+        ));
+        at = to;
+    }
+    if pieces.is_empty() {
+        return vec![span];
+    }
+    if at < end {
+        pieces.push(Span::styled(
+            span.content[at - start..].to_string(),
+            span.style,
+        ));
+    }
+    pieces
+}
+
+/// The messages in the chat log matching `query`, oldest first.
+pub fn search_log(query: &Query) -> Vec<ParsedMessage> {
+    let files = chatlog::daily_logs();
+
+    let mut results = vec![];
+    'files: for path in files.iter().rev() {
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        for line in contents.lines().rev() {
+            let record: Value = match serde_json::from_str(line) {
+                Ok(record) => record,
+                Err(_) => continue,
+            };
+            if record["type"] != "MSG" {
+                continue;
+            }
+            if let Ok(message) = serde_json::from_value::<ParsedMessage>(record["payload"].clone())
+            {
+                if query.matches(&message) {
+                    results.push(message);
+                    if results.len() >= MAX_LOG_RESULTS {
+                        break 'files;
+                    }
+                }
+            }
+        }
+    }
+
+    results.reverse();
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tui::style::Color;
+
+    fn message(nick: &str, data: &str) -> ParsedMessage {
+        ParsedMessage {
+            nick: nick.to_string(),
+            features: vec![],
+            timestamp: 0,
+            data: data.to_string(),
+        }
+    }
+
+    /// The highlighted text, one string per run of highlighted spans on a line.
+    fn highlighted(query: &str, data: &str, lines: &[&str]) -> Vec<String> {
+        let query = Query::parse(query).unwrap();
+        let message = message("alice", data);
+        let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        let mut lines =
+            utils::format_lines(&message, &lines, &Default::default(), &Default::default());
+        let style = Style::default().bg(Color::Yellow);
+        query.highlight(&message, &mut lines, style);
+
+        let mut runs: Vec<String> = vec![];
+        for line in &lines {
+            let mut in_run = false;
+            for span in &line.0 {
+                if span.style == style {
+                    match runs.last_mut() {
+                        Some(run) if in_run => run.push_str(&span.content),
+                        _ => runs.push(span.content.to_string()),
+                    }
+                    in_run = true;
+                } else {
+                    in_run = false;
+                }
+            }
+        }
+        runs
+    }
+
+    #[test]
+    fn parses_words_nicks_regexes_and_the_log() {
+        let query = Query::parse("  Hello   there from:Bob ").unwrap();
+        assert!(matches!(&query.pattern, Pattern::Text(text, _) if text == "hello there"));
+        assert_eq!(query.nick.as_deref(), Some("bob"));
+        assert!(!query.in_log);
+        assert_eq!(query.text, "Hello   there from:Bob");
+
+        let query = Query::parse("/^a+h$/ in:log").unwrap();
+        assert!(matches!(query.pattern, Pattern::Regex(_)));
+        assert!(query.in_log);
+
+        let query = Query::parse("from:bob").unwrap();
+        assert!(matches!(query.pattern, Pattern::Any));
+
+        assert!(matches!(Query::parse("/(/"), Err(e) if e.starts_with("Invalid regex")));
+        assert!(Query::parse("in:log").is_err());
+        assert!(Query::parse("   ").is_err());
+    }
+
+    #[test]
+    fn matches_text_anywhere_ignoring_case() {
+        let query = Query::parse("good MORNING").unwrap();
+        assert!(query.matches(&message("alice", "Good morning everyone")));
+        assert!(!query.matches(&message("alice", "good evening, morning")));
+    }
+
+    #[test]
+    fn from_narrows_down_to_a_nick() {
+        let query = Query::parse("from:Bob hi").unwrap();
+        assert!(query.matches(&message("BOB", "hi there")));
+        assert!(!query.matches(&message("alice", "hi there")));
+        assert!(!query.matches(&message("bob", "hello")));
+
+        let query = Query::parse("from:bob").unwrap();
+        assert!(query.matches(&message("Bob", "anything")));
+    }
+
+    #[test]
+    fn regexes_match_the_whole_message() {
+        let query = Query::parse("/^good \\w+ing$/ in:log").unwrap();
+        assert!(query.matches(&message("alice", "GOOD MORNING")));
+        assert!(!query.matches(&message("alice", "a good morning")));
+        assert!(query.in_log);
+    }
+
+    #[test]
+    fn highlights_regex_matches_across_words() {
+        assert_eq!(
+            highlighted("/good morn/", "well good morning", &["well good morning"]),
+            vec!["good morn"]
+        );
+        // Even when the message wraps in between.
+        assert_eq!(
+            highlighted(
+                "/good morn/",
+                "well good morning",
+                &["well good", "morning"]
+            ),
+            vec!["good ", "morn"]
+        );
+        assert_eq!(
+            highlighted("/o+/", "foo bar boo", &["foo bar boo"]),
+            vec!["oo", "oo"]
+        );
+    }
+
+    #[test]
+    fn highlights_every_word_of_a_text_search() {
+        assert_eq!(
+            highlighted("bar FOO", "foobar baz foo", &["foobar baz foo"]),
+            vec!["foobar", "foo"]
+        );
+        // The nick isn't part of the message.
+        assert_eq!(
+            highlighted("alice", "hi alice", &["hi alice"]),
+            vec!["alice"]
+        );
+    }
+}
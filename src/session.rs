@@ -0,0 +1,238 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::bus::{BusEvent, NetBus, Outgoing};
+use crate::connection::ConnectionState;
+use crate::protocol::Event;
+use crate::utils;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Fetched from the history endpoint at startup
+    History,
+    In,
+    Out,
+}
+
+/// One frame of a recorded session.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedFrame {
+    pub timestamp: u64,
+    pub direction: Direction,
+    pub frame: String,
+}
+
+/// Appends frames to a JSON lines file. Clones share the file.
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    /// Starts a new recording. An existing file is left alone rather than mixing
+    /// two sessions in it.
+    pub fn create(path: &Path) -> std::io::Result<Recorder> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| match e.kind() {
+                ErrorKind::AlreadyExists => std::io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} already exists, record to a new file", path.display()),
+                ),
+                _ => e,
+            })?;
+        Ok(Recorder {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn record(&self, direction: Direction, frame: &str) {
+        let line = RecordedFrame {
+            timestamp: utils::timestamp(),
+            direction,
+            frame: frame.to_string(),
+        };
+        if let (Ok(mut file), Ok(line)) = (self.file.lock(), serde_json::to_string(&line)) {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+}
+
+/// Reads a recording, skipping lines that don't parse.
+pub fn load(path: &Path) -> std::io::Result<Vec<RecordedFrame>> {
+    Ok(parse(&fs::read_to_string(path)?))
+}
+
+fn parse(recording: &str) -> Vec<RecordedFrame> {
+    recording
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// Plays the inbound frames of a recording to the UI in place of `run_ws2`,
+/// keeping their original spacing divided by `speed`. Commands from the UI
+/// fail straight away, since there's no server to send them to.
+pub async fn replay(bus: NetBus, frames: Vec<RecordedFrame>, speed: f64) {
+    let NetBus {
+        events,
+        mut commands,
+    } = bus;
+    let _ = events
+        .send(BusEvent::Connection(ConnectionState::Connected))
+        .await;
+
+    let mut previous = None;
+    for frame in frames.iter().filter(|f| f.direction == Direction::In) {
+        if let Some(previous) = previous {
+            let gap = Duration::from_millis(frame.timestamp.saturating_sub(previous));
+            let sleep = tokio::time::sleep(gap.div_f64(speed));
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    command = commands.recv() => match command {
+                        Some(outgoing) => refuse(&events, outgoing).await,
+                        None => return,
+                    },
+                }
+            }
+        }
+        previous = Some(frame.timestamp);
+
+        let event = BusEvent::Chat(Event::parse(&frame.frame));
+        if events.send(event).await.is_err() {
+            return;
+        }
+    }
+
+    let state = ConnectionState::Failed("End of replay".to_string());
+    let _ = events.send(BusEvent::Connection(state)).await;
+    while let Some(outgoing) = commands.recv().await {
+        refuse(&events, outgoing).await;
+    }
+}
+
+async fn refuse(events: &mpsc::Sender<BusEvent>, outgoing: Outgoing) {
+    let reason = "Replaying a recording, nothing is sent".to_string();
+    let _ = events.send(BusEvent::SendFailed(outgoing, reason)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus;
+    use crate::protocol::{Command, Data};
+    use crate::threads::handle_bus_event;
+    use crate::types::App;
+    use crate::ui::ui;
+    use tui::{backend::TestBackend, Terminal};
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/session.jsonl");
+
+    fn rendered(app: &mut App, width: u16, height: u16) -> Vec<String> {
+        let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+        terminal.draw(|f| ui(f, app)).unwrap();
+        let buffer = terminal.backend().buffer();
+        (0..height)
+            .map(|y| {
+                (0..width)
+                    .map(|x| buffer.get(x, y).symbol.as_str())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn skips_lines_that_are_not_frames() {
+        let frames = parse(FIXTURE);
+        assert_eq!(frames.len(), 6);
+        assert_eq!(frames[0].direction, Direction::History);
+        assert_eq!(frames[3].direction, Direction::Out);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_a_recording_into_the_chat() {
+        let (mut ui_bus, net_bus) = bus::channel();
+        let started = tokio::time::Instant::now();
+        tokio::spawn(replay(net_bus, parse(FIXTURE), 2.0));
+
+        let mut app = App::default();
+        while let Some(event) = ui_bus.events.recv().await {
+            let done = matches!(&event, BusEvent::Connection(ConnectionState::Failed(_)));
+            handle_bus_event(&mut app, event);
+            if done {
+                break;
+            }
+        }
+        // Three seconds of recording at twice the speed, on a clock that skips
+        // ahead whenever everything is waiting.
+        assert_eq!(started.elapsed(), Duration::from_millis(1500));
+
+        app.message_list.bottom();
+        let screen = rendered(&mut app, 72, 14);
+        assert_eq!(
+            screen,
+            [
+                "┌Tabs──────────────────────────────────────────────────────────────────┐",
+                "│ Chat │ Users │ Mentions                                              │",
+                "└──────────────────────────────────────────────────────────────────────┘",
+                "┌Messages──────────────────────────────────────────────────────────────┐",
+                "│<Bob> hello from the recording                                        │",
+                "│<Alice> > greentext                                                   │",
+                "│* Alice muted Bob                                                     │",
+                "│                                                                      │",
+                "│                                                                      │",
+                "└──────────────────────────────────────────────────────────────────────┘",
+                "┌──────────────────────────────────────────────────────────────────────┐",
+                "│                                                                      │",
+                "└──────────────────────────────────────────────────────────────────────┘",
+                "Press q to exit, e to start typing.          Disconnected: End of replay",
+            ]
+        );
+        assert_eq!(app.users.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_fail_while_replaying() {
+        let (mut ui_bus, net_bus) = bus::channel();
+        tokio::spawn(replay(net_bus, parse(FIXTURE), 1.0));
+
+        let command = Command::Msg(Data {
+            data: "hello?".to_string(),
+        });
+        let id = ui_bus.send(command).unwrap();
+        loop {
+            match ui_bus.events.recv().await {
+                Some(BusEvent::SendFailed(outgoing, reason)) => {
+                    assert_eq!(outgoing.id, id);
+                    assert_eq!(reason, "Replaying a recording, nothing is sent");
+                    break;
+                }
+                Some(_) => {}
+                None => panic!("the replay ended without answering"),
+            }
+        }
+    }
+
+    #[test]
+    fn recording_does_not_overwrite() {
+        let path = std::env::temp_dir().join(format!("dggtui-record-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.record(Direction::In, "MSG {}");
+
+        let error = Recorder::create(&path).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(load(&path).unwrap().len(), 1);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,522 @@
+use ::config::{Config as Config_c, File};
+use serde::Deserialize;
+use tui::style::{Color, Modifier, Style};
+
+use crate::color::{adapt, parse_color, ColorSupport};
+use crate::config::{self, StyleConfig};
+
+/// Styles for every kind of element on screen.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub tab: Style,
+    /// First letter of a tab title
+    pub tab_hotkey: Style,
+    pub tab_title: Style,
+    pub tab_selected: Style,
+    /// Unread counts and new mention markers
+    pub unread: Style,
+    pub border: Style,
+    pub help: Style,
+    /// Keys named in the help line
+    pub help_key: Style,
+    pub input: Style,
+    pub input_editing: Style,
+    pub input_search: Style,
+    /// Nicks without a flair color
+    pub nick: Style,
+    pub greentext: Style,
+    pub link: Style,
+    pub nsfw: Style,
+    pub nsfl: Style,
+    pub search_match: Style,
+    pub mention: Style,
+    pub own_message: Style,
+    pub timestamp: Style,
+    pub info: Style,
+    pub error: Style,
+    /// Separators, hidden messages and messages that weren't sent
+    pub muted: Style,
+    /// Messages on their way to the server
+    pub pending: Style,
+    pub status: Style,
+    pub status_error: Style,
+    pub connected: Style,
+    pub connecting: Style,
+    pub disconnected: Style,
+    /// Autocomplete suggestions
+    pub popup: Style,
+    /// Selected line in the Users tab
+    pub selection: Style,
+    /// Connection counts in the Users tab
+    pub connections: Style,
+    pub broadcast: Style,
+    pub donation: Style,
+    /// Subscriptions of a tier without a style of its own
+    pub subscription: Style,
+    pub tier_1: Style,
+    pub tier_2: Style,
+    pub tier_3: Style,
+    pub tier_4: Style,
+    pub tier_6: Style,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::dark()
+    }
+}
+
+fn fg(color: Color) -> Style {
+    Style::default().fg(color)
+}
+
+fn bold(color: Color) -> Style {
+    fg(color).add_modifier(Modifier::BOLD)
+}
+
+/// Bold black text on `color`.
+fn banner(color: Color) -> Style {
+    bold(Color::Black).bg(color)
+}
+
+impl Theme {
+    pub fn dark() -> Theme {
+        Theme {
+            tab: fg(Color::Cyan),
+            tab_hotkey: fg(Color::Yellow),
+            tab_title: fg(Color::Green),
+            tab_selected: Style::default()
+                .bg(Color::Black)
+                .add_modifier(Modifier::BOLD),
+            unread: bold(Color::Red),
+            border: Style::default(),
+            help: Style::default(),
+            help_key: Style::default().add_modifier(Modifier::BOLD),
+            input: Style::default(),
+            input_editing: fg(Color::Yellow),
+            input_search: fg(Color::Cyan),
+            nick: bold(Color::White),
+            greentext: fg(Color::LightGreen),
+            link: Style::default().add_modifier(Modifier::UNDERLINED),
+            nsfw: fg(Color::Red).add_modifier(Modifier::UNDERLINED),
+            nsfl: fg(Color::Yellow).add_modifier(Modifier::UNDERLINED),
+            search_match: bold(Color::Black).bg(Color::Yellow),
+            mention: bold(Color::Blue),
+            own_message: Style::default().bg(Color::Rgb(25, 25, 25)),
+            timestamp: fg(Color::DarkGray),
+            info: fg(Color::Gray).add_modifier(Modifier::ITALIC),
+            error: fg(Color::Red),
+            muted: fg(Color::DarkGray),
+            pending: Style::default().add_modifier(Modifier::DIM),
+            status: fg(Color::Gray),
+            status_error: fg(Color::Red),
+            connected: bold(Color::Green),
+            connecting: bold(Color::Yellow),
+            disconnected: bold(Color::Red),
+            popup: fg(Color::Blue),
+            selection: Style::default()
+                .bg(Color::LightGreen)
+                .add_modifier(Modifier::BOLD),
+            connections: Style::default().add_modifier(Modifier::DIM),
+            broadcast: banner(Color::Yellow),
+            donation: banner(Color::LightGreen),
+            subscription: banner(Color::White),
+            tier_1: banner(Color::Blue),
+            tier_2: banner(Color::Green),
+            tier_3: banner(Color::Cyan),
+            tier_4: banner(Color::Magenta),
+            tier_6: banner(Color::Yellow),
+        }
+    }
+
+    pub fn light() -> Theme {
+        Theme {
+            tab: fg(Color::Blue),
+            tab_hotkey: bold(Color::Magenta),
+            tab_title: fg(Color::Black),
+            tab_selected: Style::default()
+                .bg(Color::Gray)
+                .add_modifier(Modifier::BOLD),
+            nick: fg(Color::Black),
+            greentext: fg(Color::Green),
+            nsfl: fg(Color::Magenta).add_modifier(Modifier::UNDERLINED),
+            search_match: bold(Color::Black).bg(Color::LightYellow),
+            own_message: Style::default().bg(Color::Rgb(230, 230, 230)),
+            timestamp: fg(Color::DarkGray),
+            info: fg(Color::DarkGray).add_modifier(Modifier::ITALIC),
+            muted: fg(Color::Gray),
+            status: fg(Color::DarkGray),
+            input_editing: fg(Color::Blue),
+            input_search: fg(Color::Magenta),
+            connecting: bold(Color::Magenta),
+            selection: Style::default()
+                .bg(Color::LightBlue)
+                .add_modifier(Modifier::BOLD),
+            broadcast: banner(Color::LightYellow),
+            subscription: banner(Color::Gray),
+            tier_1: banner(Color::LightBlue),
+            tier_2: banner(Color::LightGreen),
+            tier_3: banner(Color::LightCyan),
+            tier_4: banner(Color::LightMagenta),
+            tier_6: banner(Color::LightYellow),
+            ..Theme::dark()
+        }
+    }
+
+    pub fn high_contrast() -> Theme {
+        Theme {
+            tab: bold(Color::White),
+            tab_hotkey: bold(Color::LightYellow),
+            tab_title: bold(Color::White),
+            tab_selected: bold(Color::Black).bg(Color::White),
+            unread: bold(Color::LightRed),
+            border: fg(Color::White),
+            help: fg(Color::White),
+            help_key: bold(Color::LightYellow),
+            input: fg(Color::White),
+            input_editing: bold(Color::LightYellow),
+            input_search: bold(Color::LightCyan),
+            nick: bold(Color::White),
+            greentext: bold(Color::LightGreen),
+            link: bold(Color::LightCyan).add_modifier(Modifier::UNDERLINED),
+            nsfw: bold(Color::LightRed).add_modifier(Modifier::UNDERLINED),
+            nsfl: bold(Color::LightYellow).add_modifier(Modifier::UNDERLINED),
+            search_match: bold(Color::Black).bg(Color::LightYellow),
+            mention: bold(Color::Black).bg(Color::LightCyan),
+            own_message: Style::default().bg(Color::Blue),
+            timestamp: fg(Color::Gray),
+            info: fg(Color::White).add_modifier(Modifier::ITALIC),
+            error: bold(Color::LightRed),
+            muted: fg(Color::Gray),
+            pending: fg(Color::Gray).add_modifier(Modifier::ITALIC),
+            status: fg(Color::White),
+            status_error: bold(Color::LightRed),
+            connected: bold(Color::LightGreen),
+            connecting: bold(Color::LightYellow),
+            disconnected: bold(Color::LightRed),
+            popup: bold(Color::LightCyan),
+            selection: bold(Color::Black).bg(Color::White),
+            connections: fg(Color::Gray),
+            broadcast: banner(Color::LightYellow),
+            donation: banner(Color::LightGreen),
+            subscription: banner(Color::White),
+            tier_1: banner(Color::LightBlue),
+            tier_2: banner(Color::LightGreen),
+            tier_3: banner(Color::LightCyan),
+            tier_4: banner(Color::LightMagenta),
+            tier_6: banner(Color::LightYellow),
+        }
+    }
+
+    /// The banner style for subscriptions of `tier`.
+    pub fn tier(&self, tier: u8) -> Style {
+        match tier {
+            1 => self.tier_1,
+            2 => self.tier_2,
+            3 => self.tier_3,
+            4 => self.tier_4,
+            6 => self.tier_6,
+            _ => self.subscription,
+        }
+    }
+
+    fn built_in(name: &str) -> Option<Theme> {
+        match name {
+            "dark" => Some(Theme::dark()),
+            "light" => Some(Theme::light()),
+            "high-contrast" => Some(Theme::high_contrast()),
+            _ => None,
+        }
+    }
+
+    /// The theme the config asks for: a built in one or a file from the themes
+    /// folder, with the `[theme]` settings on top. Falls back to the dark theme
+    /// with the reason when that doesn't work out.
+    pub fn load(config: &ThemeConfig) -> (Theme, Option<String>) {
+        let name = config.name.as_deref().unwrap_or("dark");
+        let mut error = None;
+        let mut theme = Theme::built_in(name).unwrap_or_else(|| match load_file(name) {
+            Ok(theme) => theme,
+            Err(e) => {
+                error = Some(format!("Could not load theme {}: {}", name, e));
+                Theme::dark()
+            }
+        });
+
+        if let Err(e) = theme.apply(config) {
+            error = Some(format!("Invalid [theme] setting: {}", e));
+        }
+        if ColorSupport::detect() == ColorSupport::NoColor {
+            theme.strip_colors();
+        } else {
+            theme.adapt_colors();
+        }
+        (theme, error)
+    }
+
+    /// Patches the styles with the ones set in `config`.
+    fn apply(&mut self, config: &ThemeConfig) -> Result<(), String> {
+        let overrides = [
+            (&mut self.tab, &config.tab),
+            (&mut self.tab_hotkey, &config.tab_hotkey),
+            (&mut self.tab_title, &config.tab_title),
+            (&mut self.tab_selected, &config.tab_selected),
+            (&mut self.unread, &config.unread),
+            (&mut self.border, &config.border),
+            (&mut self.help, &config.help),
+            (&mut self.help_key, &config.help_key),
+            (&mut self.input, &config.input),
+            (&mut self.input_editing, &config.input_editing),
+            (&mut self.input_search, &config.input_search),
+            (&mut self.nick, &config.nick),
+            (&mut self.greentext, &config.greentext),
+            (&mut self.link, &config.link),
+            (&mut self.nsfw, &config.nsfw),
+            (&mut self.nsfl, &config.nsfl),
+            (&mut self.search_match, &config.search_match),
+            (&mut self.mention, &config.mention),
+            (&mut self.own_message, &config.own_message),
+            (&mut self.timestamp, &config.timestamp),
+            (&mut self.info, &config.info),
+            (&mut self.error, &config.error),
+            (&mut self.muted, &config.muted),
+            (&mut self.pending, &config.pending),
+            (&mut self.status, &config.status),
+            (&mut self.status_error, &config.status_error),
+            (&mut self.connected, &config.connected),
+            (&mut self.connecting, &config.connecting),
+            (&mut self.disconnected, &config.disconnected),
+            (&mut self.popup, &config.popup),
+            (&mut self.selection, &config.selection),
+            (&mut self.connections, &config.connections),
+            (&mut self.broadcast, &config.broadcast),
+            (&mut self.donation, &config.donation),
+            (&mut self.subscription, &config.subscription),
+            (&mut self.tier_1, &config.tier_1),
+            (&mut self.tier_2, &config.tier_2),
+            (&mut self.tier_3, &config.tier_3),
+            (&mut self.tier_4, &config.tier_4),
+            (&mut self.tier_6, &config.tier_6),
+        ];
+        for (style, setting) in overrides {
+            if let Some(setting) = setting {
+                *style = self::style(setting)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn styles_mut(&mut self) -> [&mut Style; 40] {
+        [
+            &mut self.tab,
+            &mut self.tab_hotkey,
+            &mut self.tab_title,
+            &mut self.tab_selected,
+            &mut self.unread,
+            &mut self.border,
+            &mut self.help,
+            &mut self.help_key,
+            &mut self.input,
+            &mut self.input_editing,
+            &mut self.input_search,
+            &mut self.nick,
+            &mut self.greentext,
+            &mut self.link,
+            &mut self.nsfw,
+            &mut self.nsfl,
+            &mut self.search_match,
+            &mut self.mention,
+            &mut self.own_message,
+            &mut self.timestamp,
+            &mut self.info,
+            &mut self.error,
+            &mut self.muted,
+            &mut self.pending,
+            &mut self.status,
+            &mut self.status_error,
+            &mut self.connected,
+            &mut self.connecting,
+            &mut self.disconnected,
+            &mut self.popup,
+            &mut self.selection,
+            &mut self.connections,
+            &mut self.broadcast,
+            &mut self.donation,
+            &mut self.subscription,
+            &mut self.tier_1,
+            &mut self.tier_2,
+            &mut self.tier_3,
+            &mut self.tier_4,
+            &mut self.tier_6,
+        ]
+    }
+
+    /// Swaps true colors of the built in themes for ones the terminal can show.
+    fn adapt_colors(&mut self) {
+        for style in self.styles_mut() {
+            style.fg = style.fg.map(adapt);
+            style.bg = style.bg.map(adapt);
+        }
+    }
+
+    /// Leaves only bold, italics and the like, for `NO_COLOR`.
+    fn strip_colors(&mut self) {
+        for style in self.styles_mut() {
+            style.fg = None;
+            style.bg = None;
+        }
+        // Without colors these would be indistinguishable from the text around them.
+        self.search_match = self.search_match.add_modifier(Modifier::REVERSED);
+        self.tab_selected = self.tab_selected.add_modifier(Modifier::REVERSED);
+        self.selection = self.selection.add_modifier(Modifier::REVERSED);
+        for banner in [
+            &mut self.broadcast,
+            &mut self.donation,
+            &mut self.subscription,
+            &mut self.tier_1,
+            &mut self.tier_2,
+            &mut self.tier_3,
+            &mut self.tier_4,
+            &mut self.tier_6,
+        ] {
+            *banner = banner.add_modifier(Modifier::REVERSED);
+        }
+    }
+}
+
+/// The `[theme]` config section, and the contents of a theme file. Any style
+/// left out keeps the one from the theme underneath.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ThemeConfig {
+    /// `dark`, `light`, `high-contrast`, or the name of a file in
+    /// ~/.config/dggtui/themes without `.toml`. In a theme file, the built in
+    /// theme it starts from.
+    pub name: Option<String>,
+    pub tab: Option<StyleConfig>,
+    pub tab_hotkey: Option<StyleConfig>,
+    pub tab_title: Option<StyleConfig>,
+    pub tab_selected: Option<StyleConfig>,
+    pub unread: Option<StyleConfig>,
+    pub border: Option<StyleConfig>,
+    pub help: Option<StyleConfig>,
+    pub help_key: Option<StyleConfig>,
+    pub input: Option<StyleConfig>,
+    pub input_editing: Option<StyleConfig>,
+    pub input_search: Option<StyleConfig>,
+    pub nick: Option<StyleConfig>,
+    pub greentext: Option<StyleConfig>,
+    pub link: Option<StyleConfig>,
+    pub nsfw: Option<StyleConfig>,
+    pub nsfl: Option<StyleConfig>,
+    pub search_match: Option<StyleConfig>,
+    pub mention: Option<StyleConfig>,
+    pub own_message: Option<StyleConfig>,
+    pub timestamp: Option<StyleConfig>,
+    pub info: Option<StyleConfig>,
+    pub error: Option<StyleConfig>,
+    pub muted: Option<StyleConfig>,
+    pub pending: Option<StyleConfig>,
+    pub status: Option<StyleConfig>,
+    pub status_error: Option<StyleConfig>,
+    pub connected: Option<StyleConfig>,
+    pub connecting: Option<StyleConfig>,
+    pub disconnected: Option<StyleConfig>,
+    pub popup: Option<StyleConfig>,
+    pub selection: Option<StyleConfig>,
+    pub connections: Option<StyleConfig>,
+    pub broadcast: Option<StyleConfig>,
+    pub donation: Option<StyleConfig>,
+    pub subscription: Option<StyleConfig>,
+    pub tier_1: Option<StyleConfig>,
+    pub tier_2: Option<StyleConfig>,
+    pub tier_3: Option<StyleConfig>,
+    pub tier_4: Option<StyleConfig>,
+    pub tier_6: Option<StyleConfig>,
+}
+
+fn load_file(name: &str) -> Result<Theme, String> {
+    let path = config::themes_folder()
+        .ok_or("No config directory")?
+        .join(format!("{}.toml", name));
+    if !path.exists() {
+        return Err(format!("{} doesn't exist", path.display()));
+    }
+    let file: ThemeConfig = Config_c::builder()
+        .add_source(File::from(path))
+        .build()
+        .and_then(|file| file.try_deserialize())
+        .map_err(|e| e.to_string())?;
+
+    let mut theme = file
+        .name
+        .as_deref()
+        .and_then(Theme::built_in)
+        .unwrap_or_else(Theme::dark);
+    theme.apply(&file)?;
+    Ok(theme)
+}
+
+pub fn style(config: &StyleConfig) -> Result<Style, String> {
+    let mut style = Style::default();
+    if let Some(fg) = &config.fg {
+        style = style.fg(parse_color(fg)?);
+    }
+    if let Some(bg) = &config.bg {
+        style = style.bg(parse_color(bg)?);
+    }
+    let modifiers = [
+        (config.bold, Modifier::BOLD),
+        (config.italic, Modifier::ITALIC),
+        (config.underlined, Modifier::UNDERLINED),
+        (config.dim, Modifier::DIM),
+    ];
+    for (set, modifier) in modifiers {
+        if set {
+            style = style.add_modifier(modifier);
+        }
+    }
+    Ok(style)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiers_without_a_style_use_the_subscription_one() {
+        let theme = Theme::dark();
+        assert_eq!(theme.tier(2), theme.tier_2);
+        assert_eq!(theme.tier(6), theme.tier_6);
+        assert_eq!(theme.tier(0), theme.subscription);
+        assert_eq!(theme.tier(5), theme.subscription);
+    }
+
+    #[test]
+    fn config_overrides_banner_styles() {
+        let config = ThemeConfig {
+            tier_1: Some(StyleConfig {
+                fg: Some("white".to_string()),
+                bg: Some("red".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut theme = Theme::dark();
+        theme.apply(&config).unwrap();
+        assert_eq!(
+            theme.tier_1,
+            Style::default().fg(Color::White).bg(Color::Red)
+        );
+        assert_eq!(theme.tier_2, Theme::dark().tier_2);
+    }
+
+    #[test]
+    fn banners_stay_visible_without_colors() {
+        let mut theme = Theme::high_contrast();
+        theme.strip_colors();
+        assert_eq!(theme.donation.bg, None);
+        assert!(theme.donation.add_modifier.contains(Modifier::REVERSED));
+        assert!(theme.tier_4.add_modifier.contains(Modifier::REVERSED));
+    }
+}
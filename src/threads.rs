@@ -1,138 +1,251 @@
-use bytes::Bytes;
-use futures::future::{self, FutureExt};
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
 use websocket_lite::{Message, Opcode, Result};
 
-// use serde::{Deserialize, Serialize};
-use serde_json::Result as JSON_Result;
-
+use crate::bus::{BusEvent, NetBus, UiBus};
+use crate::chatlog::{self, Logger};
+use crate::commands::{self, Action};
 use crate::config::Config;
-use crate::irender;
-use crate::types::{self, Autocomplete, InternalMessage};
+use crate::connection::{Backoff, ConnectionState, Keepalive};
+use crate::outbox::{Outbox, Rejection};
+use crate::protocol::{self, Command, PrivateMessage};
+use crate::search::Query;
+use crate::session::{Direction, Recorder};
+use crate::types::{self, Autocomplete};
 use crate::ui::ui;
 use crate::utils;
-use types::{App, EmoteData, InputMode, ParsedMessage};
+use types::{App, ChatLine, Chatters, InputMode, Tab};
 
 use tui::{backend::Backend, Terminal};
 
 use crossterm::event::{self, Event, KeyCode, KeyModifiers};
 
 use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+pub async fn run_ws2(bus: NetBus, config: Config, recorder: Option<Recorder>) -> Result<()> {
+    let NetBus {
+        events,
+        mut commands,
+    } = bus;
+    let mut backoff = Backoff::new(&config.reconnect);
+    // Lives across reconnects, so queued commands go out once we're back.
+    let mut outbox = Outbox::new(&config.rate_limit, config.username.to_owned());
+    let logger = Logger::spawn(&config.log);
 
-pub async fn run_ws2(
-    tx: tokio::sync::watch::Sender<String>,
-    mut mrx: tokio::sync::watch::Receiver<String>,
-    itx: tokio::sync::watch::Sender<InternalMessage>,
-    mut irx: tokio::sync::watch::Receiver<InternalMessage>,
-    config: Config,
-) -> Result<()> {
-    while "hei" != "suh" {
-        let url = "wss://chat.destiny.gg/ws".to_owned();
-        let mut builder = websocket_lite::ClientBuilder::new(&url)?;
-
-        if let Some(token) = config.token.to_owned() {
-            builder.add_header(
-                "Cookie".to_string(),
-                format!("authtoken={}", token).to_string(),
-            )
+    loop {
+        // The UI has gone away, nobody left to connect for.
+        if events.is_closed() {
+            return Ok(());
         }
 
-        let client = builder.async_connect().await?;
-        let (sink, stream) = client.split();
-
-        let send_loop = async {
-            let mut sink = sink;
-            let mut message: String = String::new();
-            let mut message_changed = false;
+        let mut builder = websocket_lite::ClientBuilder::new(&config.server.websocket_url)?;
 
-            let mut ping_data: Bytes = Bytes::new();
-            let mut do_ping = false;
-
-            while message != "/quit" {
-                // let res = select(irx.changed().boxed(), mrx.changed().boxed()).await;
+        if let Some(token) = config.token.to_owned() {
+            builder.add_header("Cookie".to_string(), format!("authtoken={}", token))
+        }
 
-                tokio::select! {
-                    val = irx.changed() => {
-                        if val.is_ok() {
-                            let ping_msg: &InternalMessage = &*irx.borrow();
-                            ping_data = ping_msg.data.to_owned();
-                            do_ping = true;
-                        } else if val.is_err() {
-                            break
+        let client = match builder.async_connect().await {
+            Ok(client) => client,
+            Err(e) => {
+                if wait_to_reconnect(&mut backoff, &events, e.to_string()).await {
+                    continue;
+                }
+                return Err(e);
+            }
+        };
+        backoff.reset();
+        events
+            .send(BusEvent::Connection(ConnectionState::Connected))
+            .await?;
+
+        let (mut sink, mut stream) = client.split();
+        let mut counts = (0, 0);
+        let mut keepalive = Keepalive::new(&config.keepalive);
+
+        let reason = loop {
+            let wakeup = match outbox.next_wakeup() {
+                Some(wakeup) => wakeup.min(keepalive.next_wakeup()),
+                None => keepalive.next_wakeup(),
+            };
+            let sleep = tokio::time::sleep_until(wakeup.into());
+
+            tokio::select! {
+                msg = stream.next() => {
+                    let msg = match msg {
+                        Some(Ok(msg)) => msg,
+                        Some(Err(e)) => break e.to_string(),
+                        None => break "Connection closed".to_string(),
+                    };
+
+                    match msg.opcode() {
+                        Opcode::Text => {
+                            if let Some(text) = msg.as_text() {
+                                if let Some(recorder) = &recorder {
+                                    recorder.record(Direction::In, text);
+                                }
+                                let event = protocol::Event::parse(text);
+                                logger.frame(text, &event);
+                                let bus_event = outbox_answer(&mut outbox, event, &logger);
+                                if let Some(bus_event) = bus_event {
+                                    events.send(bus_event).await?;
+                                }
+                            }
                         }
-                    }
-                    val = mrx.changed() => {
-                        if val.is_ok() {
-                            let msg = &*mrx.borrow();
-                            message = msg.to_string();
-                            message_changed = true;
-                        } else if val.is_err() {
-                            break;
+                        Opcode::Ping => {
+                            if let Err(e) = sink.send(Message::pong(msg.into_data())).await {
+                                break e.to_string();
+                            }
+                        }
+                        Opcode::Close => break "Connection closed".to_string(),
+                        Opcode::Pong => {
+                            if let Some(latency) = keepalive.pong(msg.data(), Instant::now()) {
+                                events.send(BusEvent::Latency(latency)).await?;
+                            }
                         }
+                        _ => {}
                     }
-                };
-
-                if message_changed {
-                    let message_data = Message::new(
-                        Opcode::Text,
-                        format!("MSG {{ \"data\": \"{}\" }}", &message),
-                    )?;
-                    sink.send(message_data).await?;
-                    message_changed = false;
-                } else if do_ping {
-                    sink.send(Message::pong(ping_data.to_owned())).await?;
-                    do_ping = false;
                 }
+                outgoing = commands.recv() => match outgoing {
+                    Some(outgoing) => outbox.push(outgoing),
+                    None => {
+                        let _ = sink.send(Message::close(None)).await;
+                        return Ok(());
+                    }
+                },
+                _ = sleep => {}
             }
 
-            Ok(())
-        };
-
-        let recv_loop = async {
-            let mut stream_mut = stream;
-
-            loop {
-                let (msg, stream) = stream_mut.into_future().await;
-
-                let msg = if let Some(msg) = msg {
-                    msg?
-                } else {
-                    stream_mut = stream;
-                    break;
-                };
-
-                if let Opcode::Text = msg.opcode() {
-                    if let Some(text) = msg.as_text() {
-                        let msg_text: String = text.to_string();
-                        if msg_text.contains("/quit") {
-                            break;
-                        }
-                        let res = tx.send(msg_text);
+            let now = Instant::now();
+            if keepalive.timed_out(now) {
+                break "Connection timed out".to_string();
+            }
+            if let Some(payload) = keepalive.ping(now) {
+                if let Err(e) = sink.send(Message::ping(payload)).await {
+                    break e.to_string();
+                }
+            }
+            for outgoing in outbox.expire(now) {
+                let reason = "The server never answered".to_string();
+                events.send(BusEvent::SendFailed(outgoing, reason)).await?;
+            }
+            let mut send_error = None;
+            while let Some(outgoing) = outbox.next_ready(now) {
+                let frame = match outgoing.command.to_frame() {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        outbox.forget(outgoing.id);
+                        events
+                            .send(BusEvent::SendFailed(outgoing, e.to_string()))
+                            .await?;
+                        continue;
                     }
+                };
+                if let Some(recorder) = &recorder {
+                    recorder.record(Direction::Out, &frame);
                 }
-
-                if let Opcode::Ping = msg.opcode() {
-                    itx.send(InternalMessage {
-                        message_type: types::InternalMessageType::PING,
-                        message: "WS_PING".to_string(),
-                        data: msg.into_data(),
-                    })?;
+                if let Err(e) = sink.send(Message::new(Opcode::Text, frame)?).await {
+                    send_error = Some(e.to_string());
+                    break;
                 }
+            }
+            if let Some(e) = send_error {
+                break e;
+            }
 
-                stream_mut = stream;
+            if outbox.counts() != counts {
+                counts = outbox.counts();
+                let (queued, pending) = counts;
+                events.send(BusEvent::Outbox { queued, pending }).await?;
             }
-            println!("Connection closed");
+        };
+
+        for outgoing in outbox.disconnected() {
+            let _ = events
+                .send(BusEvent::SendFailed(outgoing, "Disconnected".to_string()))
+                .await;
+        }
+        let (queued, pending) = outbox.counts();
+        let _ = events.send(BusEvent::Outbox { queued, pending }).await;
+        let _ = events.send(BusEvent::Error(reason.to_owned())).await;
+
+        if !wait_to_reconnect(&mut backoff, &events, reason.to_owned()).await {
+            return Err(reason.into());
+        }
+    }
+}
 
-            Ok(()) as Result<()>
+/// Lets the outbox see an inbound event first, and decides what the UI gets to
+/// hear about it. The echo of our own message confirms its delivery, and ERRs
+/// answering one of our commands are reported as a failure of that command
+/// instead, or not at all when it's being retried.
+fn outbox_answer(outbox: &mut Outbox, event: protocol::Event, logger: &Logger) -> Option<BusEvent> {
+    if let protocol::Event::Err(error) = &event {
+        return match outbox.reject(error, Instant::now()) {
+            Rejection::NotOurs => Some(BusEvent::Chat(event)),
+            Rejection::Retrying => None,
+            Rejection::Failed {
+                outgoing,
+                certain: true,
+            } => Some(BusEvent::SendFailed(outgoing, error.message())),
+            Rejection::Failed {
+                outgoing,
+                certain: false,
+            } => {
+                let reason = format!(
+                    "{} (the server didn't say which command this was about, it may not be this one)",
+                    error.message()
+                );
+                Some(BusEvent::SendFailed(outgoing, reason))
+            }
         };
+    }
+    if let protocol::Event::Me(Some(user)) = &event {
+        outbox.learn_nick(&user.nick);
+    }
 
-        let result = future::select(send_loop.boxed(), recv_loop.boxed())
-            .await
-            .into_inner()
-            .0;
+    match outbox.confirm(&event) {
+        Some(outgoing) => match outgoing.command {
+            Command::Msg(_) => Some(BusEvent::Delivered(outgoing.id, event)),
+            Command::PrivMsg(whisper) => {
+                let message = PrivateMessage {
+                    messageid: 0,
+                    nick: outbox.nick().unwrap_or_default().to_string(),
+                    timestamp: utils::timestamp(),
+                    data: whisper.data,
+                };
+                logger.whisper(&whisper.nick, message);
+                Some(BusEvent::Delivered(outgoing.id, event))
+            }
+            _ => Some(BusEvent::Chat(event)),
+        },
+        None => Some(BusEvent::Chat(event)),
+    }
+}
+
+/// Publishes the upcoming attempt and sleeps until it's due. Returns false once
+/// we've run out of retries, after marking the connection as failed.
+async fn wait_to_reconnect(
+    backoff: &mut Backoff,
+    events: &mpsc::Sender<BusEvent>,
+    reason: String,
+) -> bool {
+    match backoff.next_delay() {
+        Some(delay) => {
+            let state = ConnectionState::Reconnecting(backoff.attempt(), delay);
+            let _ = events.send(BusEvent::Connection(state)).await;
+            tokio::time::sleep(delay).await;
+            let _ = events
+                .send(BusEvent::Connection(ConnectionState::Connecting))
+                .await;
+            true
+        }
+        None => {
+            let state = ConnectionState::Failed(reason);
+            let _ = events.send(BusEvent::Connection(state)).await;
+            false
+        }
     }
-    Ok(())
 }
 
 // pub async fn run_ws(
@@ -214,75 +327,15 @@ pub async fn run_ws2(
 //     Ok(())
 // }
 
-pub async fn run_emotes(mut erx: tokio::sync::watch::Receiver<EmoteData>) -> Result<()> {
-    while erx.changed().await.is_ok() {
-        let res = &*erx.borrow();
-
-        let max: isize = res.term_size as isize - 9;
-
-        let floor = |_: usize| -> usize {
-            if res.message_pos as isize - max <= 0 {
-                0
-            } else {
-                (res.message_pos as isize - max) as usize
-            }
-        };
-
-        let mut final_emotes: Vec<(u16, u16, String)> = Vec::new();
-
-        for (i, message) in res.messages[floor(0)..res.message_pos + 1]
-            .iter()
-            .enumerate()
-        {
-            let emote_names: Vec<String> = utils::get_emotenames();
-            let mut emote_pos: Vec<(usize, &str)> = vec![];
-
-            let parsed_output: JSON_Result<ParsedMessage>;
-            parsed_output = utils::parse_message(&message.0.as_str()[4..]);
-            let msg: ParsedMessage = parsed_output.unwrap();
-
-            for name in emote_names {
-                let mut pos: Vec<_> = msg
-                    .data
-                    .match_indices(&format!("{}", &name).to_string())
-                    .collect();
-                emote_pos.append(&mut pos);
-            }
-
-            if emote_pos.len() > 0 {
-                for pos in emote_pos.to_owned() {
-                    // utils::print_emote(i as u16, pos.0 as u16 + msg.nick.len() as u16 + 3, pos.1);
-                    final_emotes.push((
-                        i as u16,
-                        pos.0 as u16 + msg.nick.len() as u16 + 3,
-                        pos.1.to_string(),
-                    ));
-                }
-            }
-        }
-
-        // irender::clear_all();
-        if final_emotes.len() > 0 {
-            for pos in final_emotes.to_owned() {
-                irender::print_emote(pos.0, pos.1)?;
-            }
-        }
-    }
-
-    Ok(())
-}
-
 pub fn run_app<B: Backend>(
     terminal: &mut Terminal<B>,
     mut app: App,
-    mut rx: tokio::sync::watch::Receiver<String>,
-    etx: tokio::sync::watch::Sender<EmoteData>,
-    mtx: tokio::sync::watch::Sender<String>,
+    mut bus: UiBus,
     tick_rate: Duration,
 ) -> Result<()> {
     let mut last_tick = Instant::now();
-
-    app.emotes = utils::get_emotenames();
+    // Emote images go over what tui drew, so they're drawn after it.
+    let mut redraw_emotes = false;
 
     loop {
         if last_tick.elapsed() >= tick_rate {
@@ -290,51 +343,27 @@ pub fn run_app<B: Backend>(
             last_tick = Instant::now();
         }
 
-        let rx_res = match rx.has_changed() {
-            Ok(rx_res) => {
-                if rx_res {
-                    let msg = &*rx.borrow_and_update();
-                    if msg.starts_with("MSG ") {
-                        let hi: String = msg.to_string();
-                        app.message_list.items.push((hi.to_owned(), 1));
-                        match app.input_mode {
-                            InputMode::Normal => app.message_list.bottom(),
-                            InputMode::Editing => app.message_list.bottom(),
-                        }
-
-                        let term_height: u16 = terminal.size().unwrap().height;
-                        // etx.send(EmoteData {
-                        //     term_size: term_height,
-                        //     messages: app.message_list.messages.clone(),
-                        //     message_pos: app.message_list.state.selected().unwrap(),
-                        // })
-                        // .expect("Error sending EmoteData to etx");
-
-                        if app.config.emotes {
-                            irender::clear_all();
-                            irender::emote_meme(EmoteData {
-                                term_size: term_height,
-                                messages: app.message_list.items.clone(),
-                                message_pos: app.message_list.state.selected().unwrap(),
-                            })?;
-                        }
-                    } else if msg.starts_with("NAMES ") {
-                        app.users = utils::get_users(msg.to_string());
-                        for user in app.users.users.to_owned() {
-                            app.user_list.items.push(user)
-                        }
-
-                        // for user in app.users.users {
+        let mut new_messages = false;
+        while let Ok(bus_event) = bus.events.try_recv() {
+            new_messages |= handle_bus_event(&mut app, bus_event);
+        }
 
-                        // }
-                    } else if msg.starts_with("JOIN ") {
-                    }
-                }
-            }
-            Err(e) => {}
-        };
+        // Stay on the selected match while searching.
+        if new_messages && app.search.is_none() && !app.hold_scroll {
+            app.message_list.bottom();
+            redraw_emotes = true;
+        }
+        app.poll_log_search();
+        if app.emotes.poll_images() {
+            // Messages laid out before their emotes' images were made show them as text.
+            app.relayout_chat();
+        }
 
+        // Rebuilt messages are drawn again, over the images of text backends.
+        redraw_emotes |= app.spans_outdated();
         terminal.draw(|f| ui(f, &mut app))?;
+        draw_emotes(&mut app, redraw_emotes)?;
+        redraw_emotes = false;
 
         if crossterm::event::poll(tick_rate).unwrap() {
             // if let Event::Mouse(event) = event::read()? {
@@ -346,89 +375,202 @@ pub fn run_app<B: Backend>(
             //     }
             // }
 
-            if let Event::Key(key) = event::read()? {
+            let event = event::read()?;
+            if let Event::Resize(..) = event {
+                redraw_emotes = true;
+            }
+            if let Event::Key(key) = event {
                 // println!("Meep, {:?}", key);
                 match app.input_mode {
                     InputMode::Normal => match key.code {
                         KeyCode::Char('e') => {
                             app.input_mode = InputMode::Editing;
                         }
+                        KeyCode::Char('/') => {
+                            app.search_input.clear();
+                            app.input_mode = InputMode::Search;
+                        }
+                        KeyCode::Char(c @ ('n' | 'N')) if app.jump_to_match(c == 'N') => {
+                            app.select_tab(0);
+                            redraw_emotes = true;
+                        }
+                        KeyCode::Esc if app.search.is_some() || app.hold_scroll => {
+                            app.search = None;
+                            app.hold_scroll = false;
+                            app.relayout_chat();
+                            app.message_list.bottom();
+                        }
+                        KeyCode::Enter if matches!(app.tab(), Tab::Mentions) => {
+                            if let Some(i) = app.mentions.messages.state.selected() {
+                                if app.jump_to_mention(i) {
+                                    redraw_emotes = true;
+                                } else {
+                                    app.message_list.items.push(ChatLine::Error(
+                                        "That message is no longer in the chat".to_string(),
+                                    ));
+                                    app.message_list.bottom();
+                                    app.select_tab(0);
+                                }
+                            }
+                        }
                         KeyCode::Char('q') => {
                             return Ok(());
                         }
-                        KeyCode::Char('c') => irender::clear_all(),
+                        KeyCode::Char('c') => {
+                            if let Some(renderer) = &mut app.renderer {
+                                renderer.clear()?;
+                            }
+                        }
+                        KeyCode::Char('h') => {
+                            app.show_filtered = !app.show_filtered;
+                            app.relayout_chat();
+                        }
                         KeyCode::Char('r') => {
                             print!("hello");
                             terminal.clear()?;
                             // println!(utils::emotes_remote)::emotes_remote();
                         }
                         KeyCode::Char('t') => {}
-                        KeyCode::Char('p') => {
-                            irender::print_one(
-                                "/Users/lsmb/Dev/dggtui/src/emotes_resized/OOOO.png",
-                            )?
-                            // terminal.clear()?;
+                        KeyCode::Char('R') => {
+                            if let Some(outgoing) = app.send_status.failed.pop_front() {
+                                match bus.send(outgoing.command.to_owned()) {
+                                    Ok(id) => app.resend(outgoing.id, id),
+                                    Err(reason) => {
+                                        app.send_status.failed.push_front(outgoing);
+                                        app.message_list.items.push(ChatLine::Error(reason));
+                                    }
+                                }
+                            }
                         }
-                        KeyCode::Char('g') => match app.tab_index {
-                            0 => {
+                        KeyCode::Char('D') => {
+                            if let Some(outgoing) = app.send_status.failed.pop_front() {
+                                app.discard(outgoing.id);
+                            }
+                        }
+                        KeyCode::Char('x') => match app.tab() {
+                            Tab::Whisper(i) => app.close_conversation(i),
+                            Tab::Search => app.close_log_search(),
+                            _ => {}
+                        },
+                        KeyCode::Char('g') => match app.tab() {
+                            Tab::Chat => {
+                                app.hold_scroll = false;
                                 app.message_list.bottom();
-                                etx.send(EmoteData {
-                                    term_size: terminal.size().unwrap().height,
-                                    messages: app.message_list.items.clone(),
-                                    message_pos: app.message_list.state.selected().unwrap(),
-                                })
-                                .expect("Error sending EmoteData to etx");
+                                redraw_emotes = true;
                             }
-                            1 => {
+                            Tab::Users => {
                                 app.user_list.bottom();
                             }
-                            _ => unreachable!(),
+                            Tab::Mentions => app.mentions.messages.bottom(),
+                            Tab::Whisper(i) => app.conversations[i].messages.bottom(),
+                            Tab::Search => {
+                                if let Some(log_search) = &mut app.log_search {
+                                    log_search.results.bottom();
+                                }
+                            }
                         },
-                        KeyCode::Char('G') => match app.tab_index {
-                            0 => {
-                                app.message_list.top();
-                                etx.send(EmoteData {
-                                    term_size: terminal.size().unwrap().height,
-                                    messages: app.message_list.items.clone(),
-                                    message_pos: app.message_list.state.selected().unwrap(),
-                                })
-                                .expect("Error sending EmoteData to etx");
+                        KeyCode::Char('G') => match app.tab() {
+                            Tab::Chat => {
+                                app.first_line();
+                                redraw_emotes = true;
                             }
-                            1 => {
+                            Tab::Users => {
                                 app.user_list.top();
                             }
-                            _ => unreachable!(),
+                            Tab::Mentions => app.mentions.messages.top(),
+                            Tab::Whisper(i) => app.conversations[i].messages.top(),
+                            Tab::Search => {
+                                if let Some(log_search) = &mut app.log_search {
+                                    log_search.results.top();
+                                }
+                            }
                         },
 
-                        KeyCode::Down => match app.tab_index {
-                            0 => {
-                                app.message_list.next();
-                                etx.send(EmoteData {
-                                    term_size: terminal.size().unwrap().height,
-                                    messages: app.message_list.items.clone(),
-                                    message_pos: app.message_list.state.selected().unwrap(),
-                                })
-                                .expect("Error sending EmoteData to etx");
+                        KeyCode::Down => match app.tab() {
+                            Tab::Chat => {
+                                app.next_line();
+                                redraw_emotes = true;
+                            }
+                            Tab::Users => app.user_list.next(),
+                            Tab::Mentions => {
+                                if !app.mentions.messages.items.is_empty() {
+                                    app.mentions.messages.next();
+                                }
+                            }
+                            Tab::Whisper(i) => app.conversations[i].messages.next(),
+                            Tab::Search => {
+                                if let Some(log_search) = &mut app.log_search {
+                                    if !log_search.results.items.is_empty() {
+                                        log_search.results.next();
+                                    }
+                                }
+                            }
+                        },
+                        KeyCode::Up => match app.tab() {
+                            Tab::Chat => {
+                                app.previous_line();
+                                redraw_emotes = true;
+                            }
+                            Tab::Users => app.user_list.previous(),
+                            Tab::Mentions => app.mentions.messages.previous(),
+                            Tab::Whisper(i) => app.conversations[i].messages.previous(),
+                            Tab::Search => {
+                                if let Some(log_search) = &mut app.log_search {
+                                    log_search.results.previous();
+                                }
                             }
-                            1 => app.user_list.next(),
-                            _ => unreachable!(),
                         },
-                        KeyCode::Up => match app.tab_index {
-                            0 => app.message_list.previous(),
-                            1 => app.user_list.previous(),
-                            _ => unreachable!(),
+                        KeyCode::Left => match app.tab() {
+                            Tab::Chat => app.message_list.unselect(),
+                            Tab::Users => app.user_list.unselect(),
+                            Tab::Mentions => app.mentions.messages.unselect(),
+                            Tab::Whisper(i) => app.conversations[i].messages.unselect(),
+                            Tab::Search => {
+                                if let Some(log_search) = &mut app.log_search {
+                                    log_search.results.unselect();
+                                }
+                            }
                         },
-                        KeyCode::Left => app.message_list.unselect(),
-                        KeyCode::Tab => app.next_tab(),
-                        KeyCode::BackTab => app.prev_tab(),
+                        KeyCode::Tab => {
+                            app.next_tab();
+                            redraw_emotes = true;
+                        }
+                        KeyCode::BackTab => {
+                            app.prev_tab();
+                            redraw_emotes = true;
+                        }
+                        _ => {}
+                    },
+                    InputMode::Search => match key.code {
+                        KeyCode::Enter => {
+                            let input: String = app.search_input.drain(..).collect();
+                            app.input_mode = InputMode::Normal;
+                            if let Err(reason) = search(&mut app, &input) {
+                                app.message_list.items.push(ChatLine::Error(reason));
+                            }
+                            redraw_emotes = true;
+                        }
+                        KeyCode::Char(c) => app.search_input.push(c),
+                        KeyCode::Backspace => {
+                            app.search_input.pop();
+                        }
+                        KeyCode::Esc => app.input_mode = InputMode::Normal,
                         _ => {}
                     },
                     InputMode::Editing => match key.code {
                         KeyCode::Enter => {
                             let message: String = app.input.drain(..).collect();
-                            // println!("Message: {}", message);
-                            mtx.send(message.to_string());
-                            // app.message_list.messages.push((message.to_owned(), 1));
+                            if message.trim().is_empty() {
+                                continue;
+                            }
+                            if let Ok(Action::Quit) = commands::parse_input(&message) {
+                                return Ok(());
+                            }
+
+                            if let Err(reason) = submit_input(&mut app, &bus, &message) {
+                                app.message_list.items.push(ChatLine::Error(reason));
+                                app.message_list.bottom();
+                            }
                         }
                         KeyCode::Char(c) => {
                             if key.modifiers == KeyModifiers::CONTROL && c == 'w' {
@@ -436,7 +578,7 @@ pub fn run_app<B: Backend>(
                                 split_input.pop();
                                 app.input = split_input.join(" ");
                             } else {
-                                if app.autocomplete.tabbing == true {
+                                if app.autocomplete.tabbing {
                                     // app.input.push(' ');
                                     app.autocomplete.unselect();
                                     app.autocomplete.tabbing = false
@@ -446,8 +588,9 @@ pub fn run_app<B: Backend>(
                                 let autocomplete: Autocomplete = utils::get_suggestions(
                                     app.input.to_owned(),
                                     app.autocomplete.to_owned(),
-                                    app.users.to_owned(),
-                                    app.emotes.to_owned(),
+                                    &app.completable_users(),
+                                    app.emotes.names(),
+                                    &commands::available(app.is_moderator()),
                                 );
 
                                 app.autocomplete = autocomplete;
@@ -466,8 +609,9 @@ pub fn run_app<B: Backend>(
                             let autocomplete: Autocomplete = utils::get_suggestions(
                                 app.input.to_owned(),
                                 app.autocomplete.to_owned(),
-                                app.users.to_owned(),
-                                app.emotes.to_owned(),
+                                &app.completable_users(),
+                                app.emotes.names(),
+                                &commands::available(app.is_moderator()),
                             );
 
                             app.autocomplete = autocomplete;
@@ -484,7 +628,7 @@ pub fn run_app<B: Backend>(
                                 if app.autocomplete.tabbing {
                                     split_input.pop();
                                 }
-                                if split_input.len() > 0
+                                if !split_input.is_empty()
                                     && state < app.autocomplete.suggestions.len()
                                 {
                                     split_input.pop();
@@ -505,7 +649,7 @@ pub fn run_app<B: Backend>(
                                     split_input.pop();
                                 }
 
-                                if split_input.len() > 0 {
+                                if !split_input.is_empty() {
                                     split_input.pop();
                                     split_input.push(&app.autocomplete.suggestions[state]);
                                     app.input = split_input.join(" ");
@@ -523,3 +667,232 @@ pub fn run_app<B: Backend>(
         }
     }
 }
+
+/// Applies what the connection reported to the app. Returns whether lines were
+/// added to the chat.
+pub fn handle_bus_event(app: &mut App, bus_event: BusEvent) -> bool {
+    let mut new_messages = false;
+    match bus_event {
+        BusEvent::Chat(event) => match event {
+            protocol::Event::Msg(message) => {
+                let line = app.message_line(message);
+                if let ChatLine::Event(protocol::Event::Msg(message)) = &line {
+                    app.add_mention(message);
+                }
+                app.message_list.items.push(line);
+                new_messages = true;
+            }
+            _ if app.shows(&event) => {
+                app.message_list.items.push(ChatLine::Event(event));
+                new_messages = true;
+            }
+            protocol::Event::Names(users) => {
+                app.users = Chatters::from_names(users);
+                app.refresh_user_list();
+            }
+            protocol::Event::Join(presence) => app.user_joined(&presence),
+            protocol::Event::Quit(presence) => app.user_quit(&presence),
+            protocol::Event::Refresh(user) => app.user_refreshed(user),
+            protocol::Event::Me(Some(user)) => app.learn_nick(&user.nick),
+            protocol::Event::PrivMsg(whisper) if !app.filters.ignores(&whisper.nick) => {
+                app.last_whisperer = Some(whisper.nick.to_owned());
+                app.add_whisper(&whisper.nick.to_owned(), whisper);
+            }
+            protocol::Event::Mute(mute) => {
+                app.set_moderated(&mute.data, true);
+                app.message_list
+                    .items
+                    .push(ChatLine::Info(format!("{} muted {}", mute.nick, mute.data)));
+                new_messages = true;
+            }
+            protocol::Event::Unmute(unmute) => {
+                app.set_moderated(&unmute.data, false);
+                app.message_list.items.push(ChatLine::Info(format!(
+                    "{} unmuted {}",
+                    unmute.nick, unmute.data
+                )));
+                new_messages = true;
+            }
+            protocol::Event::Ban(ban) => {
+                app.set_moderated(&ban.data, true);
+                app.message_list
+                    .items
+                    .push(ChatLine::Info(format!("{} banned {}", ban.nick, ban.data)));
+                new_messages = true;
+            }
+            protocol::Event::Unban(unban) => {
+                app.set_moderated(&unban.data, false);
+                app.message_list.items.push(ChatLine::Info(format!(
+                    "{} unbanned {}",
+                    unban.nick, unban.data
+                )));
+                new_messages = true;
+            }
+            protocol::Event::SubOnly(subonly) => {
+                app.message_list.items.push(ChatLine::Info(format!(
+                    "{} turned subscriber only mode {}",
+                    subonly.nick, subonly.data
+                )));
+                new_messages = true;
+            }
+            protocol::Event::Err(error) => {
+                app.message_list
+                    .items
+                    .push(ChatLine::Error(error.message()));
+                new_messages = true;
+            }
+            _ => {}
+        },
+        BusEvent::Connection(state) => {
+            app.connection_state = state;
+            app.latency = None;
+        }
+        BusEvent::Latency(latency) => app.latency = Some(latency),
+        BusEvent::Outbox { queued, pending } => {
+            app.send_status.queued = queued;
+            app.send_status.pending = pending;
+        }
+        BusEvent::Delivered(id, event) => app.deliver(id, event),
+        BusEvent::SendFailed(outgoing, reason) => {
+            if !app.fail(outgoing.id, &reason) {
+                app.message_list.items.push(ChatLine::Error(format!(
+                    "Could not {}: {}",
+                    outgoing.command.describe(),
+                    reason
+                )));
+                new_messages = true;
+            }
+            if !outgoing.command.is_moderation() {
+                app.send_status.failed.push_back(outgoing);
+            }
+        }
+        BusEvent::Error(reason) => {
+            app.message_list.items.push(ChatLine::Error(reason));
+            new_messages = true;
+        }
+    }
+    new_messages
+}
+
+/// Puts the emote images of the chat where the last draw put its messages,
+/// and takes them off the screen when another tab is shown. `force` draws
+/// them again even if they haven't moved.
+fn draw_emotes(app: &mut App, force: bool) -> Result<()> {
+    // The layout is only kept up to date while the chat is shown.
+    let chat = matches!(app.tab(), Tab::Chat).then_some(&app.chat_layout);
+    if let Some(renderer) = &mut app.renderer {
+        renderer.update(chat, &app.emotes, force)?;
+    }
+    Ok(())
+}
+
+/// Highlights what the query matches in the chat and selects the newest match.
+/// With `in:log` the chat log is searched too, with results in their own tab.
+fn search(app: &mut App, input: &str) -> std::result::Result<(), String> {
+    let query = Query::parse(input)?;
+    let in_log = query.in_log;
+    app.search = Some(query);
+    app.relayout_chat();
+    app.message_list.unselect();
+
+    if in_log {
+        app.start_log_search(input)
+    } else {
+        app.select_tab(0);
+        if !app.jump_to_match(false) {
+            app.message_list.bottom();
+            return Err(format!("No messages match {}", input.trim()));
+        }
+        Ok(())
+    }
+}
+
+/// Acts on a line the user entered. In a whisper tab plain text goes to that conversation.
+fn submit_input(app: &mut App, bus: &UiBus, message: &str) -> std::result::Result<(), String> {
+    let action = match app.tab() {
+        Tab::Whisper(i) if !message.starts_with('/') => {
+            commands::whisper(&app.conversations[i].nick, message).map(Action::Send)
+        }
+        _ => commands::parse_input(message),
+    }?;
+
+    match action {
+        Action::Send(command) => send_command(app, bus, command),
+        Action::Reply(data) => match app.last_whisperer.to_owned() {
+            Some(nick) => send_command(app, bus, commands::whisper(&nick, &data)?),
+            None => Err("Nobody has whispered you yet".to_string()),
+        },
+        Action::OpenWhisper(nick) => {
+            let i = app.conversation(&nick);
+            app.show_conversation(i);
+            Ok(())
+        }
+        Action::Export => {
+            let path = chatlog::export(&app.message_list.items)
+                .map_err(|e| format!("Could not save the chat: {}", e))?;
+            app.message_list.items.push(ChatLine::Info(format!(
+                "Saved the chat to {}",
+                path.display()
+            )));
+            app.message_list.bottom();
+            Ok(())
+        }
+        Action::Ignore(nick) => {
+            if !app.filters.ignore(&nick) {
+                return Err(format!("Already ignoring {}", nick));
+            }
+            app.refilter();
+            app.message_list
+                .items
+                .push(ChatLine::Info(format!("Ignoring {}", nick)));
+            app.message_list.bottom();
+            Ok(())
+        }
+        Action::Quit => Ok(()),
+        Action::Unignore(nick) => {
+            if !app.filters.unignore(&nick) {
+                return Err(format!("Not ignoring {}", nick));
+            }
+            app.refilter();
+            let mut info = format!("No longer ignoring {}", nick);
+            if app
+                .config
+                .ignore
+                .iter()
+                .any(|ignored| ignored.eq_ignore_ascii_case(&nick))
+            {
+                info.push_str(", until the next start unless removed from the config file");
+            }
+            app.message_list.items.push(ChatLine::Info(info));
+            app.message_list.bottom();
+            Ok(())
+        }
+    }
+}
+
+fn send_command(app: &mut App, bus: &UiBus, command: Command) -> std::result::Result<(), String> {
+    if command.is_moderation() && !app.is_moderator() {
+        return Err("Only moderators can do that".to_string());
+    }
+
+    let whisper = match &command {
+        Command::PrivMsg(whisper) => Some(whisper.to_owned()),
+        _ => None,
+    };
+    let message = match &command {
+        Command::Msg(message) => Some(message.data.to_owned()),
+        _ => None,
+    };
+
+    let id = bus.send(command)?;
+
+    if let Some(data) = message {
+        app.echo(id, data);
+    }
+
+    if let Some(whisper) = whisper {
+        app.pending_whisper(id, &whisper.nick, whisper.data);
+    }
+
+    Ok(())
+}
@@ -1,6 +1,18 @@
-use crate::{config::Config, utils};
-use bytes::Bytes;
+use crate::bus::Outgoing;
+use crate::connection::ConnectionState;
+use crate::emotes::Emotes;
+use crate::filter::{FilterAction, Filters};
+use crate::flairs::Flairs;
+use crate::highlight::Highlighter;
+use crate::irender::{ChatLayout, Renderer};
+use crate::protocol::{Event, ParsedMessage, Presence, PrivateMessage, User, Users};
+use crate::search::{self, Query};
+use crate::theme::Theme;
+use crate::{config::Config, mentions, utils, whispers};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::mpsc;
+use std::time::Duration;
 use tui::widgets::{ListItem, ListState};
 
 /// App holds the state of the application
@@ -12,14 +24,47 @@ pub struct App<'a> {
     pub tab_titles: Vec<String>,
     pub tab_index: usize,
     /// History of recorded messages
-    pub message_list: MessageList<(String, usize)>,
+    pub message_list: MessageList<ChatLine>,
+    /// The lines of message_list that are drawn, leaving out hidden ones
     pub message_spans: MessageList<ListItem<'a>>,
+    /// Which line of message_list each of message_spans is
+    pub message_rows: Vec<usize>,
+    /// How many lines of message_list message_spans was built from
+    pub spans_built: usize,
+    /// Where the chat was drawn, for the emote images
+    pub chat_layout: ChatLayout,
     pub user_list: UserList<User>,
-    pub show_suggestion: bool,
-    pub users: Users,
-    pub emotes: Vec<String>,
+    pub users: Chatters,
+    pub emotes: Emotes,
+    /// Draws emote images, when they're on
+    pub renderer: Option<Renderer>,
     pub autocomplete: Autocomplete,
     pub config: Config,
+    pub connection_state: ConnectionState,
+    /// Round trip time of the last keepalive ping on this connection
+    pub latency: Option<Duration>,
+    /// Whisper conversations, each with its own tab after the fixed ones
+    pub conversations: Vec<Conversation>,
+    /// Who `/r` replies to
+    pub last_whisperer: Option<String>,
+    /// Lowercased nicks that are currently muted or banned
+    pub moderated: HashSet<String>,
+    pub send_status: SendStatus,
+    /// What's being typed in search mode
+    pub search_input: String,
+    /// The last search, highlighted in the chat until cleared with Esc
+    pub search: Option<Query>,
+    /// Results of searching the chat log, shown in a tab after the whispers
+    pub log_search: Option<LogSearch>,
+    pub filters: Filters,
+    pub highlighter: Highlighter,
+    pub flairs: Flairs,
+    pub theme: Theme,
+    pub mentions: Mentions,
+    /// Keep the selected line in view as messages arrive, after jumping to a mention
+    pub hold_scroll: bool,
+    /// Show the messages filters hid, toggled with h
+    pub show_filtered: bool,
 }
 
 impl<'a> Default for App<'a> {
@@ -27,16 +72,39 @@ impl<'a> Default for App<'a> {
         App {
             input: String::new(),
             input_mode: InputMode::Normal,
-            tab_titles: vec!["Chat".to_string(), "Users".to_string()],
+            tab_titles: vec![
+                "Chat".to_string(),
+                "Users".to_string(),
+                "Mentions".to_string(),
+            ],
             tab_index: 0,
             message_list: MessageList::with_items(vec![]),
             message_spans: MessageList::with_items(vec![]),
+            message_rows: vec![],
+            spans_built: 0,
+            chat_layout: ChatLayout::default(),
             user_list: UserList::with_items(vec![]),
-            show_suggestion: false,
-            users: Users::from(Users::default()),
-            emotes: { utils::get_emotenames() },
-            autocomplete: Autocomplete::from(Autocomplete::default()),
+            users: Chatters::default(),
+            emotes: Emotes::default(),
+            renderer: None,
+            autocomplete: Autocomplete::default(),
             config: Config::default(),
+            connection_state: ConnectionState::Connecting,
+            latency: None,
+            conversations: vec![],
+            last_whisperer: None,
+            moderated: HashSet::new(),
+            send_status: SendStatus::default(),
+            search_input: String::new(),
+            search: None,
+            log_search: None,
+            filters: Filters::default(),
+            highlighter: Highlighter::default(),
+            flairs: Flairs::default(),
+            theme: Theme::default(),
+            mentions: Mentions::default(),
+            hold_scroll: false,
+            show_filtered: false,
         }
     }
 }
@@ -50,23 +118,644 @@ impl<'a> App<'a> {
     // }
 
     pub fn next_tab(&mut self) {
-        self.tab_index = (self.tab_index + 1) % self.tab_titles.len();
+        self.select_tab((self.tab_index + 1) % self.tab_titles.len());
     }
 
     pub fn prev_tab(&mut self) {
         if self.tab_index > 0 {
-            self.tab_index -= 1;
+            self.select_tab(self.tab_index - 1);
         } else {
-            self.tab_index = self.tab_titles.len() - 1;
+            self.select_tab(self.tab_titles.len() - 1);
         }
     }
+
+    pub fn select_tab(&mut self, index: usize) {
+        self.tab_index = index;
+        match self.tab() {
+            Tab::Whisper(i) => self.conversations[i].unread = 0,
+            Tab::Mentions => {
+                self.mentions.new_after = self.mentions.read;
+                self.mentions.mark_read();
+            }
+            _ => {}
+        }
+    }
+
+    /// Unread whispers or mentions in the tab at `index`.
+    pub fn unread(&self, index: usize) -> usize {
+        if index == MENTIONS_TAB {
+            return self.mentions.unread();
+        }
+        index
+            .checked_sub(FIXED_TABS)
+            .and_then(|i| self.conversations.get(i))
+            .map_or(0, |c| c.unread)
+    }
+
+    pub fn tab(&self) -> Tab {
+        match self.tab_index {
+            0 => Tab::Chat,
+            1 => Tab::Users,
+            MENTIONS_TAB => Tab::Mentions,
+            i if i - FIXED_TABS < self.conversations.len() => Tab::Whisper(i - FIXED_TABS),
+            _ => Tab::Search,
+        }
+    }
+
+    /// Index of the conversation with `nick`, opening a tab for it if there isn't one yet.
+    pub fn conversation(&mut self, nick: &str) -> usize {
+        if let Some(i) = self
+            .conversations
+            .iter()
+            .position(|c| c.nick.eq_ignore_ascii_case(nick))
+        {
+            return i;
+        }
+
+        let mut messages = MessageList::with_items(whispers::load(nick));
+        messages.bottom();
+        // Whisper tabs go before the search tab, if that's open.
+        let index = FIXED_TABS + self.conversations.len();
+        self.conversations.push(Conversation {
+            nick: nick.to_string(),
+            messages,
+            pending: vec![],
+            unread: 0,
+        });
+        self.tab_titles.insert(index, nick.to_string());
+        if self.tab_index >= index {
+            self.tab_index += 1;
+        }
+        self.conversations.len() - 1
+    }
+
+    /// Adds a whisper to the conversation with `nick`.
+    pub fn add_whisper(&mut self, nick: &str, message: PrivateMessage) {
+        let i = self.conversation(nick);
+
+        let is_open = matches!(self.tab(), Tab::Whisper(current) if current == i);
+        let conversation = &mut self.conversations[i];
+        // The logger may have saved it before the conversation was loaded.
+        let loaded = conversation.messages.items.iter().rev().take(5).any(|m| {
+            m.timestamp == message.timestamp && m.nick == message.nick && m.data == message.data
+        });
+        if loaded {
+            return;
+        }
+        conversation.messages.items.push(message);
+        conversation.messages.bottom();
+        if !is_open {
+            conversation.unread += 1;
+        }
+    }
+
+    /// Shows a whisper we just sent to `nick` as pending until the server confirms it.
+    pub fn pending_whisper(&mut self, id: u64, nick: &str, data: String) {
+        let i = self.conversation(nick);
+        let own_nick = self.config.username.to_owned().unwrap_or_default();
+        self.conversations[i].pending.push(LocalMessage {
+            id,
+            message: ParsedMessage {
+                nick: own_nick,
+                features: vec![],
+                timestamp: utils::timestamp(),
+                data,
+            },
+            failed: None,
+        });
+    }
+
+    /// Moves a pending whisper into its conversation once the server confirmed it.
+    fn commit_whisper(&mut self, id: u64) {
+        let own_nick = self.config.username.to_owned().unwrap_or_default();
+        for conversation in &mut self.conversations {
+            if let Some(i) = conversation.pending.iter().position(|local| local.id == id) {
+                let local = conversation.pending.remove(i);
+                conversation.messages.items.push(PrivateMessage {
+                    messageid: 0,
+                    nick: own_nick,
+                    timestamp: utils::timestamp(),
+                    data: local.message.data,
+                });
+                conversation.messages.bottom();
+                return;
+            }
+        }
+    }
+
+    pub fn show_conversation(&mut self, i: usize) {
+        self.select_tab(i + FIXED_TABS);
+    }
+
+    pub fn is_moderator(&self) -> bool {
+        let nick = match &self.config.username {
+            Some(nick) => nick,
+            None => return false,
+        };
+        self.users.get(nick).is_some_and(|user| {
+            user.features
+                .iter()
+                .any(|feature| feature == "moderator" || feature == "admin")
+        })
+    }
+
+    /// Whether the chat shows this event when it arrives.
+    pub fn shows(&self, event: &Event) -> bool {
+        match event {
+            Event::Msg(_) => true,
+            _ => event.is_banner() && !self.config.hide.hides(event),
+        }
+    }
+
+    /// Shows a message we just sent as pending until the server echoes it.
+    pub fn echo(&mut self, id: u64, data: String) {
+        let nick = self.config.username.to_owned().unwrap_or_default();
+        let features = self
+            .users
+            .get(&nick)
+            .map(|user| user.features.to_owned())
+            .unwrap_or_default();
+        self.message_list.items.push(ChatLine::Local(LocalMessage {
+            id,
+            message: ParsedMessage {
+                nick,
+                features,
+                timestamp: utils::timestamp(),
+                data,
+            },
+            failed: None,
+        }));
+        self.message_list.bottom();
+    }
+
+    fn local_message(&mut self, id: u64) -> Option<&mut LocalMessage> {
+        self.message_list
+            .items
+            .iter_mut()
+            .rev()
+            .filter_map(|line| match line {
+                ChatLine::Local(local) => Some(local),
+                _ => None,
+            })
+            .chain(
+                self.conversations
+                    .iter_mut()
+                    .flat_map(|conversation| conversation.pending.iter_mut()),
+            )
+            .find(|local| local.id == id)
+    }
+
+    /// Takes `nick` as ours when the config doesn't say, so our own messages
+    /// and mentions of us are recognized.
+    pub fn learn_nick(&mut self, nick: &str) {
+        if self.config.username.is_some() || nick.is_empty() {
+            return;
+        }
+        self.config.username = Some(nick.to_string());
+        self.highlighter = Highlighter::new(&self.config, &self.theme).0;
+        self.relayout_chat();
+    }
+
+    /// Replaces the pending line with the server's echo of it.
+    pub fn deliver(&mut self, id: u64, event: Event) {
+        if let Event::PrivMsgSent = event {
+            self.commit_whisper(id);
+            return;
+        }
+        if let Event::Msg(msg) = &event {
+            self.learn_nick(&msg.nick);
+        }
+        let line = self
+            .message_list
+            .items
+            .iter_mut()
+            .rev()
+            .find(|line| matches!(line, ChatLine::Local(local) if local.id == id));
+        match line {
+            Some(line) => *line = ChatLine::Event(event),
+            None => self.message_list.items.push(ChatLine::Event(event)),
+        }
+        self.relayout_chat();
+    }
+
+    /// Marks the pending line as failed. Returns false if there's no such line.
+    pub fn fail(&mut self, id: u64, reason: &str) -> bool {
+        let found = match self.local_message(id) {
+            Some(local) => {
+                local.failed = Some(reason.to_string());
+                true
+            }
+            None => false,
+        };
+        self.relayout_chat();
+        found
+    }
+
+    /// A failed message was sent again under a new id.
+    pub fn resend(&mut self, old_id: u64, id: u64) {
+        if let Some(local) = self.local_message(old_id) {
+            local.id = id;
+            local.failed = None;
+        }
+        self.relayout_chat();
+    }
+
+    /// Removes the line of a failed message the user gave up on.
+    pub fn discard(&mut self, id: u64) {
+        self.message_list
+            .items
+            .retain(|line| !matches!(line, ChatLine::Local(local) if local.id == id));
+        for conversation in &mut self.conversations {
+            conversation.pending.retain(|local| local.id != id);
+        }
+        self.relayout_chat();
+    }
+
+    /// Marks `nick` as muted/banned or not, restyling their lines if we strike them out.
+    pub fn set_moderated(&mut self, nick: &str, moderated: bool) {
+        let nick = nick.to_lowercase();
+        if moderated {
+            self.moderated.insert(nick);
+        } else {
+            self.moderated.remove(&nick);
+        }
+        if self.config.strike_moderated {
+            self.relayout_chat();
+        }
+    }
+
+    /// The line a received message goes into the chat as. Our own messages are
+    /// never filtered.
+    pub fn message_line(&self, message: ParsedMessage) -> ChatLine {
+        let own = self
+            .config
+            .username
+            .as_ref()
+            .is_some_and(|nick| nick.eq_ignore_ascii_case(&message.nick));
+        if own {
+            ChatLine::Event(Event::Msg(message))
+        } else {
+            self.filters.line(message)
+        }
+    }
+
+    /// Filters the messages already in the chat again, after the ignore list changed.
+    pub fn refilter(&mut self) {
+        let lines = std::mem::take(&mut self.message_list.items);
+        self.message_list.items = lines
+            .into_iter()
+            .map(|line| match line {
+                ChatLine::Event(Event::Msg(message)) | ChatLine::Filtered(message, _) => {
+                    self.message_line(message)
+                }
+                line => line,
+            })
+            .collect();
+        self.relayout_chat();
+    }
+
+    /// Collects a message that matches the highlight rules into the Mentions tab.
+    pub fn add_mention(&mut self, message: &ParsedMessage) {
+        if !self.highlighter.mentions(message) {
+            return;
+        }
+        let mentions = &mut self.mentions;
+        let known = mentions.messages.items.iter().rev().any(|m| {
+            m.timestamp == message.timestamp && m.nick == message.nick && m.data == message.data
+        });
+        if known {
+            return;
+        }
+
+        mentions.messages.items.push(message.to_owned());
+        if mentions.persist {
+            let _ = mentions::save(message);
+        }
+        if matches!(self.tab(), Tab::Mentions) {
+            self.mentions.mark_read();
+        }
+    }
+
+    /// Whether a chat line is left out of the chat altogether.
+    pub fn hides(&self, line: &ChatLine) -> bool {
+        !self.show_filtered && matches!(line, ChatLine::Filtered(_, FilterAction::Hide))
+    }
+
+    /// Has the chat laid out again on the next draw, after something changed how it looks.
+    pub fn relayout_chat(&mut self) {
+        self.message_spans.items.clear();
+        self.spans_built = 0;
+    }
+
+    /// Whether message_spans has to be rebuilt, because lines came in or it was thrown away.
+    pub fn spans_outdated(&self) -> bool {
+        self.spans_built != self.message_list.items.len()
+    }
+
+    /// The drawn line the chat selection shows up on: the selected one, or the
+    /// closest one after it if that's hidden, or before it at the end.
+    fn shown_line(&self) -> Option<usize> {
+        let selected = self.message_list.state.selected()?;
+        let items = &self.message_list.items;
+        (selected..items.len())
+            .find(|&i| !self.hides(&items[i]))
+            .or_else(|| {
+                (0..selected.min(items.len()))
+                    .rev()
+                    .find(|&i| !self.hides(&items[i]))
+            })
+    }
+
+    /// Which of message_spans is selected.
+    pub fn selected_row(&self) -> Option<usize> {
+        let line = self.shown_line()?;
+        Some(self.message_rows.partition_point(|&row| row < line))
+    }
+
+    /// Selects the next drawn line in the chat, staying on the last one.
+    pub fn next_line(&mut self) {
+        let start = self.shown_line().map_or(0, |line| line + 1);
+        let items = &self.message_list.items;
+        if let Some(i) = (start..items.len()).find(|&i| !self.hides(&items[i])) {
+            self.message_list.state.select(Some(i));
+        }
+    }
+
+    /// Selects the previous drawn line in the chat, staying on the first one.
+    pub fn previous_line(&mut self) {
+        let end = match self.shown_line() {
+            Some(line) => line,
+            None => return self.first_line(),
+        };
+        let items = &self.message_list.items;
+        if let Some(i) = (0..end).rev().find(|&i| !self.hides(&items[i])) {
+            self.message_list.state.select(Some(i));
+        }
+    }
+
+    /// Selects the first drawn line in the chat.
+    pub fn first_line(&mut self) {
+        let items = &self.message_list.items;
+        if let Some(i) = (0..items.len()).find(|&i| !self.hides(&items[i])) {
+            self.message_list.state.select(Some(i));
+        }
+    }
+
+    /// Selects the mention's message in the chat, if it's still there.
+    pub fn jump_to_mention(&mut self, i: usize) -> bool {
+        let mention = match self.mentions.messages.items.get(i) {
+            Some(mention) => mention,
+            None => return false,
+        };
+        let found = self.message_list.items.iter().rposition(|line| match line {
+            ChatLine::Event(Event::Msg(m)) => {
+                m.timestamp == mention.timestamp && m.nick == mention.nick && m.data == mention.data
+            }
+            _ => false,
+        });
+        match found {
+            Some(found) => {
+                self.message_list.state.select(Some(found));
+                self.hold_scroll = true;
+                self.select_tab(0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Users worth suggesting when completing a nick, leaving out ignored ones.
+    pub fn completable_users(&self) -> Vec<User> {
+        self.user_list
+            .items
+            .iter()
+            .filter(|user| !self.filters.ignores(&user.nick))
+            .cloned()
+            .collect()
+    }
+
+    /// Rebuilds the Users tab from the live user model, keeping the selection in place.
+    pub fn refresh_user_list(&mut self) {
+        self.user_list.items = self.users.sorted();
+        if let Some(selected) = self.user_list.state.selected() {
+            if selected >= self.user_list.items.len() {
+                self.user_list.unselect();
+                self.user_list.bottom();
+            }
+        }
+    }
+
+    /// Adds someone who joined to the user list, where they sort.
+    pub fn user_joined(&mut self, presence: &Presence) {
+        if !self.users.join(presence) {
+            return;
+        }
+        let user = match self.users.get(&presence.nick) {
+            Some(user) => user.clone(),
+            None => return,
+        };
+        if let Err(i) = self.user_position(&user.nick) {
+            self.user_list.items.insert(i, user);
+            if let Some(selected) = self.user_list.state.selected() {
+                if selected >= i {
+                    self.user_list.state.select(Some(selected + 1));
+                }
+            }
+        }
+    }
+
+    /// Takes someone off the user list once their last connection quits.
+    pub fn user_quit(&mut self, presence: &Presence) {
+        if !self.users.quit(presence) {
+            return;
+        }
+        if let Ok(i) = self.user_position(&presence.nick) {
+            self.user_list.items.remove(i);
+            match self.user_list.state.selected() {
+                Some(selected) if selected > i => self.user_list.state.select(Some(selected - 1)),
+                Some(selected) if selected >= self.user_list.items.len() => {
+                    self.user_list.unselect();
+                    self.user_list.bottom();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Takes the new features of someone whose flairs changed.
+    pub fn user_refreshed(&mut self, user: User) {
+        if let Ok(i) = self.user_position(&user.nick) {
+            self.user_list.items[i].features = user.features.to_owned();
+        }
+        self.users.refresh(user);
+    }
+
+    /// Where `nick` is in the sorted user list, or would go.
+    fn user_position(&self, nick: &str) -> Result<usize, usize> {
+        let key = nick.to_lowercase();
+        self.user_list
+            .items
+            .binary_search_by(|user| user.nick.to_lowercase().cmp(&key))
+    }
+
+    /// Selects the next line matching the search, older ones first unless going
+    /// `down`, wrapping around at either end.
+    pub fn jump_to_match(&mut self, down: bool) -> bool {
+        let query = match &self.search {
+            Some(query) => query,
+            None => return false,
+        };
+        let len = self.message_list.items.len();
+        let start = self.message_list.state.selected().unwrap_or(len);
+
+        for step in 1..=len {
+            let i = if down {
+                (start + step) % len
+            } else {
+                (start + 2 * len - step) % len
+            };
+            if query.matches_line(&self.message_list.items[i]) {
+                self.message_list.state.select(Some(i));
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Searches the chat log in the background and opens a tab for the results.
+    pub fn start_log_search(&mut self, input: &str) -> Result<(), String> {
+        let query = Query::parse(input)?;
+        let (results_tx, results) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = results_tx.send(search::search_log(&query));
+        });
+
+        if self.log_search.is_none() {
+            self.tab_titles.push("Search".to_string());
+        }
+        self.log_search = Some(LogSearch {
+            query: Query::parse(input)?,
+            results: MessageList::with_items(vec![]),
+            pending: Some(results),
+        });
+        self.select_tab(self.tab_titles.len() - 1);
+        Ok(())
+    }
+
+    /// Picks up the results of a log search once it's done.
+    pub fn poll_log_search(&mut self) {
+        if let Some(log_search) = &mut self.log_search {
+            if let Some(Ok(results)) = log_search.pending.as_ref().map(|rx| rx.try_recv()) {
+                log_search.results = MessageList::with_items(results);
+                log_search.results.bottom();
+                log_search.pending = None;
+            }
+        }
+    }
+
+    pub fn close_log_search(&mut self) {
+        if self.log_search.take().is_some() {
+            self.tab_titles.pop();
+            self.select_tab(self.tab_index.min(self.tab_titles.len() - 1));
+        }
+    }
+
+    pub fn close_conversation(&mut self, i: usize) {
+        self.conversations.remove(i);
+        self.tab_titles.remove(i + FIXED_TABS);
+        self.select_tab(self.tab_index.min(self.tab_titles.len() - 1));
+    }
+}
+
+/// Chat, Users and Mentions always come first, whisper tabs follow, then log search results.
+const FIXED_TABS: usize = 3;
+const MENTIONS_TAB: usize = 2;
+
+pub enum Tab {
+    Chat,
+    Users,
+    Mentions,
+    Whisper(usize),
+    Search,
+}
+
+pub struct LogSearch {
+    pub query: Query,
+    pub results: MessageList<ParsedMessage>,
+    /// Where the results arrive from while the search is running
+    pub pending: Option<mpsc::Receiver<Vec<ParsedMessage>>>,
+}
+
+/// Outgoing commands, as last reported by the websocket task.
+#[derive(Default)]
+pub struct SendStatus {
+    pub queued: usize,
+    pub pending: usize,
+    /// Messages the server refused, oldest first, until retried or discarded
+    pub failed: VecDeque<Outgoing>,
+}
+
+/// Messages matching the highlight rules, kept across restarts.
+#[derive(Default)]
+pub struct Mentions {
+    pub messages: MessageList<ParsedMessage>,
+    /// Timestamp of the newest mention seen in the Mentions tab
+    pub read: u64,
+    /// Mentions newer than this are marked as new, it's where `read` was when
+    /// the tab was opened
+    pub new_after: u64,
+    /// Whether new mentions are saved, not when replaying a recording
+    pub persist: bool,
+}
+
+impl Mentions {
+    /// The saved mentions, and where we left off reading them.
+    pub fn load() -> Mentions {
+        let read = mentions::load_read();
+        let mut messages = MessageList::with_items(mentions::load());
+        messages.bottom();
+        Mentions {
+            messages,
+            read,
+            new_after: read,
+            persist: true,
+        }
+    }
+
+    pub fn unread(&self) -> usize {
+        self.messages
+            .items
+            .iter()
+            .filter(|m| m.timestamp > self.read)
+            .count()
+    }
+
+    fn mark_read(&mut self) {
+        let newest = self.messages.items.iter().map(|m| m.timestamp).max();
+        if let Some(newest) = newest.filter(|newest| *newest > self.read) {
+            self.read = newest;
+            if self.persist {
+                let _ = mentions::save_read(newest);
+            }
+        }
+    }
+}
+
+pub struct Conversation {
+    pub nick: String,
+    pub messages: MessageList<PrivateMessage>,
+    /// Whispers we sent that the server hasn't confirmed yet.
+    pub pending: Vec<LocalMessage>,
+    pub unread: usize,
 }
 
 pub enum InputMode {
     Normal,
     Editing,
+    Search,
 }
 
+#[derive(Default)]
 pub struct MessageList<T> {
     pub state: ListState,
     pub items: Vec<T>,
@@ -80,7 +769,11 @@ impl<T> MessageList<T> {
         }
     }
 
+    /// Does nothing on an empty list.
     pub fn next(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i >= self.items.len() - 1 {
@@ -95,6 +788,9 @@ impl<T> MessageList<T> {
     }
 
     pub fn previous(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i == 0 {
@@ -113,11 +809,15 @@ impl<T> MessageList<T> {
     }
 
     pub fn bottom(&mut self) {
-        self.state.select(Some(self.items.len() - 1));
+        if !self.items.is_empty() {
+            self.state.select(Some(self.items.len() - 1));
+        }
     }
 
     pub fn top(&mut self) {
-        self.state.select(Some(0));
+        if !self.items.is_empty() {
+            self.state.select(Some(0));
+        }
     }
 }
 
@@ -134,7 +834,11 @@ impl<T> UserList<T> {
         }
     }
 
+    /// Does nothing on an empty list.
     pub fn next(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i >= self.items.len() - 1 {
@@ -149,6 +853,9 @@ impl<T> UserList<T> {
     }
 
     pub fn previous(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i == 0 {
@@ -167,32 +874,132 @@ impl<T> UserList<T> {
     }
 
     pub fn bottom(&mut self) {
-        self.state.select(Some(self.items.len() - 1));
+        if !self.items.is_empty() {
+            self.state.select(Some(self.items.len() - 1));
+        }
     }
 
     pub fn top(&mut self) {
-        self.state.select(Some(0));
+        if !self.items.is_empty() {
+            self.state.select(Some(0));
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct EmoteData {
-    pub term_size: u16,
-    pub messages: Vec<(String, usize)>,
-    pub message_pos: usize,
+/// Everyone in chat, kept in sync from NAMES, JOIN and QUIT.
+///
+/// One user can be connected several times, so we count connections per nick and
+/// only drop someone once their last connection quits. NAMES only lists who is
+/// here, so for those users the count is unknown and their first QUIT drops them.
+#[derive(Debug, Default, Clone)]
+pub struct Chatters {
+    pub connectioncount: u16,
+    /// Keyed by lowercased nick, with their connections when we know them
+    users: HashMap<String, (User, Option<u16>)>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ParsedMessage {
-    pub nick: String,
-    pub features: Vec<String>,
-    pub timestamp: u64,
-    pub data: String,
+impl Chatters {
+    pub fn from_names(names: Users) -> Chatters {
+        let users = names
+            .users
+            .into_iter()
+            .map(|user| (user.nick.to_lowercase(), (user, None)))
+            .collect();
+
+        Chatters {
+            connectioncount: names.connectioncount,
+            users,
+        }
+    }
+
+    /// Counts a new connection, and says whether it's someone who wasn't here yet.
+    pub fn join(&mut self, presence: &Presence) -> bool {
+        self.connectioncount = self.connectioncount.saturating_add(1);
+        match self.users.get_mut(&presence.nick.to_lowercase()) {
+            Some(entry) => {
+                entry.1 = entry.1.map(|connections| connections.saturating_add(1));
+                false
+            }
+            None => {
+                let user = User {
+                    nick: presence.nick.to_owned(),
+                    features: presence.features.to_owned(),
+                };
+                self.users
+                    .insert(presence.nick.to_lowercase(), (user, Some(1)));
+                true
+            }
+        }
+    }
+
+    /// Counts a closed connection, and says whether it was their last one.
+    pub fn quit(&mut self, presence: &Presence) -> bool {
+        self.connectioncount = self.connectioncount.saturating_sub(1);
+        let key = presence.nick.to_lowercase();
+        match self.users.get_mut(&key) {
+            Some((_, Some(connections))) if *connections > 1 => {
+                *connections -= 1;
+                false
+            }
+            Some(_) => {
+                self.users.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes the new features of someone whose flairs changed.
+    pub fn refresh(&mut self, user: User) {
+        if let Some(entry) = self.users.get_mut(&user.nick.to_lowercase()) {
+            entry.0.features = user.features;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn get(&self, nick: &str) -> Option<&User> {
+        self.users.get(&nick.to_lowercase()).map(|(user, _)| user)
+    }
+
+    /// How many connections `nick` has open, if we know.
+    pub fn connections(&self, nick: &str) -> Option<u16> {
+        self.users
+            .get(&nick.to_lowercase())
+            .and_then(|(_, connections)| *connections)
+    }
+
+    /// Everyone in chat, sorted by nick.
+    pub fn sorted(&self) -> Vec<User> {
+        let mut users: Vec<User> = self.users.values().map(|(user, _)| user.clone()).collect();
+        users.sort_by_key(|user| user.nick.to_lowercase());
+        users
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum HistoryJSON {
-    String(String),
+/// A line in the message list, either received from the server or produced locally.
+#[derive(Debug, Clone)]
+pub enum ChatLine {
+    Event(Event),
+    /// A message we sent that the server hasn't echoed back yet.
+    Local(LocalMessage),
+    Info(String),
+    Error(String),
+    /// Where scrollback from the log ends, with the time of the last logged line.
+    Separator(u64),
+    /// A message from an ignored user or caught by a content filter.
+    Filtered(ParsedMessage, FilterAction),
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalMessage {
+    /// Id the message was sent with, see `UiBus::send`
+    pub id: u64,
+    pub message: ParsedMessage,
+    /// Why it wasn't delivered, or None while we're still waiting
+    pub failed: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -229,12 +1036,10 @@ impl Autocomplete {
             } else {
                 self.selected = Some(0)
             }
+        } else if !self.suggestions.is_empty() {
+            self.selected = Some(0)
         } else {
-            if self.suggestions.len() > 0 {
-                self.selected = Some(0)
-            } else {
-                self.selected = None
-            }
+            self.selected = None
         }
     }
 
@@ -253,29 +1058,186 @@ impl Autocomplete {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
-pub struct User {
-    pub nick: String,
-    pub features: Vec<String>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
-pub struct Users {
-    pub connectioncount: u16,
-    pub users: Vec<User>,
-}
+    #[test]
+    fn moving_through_an_empty_list_does_nothing() {
+        let mut list: MessageList<ChatLine> = MessageList::with_items(vec![]);
+        list.next();
+        list.previous();
+        list.top();
+        list.bottom();
+        assert_eq!(list.state.selected(), None);
 
-#[derive(Debug, Clone)]
-pub enum InternalMessageType {
-    UPDATE,
-    ERROR,
-    COMMAND,
-    PING,
-}
+        let mut users: UserList<User> = UserList::with_items(vec![]);
+        users.next();
+        users.previous();
+        users.top();
+        assert_eq!(users.state.selected(), None);
+    }
 
-#[derive(Debug, Clone)]
-pub struct InternalMessage {
-    pub message_type: InternalMessageType,
-    pub message: String,
-    pub data: Bytes,
+    #[test]
+    fn next_and_previous_stop_at_the_ends() {
+        let mut list = MessageList::with_items(vec![1, 2, 3]);
+        list.next();
+        assert_eq!(list.state.selected(), Some(0));
+        list.previous();
+        assert_eq!(list.state.selected(), Some(0));
+        list.bottom();
+        list.next();
+        assert_eq!(list.state.selected(), Some(2));
+    }
+
+    fn presence(nick: &str) -> Presence {
+        Presence {
+            nick: nick.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn nicks(app: &App) -> Vec<String> {
+        app.user_list
+            .items
+            .iter()
+            .map(|user| user.nick.to_owned())
+            .collect()
+    }
+
+    fn app_with_names(names: &[&str]) -> App<'static> {
+        let mut app = App {
+            users: Chatters::from_names(Users {
+                connectioncount: names.len() as u16,
+                users: names
+                    .iter()
+                    .map(|nick| User {
+                        nick: nick.to_string(),
+                        features: vec![],
+                    })
+                    .collect(),
+            }),
+            ..Default::default()
+        };
+        app.refresh_user_list();
+        app
+    }
+
+    #[test]
+    fn connections_from_names_are_unknown() {
+        let app = app_with_names(&["Bob", "alice"]);
+        assert_eq!(nicks(&app), vec!["alice", "Bob"]);
+        assert_eq!(app.users.connections("bob"), None);
+    }
+
+    #[test]
+    fn joins_and_quits_keep_the_list_sorted() {
+        let mut app = app_with_names(&["alice", "Carol"]);
+        app.user_list.state.select(Some(1));
+
+        app.user_joined(&presence("bob"));
+        assert_eq!(nicks(&app), vec!["alice", "bob", "Carol"]);
+        assert_eq!(app.users.connections("bob"), Some(1));
+        // Carol stays selected.
+        assert_eq!(app.user_list.state.selected(), Some(2));
+
+        app.user_joined(&presence("Bob"));
+        assert_eq!(app.users.connections("bob"), Some(2));
+        assert_eq!(nicks(&app), vec!["alice", "bob", "Carol"]);
+
+        app.user_quit(&presence("bob"));
+        assert_eq!(nicks(&app), vec!["alice", "bob", "Carol"]);
+        app.user_quit(&presence("bob"));
+        assert_eq!(nicks(&app), vec!["alice", "Carol"]);
+        assert_eq!(app.user_list.state.selected(), Some(1));
+
+        app.user_quit(&presence("Carol"));
+        assert_eq!(nicks(&app), vec!["alice"]);
+        assert_eq!(app.user_list.state.selected(), Some(0));
+        assert_eq!(app.users.connectioncount, 1);
+    }
+
+    #[test]
+    fn whispers_wait_for_the_server() {
+        let mut app = App::default();
+        app.pending_whisper(1, "test-whisper-partner", "hi".to_string());
+        app.pending_whisper(2, "test-whisper-partner", "still there?".to_string());
+        let conversation = &app.conversations[0];
+        assert!(conversation.messages.items.is_empty());
+        assert_eq!(conversation.pending.len(), 2);
+
+        // We learn who we are before the server confirms the first one.
+        app.learn_nick("tester");
+        app.deliver(1, Event::PrivMsgSent);
+        let conversation = &app.conversations[0];
+        assert_eq!(conversation.messages.items.len(), 1);
+        assert_eq!(conversation.messages.items[0].nick, "tester");
+        assert_eq!(conversation.messages.items[0].data, "hi");
+        assert_eq!(conversation.pending.len(), 1);
+
+        assert!(app.fail(2, "throttled"));
+        let conversation = &app.conversations[0];
+        assert_eq!(conversation.messages.items.len(), 1);
+        assert_eq!(conversation.pending[0].failed.as_deref(), Some("throttled"));
+
+        app.resend(2, 3);
+        assert_eq!(app.conversations[0].pending[0].failed, None);
+        app.discard(3);
+        assert!(app.conversations[0].pending.is_empty());
+        assert_eq!(app.conversations[0].messages.items.len(), 1);
+    }
+
+    fn chat_line(data: &str, action: Option<FilterAction>) -> ChatLine {
+        let message = ParsedMessage {
+            nick: "alice".to_string(),
+            features: vec![],
+            timestamp: 0,
+            data: data.to_string(),
+        };
+        match action {
+            Some(action) => ChatLine::Filtered(message, action),
+            None => ChatLine::Event(Event::Msg(message)),
+        }
+    }
+
+    #[test]
+    fn hidden_lines_are_never_selected() {
+        let mut app = App::default();
+        app.message_list.items = vec![
+            chat_line("hidden first", Some(FilterAction::Hide)),
+            chat_line("one", None),
+            chat_line("hidden", Some(FilterAction::Hide)),
+            chat_line("collapsed", Some(FilterAction::Collapse)),
+            chat_line("hidden last", Some(FilterAction::Hide)),
+        ];
+        app.message_rows = vec![1, 3];
+
+        app.message_list.bottom();
+        assert_eq!(app.selected_row(), Some(1));
+        app.previous_line();
+        assert_eq!(app.message_list.state.selected(), Some(1));
+        assert_eq!(app.selected_row(), Some(0));
+        app.previous_line();
+        assert_eq!(app.message_list.state.selected(), Some(1));
+        app.next_line();
+        assert_eq!(app.message_list.state.selected(), Some(3));
+        app.next_line();
+        assert_eq!(app.message_list.state.selected(), Some(3));
+
+        app.message_list.unselect();
+        app.next_line();
+        assert_eq!(app.message_list.state.selected(), Some(1));
+        app.message_list.top();
+        assert_eq!(app.selected_row(), Some(0));
+        app.first_line();
+        assert_eq!(app.message_list.state.selected(), Some(1));
+
+        // Showing what the filters took out makes every line selectable.
+        app.show_filtered = true;
+        app.message_rows = (0..5).collect();
+        app.first_line();
+        assert_eq!(app.message_list.state.selected(), Some(0));
+        app.next_line();
+        assert_eq!(app.message_list.state.selected(), Some(1));
+    }
 }
@@ -1,19 +1,24 @@
 use tui::{
     backend::Backend,
-    layout::{Constraint, Direction, Layout, Rect},
-    style::{Color, Modifier, Style},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
+    style::{Modifier, Style},
     text::{Span, Spans, Text},
-    widgets::{Block, Borders, Clear, List, ListItem, Paragraph, Tabs},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Tabs},
     Frame,
 };
 
 use unicode_width::UnicodeWidthStr;
 
-use crate::types::{App, InputMode, ParsedMessage};
-use crate::utils::{format_message, format_user, parse_message};
-use crate::JSON_Result;
+use crate::connection::ConnectionState;
+use crate::filter::FilterAction;
+use crate::irender::EmoteCell;
+use crate::protocol::{Event, ParsedMessage};
+use crate::theme::Theme;
+use crate::types::{App, ChatLine, InputMode, Tab};
+use crate::utils::{self, format_banner, format_message, format_user};
 
 pub fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
+    let theme = &app.theme;
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(0)
@@ -32,67 +37,86 @@ pub fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
         InputMode::Normal => (
             vec![
                 Span::raw("Press "),
-                Span::styled("q", Style::default().add_modifier(Modifier::BOLD)),
+                Span::styled("q", theme.help_key),
                 Span::raw(" to exit, "),
-                Span::styled("e", Style::default().add_modifier(Modifier::BOLD)),
+                Span::styled("e", theme.help_key),
                 Span::raw(" to start typing."),
             ],
-            Style::default().add_modifier(Modifier::RAPID_BLINK),
+            theme.help.add_modifier(Modifier::RAPID_BLINK),
         ),
         InputMode::Editing => (
             vec![
                 Span::raw("Press "),
-                Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+                Span::styled("Esc", theme.help_key),
                 Span::raw(" to stop typing, "),
-                Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+                Span::styled("Enter", theme.help_key),
                 Span::raw(" to send."),
             ],
-            Style::default(),
+            theme.help,
+        ),
+        InputMode::Search => (
+            vec![
+                Span::raw("Press "),
+                Span::styled("Enter", theme.help_key),
+                Span::raw(" to search, "),
+                Span::styled("n", theme.help_key),
+                Span::raw("/"),
+                Span::styled("N", theme.help_key),
+                Span::raw(" for older/newer matches."),
+            ],
+            theme.help,
         ),
     };
 
     let tab_titles: Vec<Spans> = app
         .tab_titles
         .iter()
-        .map(|t| {
+        .enumerate()
+        .map(|(i, t)| {
             let (first, rest) = t.split_at(1);
-            Spans::from(vec![
-                Span::styled(first, Style::default().fg(Color::Yellow)),
-                Span::styled(rest, Style::default().fg(Color::Green)),
-            ])
+            let mut title = vec![
+                Span::styled(first, theme.tab_hotkey),
+                Span::styled(rest, theme.tab_title),
+            ];
+            let unread = app.unread(i);
+            if unread > 0 {
+                title.push(Span::styled(format!(" ({})", unread), theme.unread));
+            }
+            Spans::from(title)
         })
         .collect();
 
     let tabs = Tabs::new(tab_titles)
-        .block(Block::default().borders(Borders::ALL).title("Tabs"))
+        .block(bordered(theme).title("Tabs"))
         .select(app.tab_index)
-        .style(Style::default().fg(Color::Cyan))
-        .highlight_style(
-            Style::default()
-                .add_modifier(Modifier::BOLD)
-                .bg(Color::Black),
-        );
+        .style(theme.tab)
+        .highlight_style(theme.tab_selected);
 
     let mut text = Text::from(Spans::from(msg));
     text.patch_style(style);
     let help_message = Paragraph::new(text);
 
-    let input = Paragraph::new(app.input.as_ref())
+    let input_text = match app.input_mode {
+        InputMode::Search => format!("/{}", app.search_input),
+        _ => app.input.to_owned(),
+    };
+    let input = Paragraph::new(input_text.as_ref())
         .style(match app.input_mode {
-            InputMode::Normal => Style::default(),
-            InputMode::Editing => Style::default().fg(Color::Yellow),
+            InputMode::Normal => theme.input,
+            InputMode::Editing => theme.input_editing,
+            InputMode::Search => theme.input_search,
         })
-        .block(Block::default().borders(Borders::ALL));
+        .block(bordered(theme));
     match app.input_mode {
         InputMode::Normal =>
             // Hide the cursor. `Frame` does this by default, so we don't need to do anything here
             {}
 
-        InputMode::Editing => {
+        InputMode::Editing | InputMode::Search => {
             // Make the cursor visible and ask tui-rs to put it at the specified coordinates after rendering
             f.set_cursor(
                 // Put cursor past the end of the input text
-                chunks[2].x + app.input.width() as u16 + 1,
+                chunks[2].x + input_text.width() as u16 + 1,
                 // Move one line down, from the border to the input line
                 chunks[2].y + 1,
             )
@@ -109,110 +133,455 @@ pub fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
 
     f.render_widget(help_message, bottom_layout[0]);
 
-    let connected_spans: Vec<Span> = vec![
-        Span::styled("Connected", Style::default().add_modifier(Modifier::BOLD)),
-        // Span::raw(" to exit, "),
-        // Span::styled("e", Style::default().add_modifier(Modifier::BOLD)),
-        // Span::raw(" to start typing."),
-    ];
-
-    match app.tab_index {
-        0 => {
-            if app.message_list.items.len() > app.message_spans.items.len() {
-                let messages: Vec<ListItem> = app
+    let state_style = match app.connection_state {
+        ConnectionState::Connected => theme.connected,
+        ConnectionState::Connecting | ConnectionState::Reconnecting(..) => theme.connecting,
+        ConnectionState::Failed(_) => theme.disconnected,
+    };
+    let status = &app.send_status;
+    let mut status_spans = vec![];
+    if status.queued > 0 || status.pending > 0 {
+        status_spans.push(Span::styled(
+            format!("{} queued, {} pending  ", status.queued, status.pending),
+            theme.status,
+        ));
+    }
+    if !status.failed.is_empty() {
+        status_spans.push(Span::styled(
+            format!("{} failed (R retry, D discard)  ", status.failed.len()),
+            theme.status_error,
+        ));
+    }
+    if app.show_filtered {
+        status_spans.push(Span::styled("Showing filtered (h to hide)  ", theme.status));
+    }
+    if let Some(latency) = app.latency {
+        status_spans.push(Span::styled(
+            format!("{}ms  ", latency.as_millis()),
+            theme.status,
+        ));
+    }
+    status_spans.push(Span::styled(app.connection_state.to_string(), state_style));
+    let connection = Paragraph::new(Spans::from(status_spans)).alignment(Alignment::Right);
+    f.render_widget(connection, bottom_layout[1]);
+
+    match app.tab() {
+        Tab::Chat => {
+            if app.spans_outdated() {
+                let width = chunks[0].width;
+                let rows = (0..app.message_list.items.len())
+                    .filter(|&i| !app.hides(&app.message_list.items[i]))
+                    .collect();
+                let (messages, emotes): (Vec<ListItem>, Vec<Vec<EmoteCell>>) = app
                     .message_list
                     .items
                     .iter()
-                    .map(|i| {
-                        let mut lines = vec![];
-
-                        let parsed_output: JSON_Result<ParsedMessage>;
-                        parsed_output = parse_message(&i.0.as_str()[4..]);
-                        let parsed_message = parsed_output.unwrap();
+                    .filter(|line| !app.hides(line))
+                    .map(|line| {
+                        let parsed_message = match line {
+                            ChatLine::Event(Event::Msg(parsed_message)) => parsed_message,
+                            ChatLine::Error(reason) => {
+                                let item = ListItem::new(Spans::from(Span::styled(
+                                    format!("* {}", reason),
+                                    theme.error,
+                                )));
+                                return (item, vec![]);
+                            }
+                            ChatLine::Local(local) => {
+                                let (mut lines, emotes) = chat_message(&local.message, width, app);
+                                if let Some(query) =
+                                    app.search.as_ref().filter(|q| q.matches(&local.message))
+                                {
+                                    query.highlight(&local.message, &mut lines, theme.search_match);
+                                }
+                                let style = match &local.failed {
+                                    Some(reason) => {
+                                        lines.push(Spans::from(Span::styled(
+                                            format!("  Not sent: {}", reason),
+                                            theme.error,
+                                        )));
+                                        theme.muted
+                                    }
+                                    None => theme.pending,
+                                };
+                                return (ListItem::new(lines).style(style), emotes);
+                            }
+                            ChatLine::Separator(timestamp) => {
+                                let label = format!(
+                                    " Previous session, until {} {} UTC ",
+                                    utils::format_date(*timestamp),
+                                    utils::format_time(*timestamp)
+                                );
+                                let width = (width as usize).saturating_sub(2);
+                                let side = "─".repeat(width.saturating_sub(label.len()) / 2);
+                                let item = ListItem::new(Spans::from(Span::styled(
+                                    format!("{}{}{}", side, label, side),
+                                    theme.muted,
+                                )));
+                                return (item, vec![]);
+                            }
+                            ChatLine::Filtered(message, FilterAction::Collapse)
+                                if !app.show_filtered =>
+                            {
+                                let item = ListItem::new(Spans::from(Span::styled(
+                                    format!("* Message from {} hidden (h to show)", message.nick),
+                                    theme.muted,
+                                )));
+                                return (item, vec![]);
+                            }
+                            ChatLine::Filtered(message, _) => {
+                                let (lines, emotes) = chat_message(message, width, app);
+                                return (ListItem::new(lines).style(theme.muted), emotes);
+                            }
+                            ChatLine::Info(info) => {
+                                let item = ListItem::new(Spans::from(Span::styled(
+                                    format!("* {}", info),
+                                    theme.info,
+                                )));
+                                return (item, vec![]);
+                            }
+                            ChatLine::Event(event) => {
+                                let item = format_banner(event, width, theme)
+                                    .unwrap_or_else(|| ListItem::new(Spans::default()));
+                                return (item, vec![]);
+                            }
+                        };
 
-                        let mut list_style: Style = Style::default();
-
-                        if parsed_message.nick == "Keah" {
-                            list_style = list_style.bg(Color::Rgb(25, 25, 25))
+                        let mut list_style =
+                            app.highlighter.style(parsed_message).unwrap_or_default();
+                        if parsed_message.data.starts_with('>') {
+                            list_style = list_style.patch(theme.greentext)
                         }
-
-                        if parsed_message.data.to_lowercase().contains("keah")
-                            && parsed_message.nick != "Keah"
+                        if app.config.strike_moderated
+                            && app.moderated.contains(&parsed_message.nick.to_lowercase())
                         {
-                            list_style = list_style.fg(Color::Blue).add_modifier(Modifier::BOLD)
-                        }
-                        if parsed_message.data.starts_with(">") {
-                            list_style = list_style.fg(Color::LightGreen)
+                            list_style = list_style.add_modifier(Modifier::CROSSED_OUT)
                         }
 
-                        let formatted_message: Vec<Spans> =
-                            format_message(parsed_message, chunks[0].width);
-                        for line in formatted_message {
-                            lines.push(line)
+                        let (mut lines, emotes) = chat_message(parsed_message, width, app);
+                        if let Some(query) =
+                            app.search.as_ref().filter(|q| q.matches(parsed_message))
+                        {
+                            query.highlight(parsed_message, &mut lines, theme.search_match);
                         }
 
-                        ListItem::new(lines).style(list_style)
+                        (ListItem::new(lines).style(list_style), emotes)
                     })
-                    .collect();
-                app.message_spans.items = messages.to_owned();
+                    .unzip();
+                app.message_spans.items = messages;
+                app.message_rows = rows;
+                app.spans_built = app.message_list.items.len();
+                app.chat_layout.emotes = emotes;
             }
 
-            // Create a List from all messages and manage highlighting based on state
-            let messages = List::new(app.message_spans.items.to_owned())
-                .block(Block::default().borders(Borders::ALL).title("Messages"))
-                .highlight_style(match app.input_mode {
-                    InputMode::Normal => Style::default(),
-                    InputMode::Editing => Style::default(),
-                })
-                .highlight_symbol(match app.input_mode {
-                    InputMode::Normal => "",
-                    InputMode::Editing => "",
-                });
-            f.render_stateful_widget(messages, chunks[1], &mut app.message_list.state);
+            // Only the messages that fit are handed to the list, so what ends
+            // up on screen is known to the emote images.
+            let block = bordered(theme).title("Messages");
+            let area = block.inner(chunks[1]);
+            let heights: Vec<usize> = app
+                .message_spans
+                .items
+                .iter()
+                .map(ListItem::height)
+                .collect();
+            let selected = app.selected_row();
+            let (start, end) = visible_range(
+                &heights,
+                selected,
+                app.chat_layout.start,
+                area.height as usize,
+            );
+            let highlight_symbol = if app.search.is_some() || app.hold_scroll {
+                "> "
+            } else {
+                ""
+            };
+
+            let mut state = ListState::default();
+            state.select(selected.map(|selected| selected.saturating_sub(start)));
+            let messages = List::new(app.message_spans.items[start..end].to_vec())
+                .block(block)
+                .highlight_symbol(highlight_symbol);
+            f.render_stateful_widget(messages, chunks[1], &mut state);
+
+            let layout = &mut app.chat_layout;
+            layout.area = area;
+            layout.start = start;
+            layout.end = end;
+            // The list shifts every line over when something is selected.
+            layout.indent = match selected {
+                Some(_) => highlight_symbol.width() as u16,
+                None => 0,
+            };
+            layout.heights = heights;
         }
-        1 => {
+        Tab::Users => {
             let users: Vec<ListItem> = app
                 .user_list
                 .items
                 .iter()
                 .map(|i| {
-                    let mut lines = vec![];
-
-                    lines.push(format_user(i));
-                    ListItem::new(lines)
+                    ListItem::new(vec![format_user(
+                        i,
+                        app.users.connections(&i.nick),
+                        &app.flairs,
+                        theme,
+                    )])
                 })
                 .collect();
 
+            let title = format!(
+                "Users ({} users, {} connections)",
+                app.users.len(),
+                app.users.connectioncount
+            );
             let user_items = List::new(users)
-                .block(Block::default().borders(Borders::ALL).title("Users"))
-                .highlight_style(
-                    Style::default()
-                        .bg(Color::LightGreen)
-                        .add_modifier(Modifier::BOLD),
-                )
+                .block(bordered(theme).title(title))
+                .highlight_style(theme.selection)
                 .highlight_symbol(">> ");
 
             f.render_stateful_widget(user_items, chunks[1], &mut app.user_list.state);
         }
-        _ => unreachable!(),
+        Tab::Mentions => {
+            let mentions = &mut app.mentions;
+            let messages: Vec<ListItem> = mentions
+                .messages
+                .items
+                .iter()
+                .map(|message| {
+                    let mut lines = format_message(
+                        message.to_owned(),
+                        chunks[0].width,
+                        &app.flairs,
+                        None,
+                        theme,
+                    );
+                    if let Some(first) = lines.first_mut() {
+                        let marker = if message.timestamp > mentions.new_after {
+                            Span::styled("● ", theme.unread)
+                        } else {
+                            Span::raw("  ")
+                        };
+                        first.0.insert(0, marker);
+                        first.0.insert(
+                            1,
+                            Span::styled(
+                                format!(
+                                    "[{} {}] ",
+                                    utils::format_date(message.timestamp),
+                                    utils::format_time(message.timestamp)
+                                ),
+                                theme.timestamp,
+                            ),
+                        );
+                    }
+                    ListItem::new(lines)
+                })
+                .collect();
+
+            let messages = List::new(messages)
+                .block(bordered(theme).title("Mentions (Enter to find in chat)"))
+                .highlight_symbol("> ");
+            f.render_stateful_widget(messages, chunks[1], &mut mentions.messages.state);
+        }
+        Tab::Whisper(i) => {
+            let own_nick = app.config.username.to_owned().unwrap_or_default();
+            let own_style = app.highlighter.own_style();
+            let conversation = &mut app.conversations[i];
+            let mut messages: Vec<ListItem> = conversation
+                .messages
+                .items
+                .iter()
+                .map(|whisper| {
+                    let message = ParsedMessage {
+                        nick: whisper.nick.to_owned(),
+                        features: vec![],
+                        timestamp: whisper.timestamp,
+                        data: whisper.data.to_owned(),
+                    };
+                    let mut style = Style::default();
+                    if whisper.nick == own_nick {
+                        style = own_style;
+                    }
+                    ListItem::new(format_message(
+                        message,
+                        chunks[0].width,
+                        &app.flairs,
+                        None,
+                        theme,
+                    ))
+                    .style(style)
+                })
+                .collect();
+            messages.extend(conversation.pending.iter().map(|local| {
+                let mut lines = format_message(
+                    local.message.to_owned(),
+                    chunks[0].width,
+                    &app.flairs,
+                    None,
+                    theme,
+                );
+                let style = match &local.failed {
+                    Some(reason) => {
+                        lines.push(Spans::from(Span::styled(
+                            format!("  Not sent: {}", reason),
+                            theme.error,
+                        )));
+                        theme.muted
+                    }
+                    None => theme.pending,
+                };
+                ListItem::new(lines).style(style)
+            }));
+
+            // Pending whispers come after the last one, keep them in view when that's selected.
+            let committed = conversation.messages.items.len();
+            let selected = conversation.messages.state.selected();
+            if selected.is_none_or(|i| i + 1 >= committed) {
+                conversation
+                    .messages
+                    .state
+                    .select(messages.len().checked_sub(1));
+            }
+            let messages = List::new(messages).block(
+                bordered(theme).title(format!("Whispers with {} (x to close)", conversation.nick)),
+            );
+            f.render_stateful_widget(messages, chunks[1], &mut conversation.messages.state);
+            conversation.messages.state.select(selected);
+        }
+        Tab::Search => {
+            let log_search = match &mut app.log_search {
+                Some(log_search) => log_search,
+                None => return,
+            };
+            let messages: Vec<ListItem> = log_search
+                .results
+                .items
+                .iter()
+                .map(|message| {
+                    let mut lines = format_message(
+                        message.to_owned(),
+                        chunks[0].width,
+                        &app.flairs,
+                        None,
+                        theme,
+                    );
+                    log_search
+                        .query
+                        .highlight(message, &mut lines, theme.search_match);
+                    if let Some(first) = lines.first_mut() {
+                        first.0.insert(
+                            0,
+                            Span::styled(
+                                format!(
+                                    "[{} {}] ",
+                                    utils::format_date(message.timestamp),
+                                    utils::format_time(message.timestamp)
+                                ),
+                                theme.timestamp,
+                            ),
+                        );
+                    }
+                    ListItem::new(lines)
+                })
+                .collect();
+
+            let title = match log_search.pending {
+                Some(_) => format!("Searching the log for {}…", log_search.query.text),
+                None => format!(
+                    "Search: {} ({} results, x to close)",
+                    log_search.query.text,
+                    log_search.results.items.len()
+                ),
+            };
+            let messages = List::new(messages)
+                .block(bordered(theme).title(title))
+                .highlight_symbol("> ");
+            f.render_stateful_widget(messages, chunks[1], &mut log_search.results.state);
+        }
     }
 
-    if app.config.autocomplete {
-        if app.input.len() > 0 {
-            let area = suggestion_rect(f.size());
-            let block = Block::default()/* .borders(Borders::ALL) */;
+    if app.config.autocomplete && !app.input.is_empty() {
+        let area = suggestion_rect(f.size());
+        let block = Block::default()/* .borders(Borders::ALL) */;
 
-            if app.autocomplete.suggestions.len() > 0 {
-                let suggestions =
-                    Paragraph::new(app.autocomplete.suggestions.join(" ").to_string())
-                        .style(Style::default().fg(Color::Blue))
-                        .block(block);
+        if !app.autocomplete.suggestions.is_empty() {
+            let suggestions = Paragraph::new(app.autocomplete.suggestions.join(" "))
+                .style(theme.popup)
+                .block(block);
 
-                f.render_widget(Clear, area); //this clears out the background
-                f.render_widget(suggestions, area);
-            }
+            f.render_widget(Clear, area); //this clears out the background
+            f.render_widget(suggestions, area);
+        }
+    }
+}
+
+/// The lines of a chat message, and where the images of its emotes go.
+fn chat_message(
+    msg: &ParsedMessage,
+    width: u16,
+    app: &App,
+) -> (Vec<Spans<'static>>, Vec<EmoteCell>) {
+    let lines = utils::wrap_message(msg, utils::message_width(msg, width), Some(&app.emotes));
+    let emotes = utils::emote_cells(msg, &lines, &app.emotes)
+        .into_iter()
+        .map(|(line, column, emote)| (line, column, emote.clone()))
+        .collect();
+    (
+        utils::format_lines(msg, &lines, &app.flairs, &app.theme),
+        emotes,
+    )
+}
+
+/// The messages a list `height` rows high shows, from the first to the one
+/// after the last. Like tui's own list it scrolls from where it was at
+/// `offset` just far enough to show the selected message.
+fn visible_range(
+    heights: &[usize],
+    selected: Option<usize>,
+    offset: usize,
+    height: usize,
+) -> (usize, usize) {
+    if heights.is_empty() {
+        return (0, 0);
+    }
+    // Nothing selected starts at the top.
+    let offset = selected.map_or(0, |_| offset.min(heights.len() - 1));
+    let mut start = offset;
+    let mut end = offset;
+    let mut used = 0;
+    for item in &heights[offset..] {
+        if used + item > height {
+            break;
+        }
+        used += item;
+        end += 1;
+    }
+
+    let selected = selected.unwrap_or(0).min(heights.len() - 1);
+    while selected >= end {
+        used += heights[end];
+        end += 1;
+        while used > height {
+            used -= heights[start];
+            start += 1;
         }
     }
+    while selected < start {
+        start -= 1;
+        used += heights[start];
+        while used > height {
+            end -= 1;
+            used -= heights[end];
+        }
+    }
+    (start, end)
+}
+
+fn bordered(theme: &Theme) -> Block<'static> {
+    Block::default()
+        .borders(Borders::ALL)
+        .border_style(theme.border)
 }
 
 fn suggestion_rect(r: Rect) -> Rect {
@@ -1,81 +1,38 @@
-use std::fs;
-
-use crate::types::{Autocomplete, Emote, ParsedMessage, User, Users};
+use crate::emotes::{EmoteInfo, Emotes};
+use crate::flairs::Flairs;
+use crate::protocol::{Event, ParsedMessage, User};
+use crate::theme::Theme;
+use crate::types::{Autocomplete, Emote};
 use serde_json::Result as JSON_Result;
 use std::borrow::Cow::{Borrowed, Owned};
 use textwrap::Options;
 use tui::{
-    style::{Color, Modifier, Style},
+    style::Style,
     text::{Span, Spans},
+    widgets::ListItem,
 };
+use unicode_width::UnicodeWidthStr;
 
 use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use hyper::body;
 use hyper::Client;
 use hyper_tls::HttpsConnector;
 
-use viuer::Config;
-
-pub fn get_emotenames() -> Vec<String> {
-    let paths = fs::read_dir("./src/emotes_resized/").unwrap();
-    let mut names: Vec<String> = vec![];
-    for path in paths {
-        let pathstr: String = path
-            .unwrap()
-            .path()
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
-        names.push(format!("{}", &pathstr[0..pathstr.len() - 4],));
-    }
-
-    names
-}
-
-// pub async fn get_emotenames() -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
-//     let https = HttpsConnector::new();
-//     let client = Client::builder().build::<_, hyper::Body>(https);
-
-//     // Parse an `http::Uri`...
-//     let uri = "https://cdn.destiny.gg/emotes/emotes.json".parse()?;
-
-//     // Await the response...
-//     let resp = client.get(uri).await?;
-
-//     // println!("Response: {}", resp.status());
-
-//     let bytes = body::to_bytes(resp.into_body()).await?;
-//     // let json_str: &str = str::from_utf8(&bytes).unwrap();
-//     // println!("Hey: {}", json_str);
-
-//     let emote_json: Vec<Emote> = serde_json::from_slice(&bytes)?;
-
-//     let mut names: Vec<String> = Vec::new();
-
-//     for emote in emote_json {
-//         names.push(emote.name.to_owned())
-//     }
-//     Ok(names)
-// }
-
-pub fn parse_message(msg: &str) -> JSON_Result<ParsedMessage> {
-    let json: ParsedMessage = serde_json::from_str(msg)?;
-    return Ok(json);
-}
-
+#[allow(dead_code)]
 pub fn parse_emote_json(json_data: &str) -> JSON_Result<Emote> {
     let json: Emote = serde_json::from_str(json_data)?;
-    return Ok(json);
+    Ok(json)
 }
 
-pub async fn get_history() -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+pub async fn get_history(
+    url: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
     let https = HttpsConnector::new();
     let client = Client::builder().build::<_, hyper::Body>(https);
 
-    let uri = "https://www.destiny.gg/api/chat/history".parse()?;
+    let uri = url.parse()?;
 
     let resp = client.get(uri).await?;
 
@@ -85,33 +42,25 @@ pub async fn get_history() -> Result<Vec<String>, Box<dyn std::error::Error + Se
     Ok(history_json)
 }
 
-pub fn parse_users(msg: &str) -> JSON_Result<Users> {
-    let json: Users = serde_json::from_str(msg)?;
-    return Ok(json);
+/// The lines of a message. With `emotes`, room is left for the emote images.
+pub fn format_message(
+    msg: ParsedMessage,
+    width: u16,
+    flairs: &Flairs,
+    emotes: Option<&Emotes>,
+    theme: &Theme,
+) -> Vec<Spans<'static>> {
+    let lines: Vec<String> = wrap_message(&msg, message_width(&msg, width), emotes);
+    format_lines(&msg, &lines, flairs, theme)
 }
 
-pub fn print_emote(voffset: u16, xoffset: u16, emote_name: &str) {
-    let conf = Config {
-        width: Some(0),
-        height: Some(0),
-        absolute_offset: true,
-        ..Default::default()
-    };
-
-    viuer::print_from_file(
-        format!("./src/emotes_resized/{}.png", emote_name),
-        &Config {
-            y: 2 + voffset as i16,
-            x: xoffset + 2,
-            ..conf
-        },
-    )
-    .expect("Imge printing failed.");
-}
-
-pub fn format_message(msg: ParsedMessage, width: u16) -> Vec<Spans<'static>> {
-    let lines: Vec<String> = wrap_message(&msg, width - 5 - msg.nick.len() as u16).to_vec();
-
+/// Styles the lines `wrap_message` made of `msg`, with the nick in front.
+pub fn format_lines(
+    msg: &ParsedMessage,
+    lines: &[String],
+    flairs: &Flairs,
+    theme: &Theme,
+) -> Vec<Spans<'static>> {
     let mut message_lines: Vec<Spans> = Vec::new();
 
     for (i, line) in lines.iter().enumerate() {
@@ -121,23 +70,22 @@ pub fn format_message(msg: ParsedMessage, width: u16) -> Vec<Spans<'static>> {
             words.push(
                 Span::styled(
                     format!("<{}> ", msg.nick),
-                    Style::default()
-                        .fg(Color::from_tier(get_tier(msg.features.to_owned())))
-                        .add_modifier(Modifier::BOLD),
+                    nick_style(&msg.features, flairs, theme),
                 )
                 .to_owned(),
             )
         }
 
-        for word in line.split(" ") {
+        for word in line.split(' ') {
             let mut word_style: Style = Style::default();
             if word.contains("http") {
-                word_style = word_style.add_modifier(Modifier::UNDERLINED);
-                if msg.data.to_lowercase().contains("nsfl") {
-                    word_style = word_style.fg(Color::Yellow)
+                word_style = if msg.data.to_lowercase().contains("nsfl") {
+                    theme.nsfl
                 } else if msg.data.to_lowercase().contains("nsfw") {
-                    word_style = word_style.fg(Color::Red)
-                }
+                    theme.nsfw
+                } else {
+                    theme.link
+                };
             }
             words.push(Span::styled(word.to_owned(), word_style));
             words.push(Span::styled(" ", Style::default()))
@@ -149,17 +97,154 @@ pub fn format_message(msg: ParsedMessage, width: u16) -> Vec<Spans<'static>> {
     message_lines
 }
 
-pub fn format_user(user: &User) -> Spans<'static> {
-    Spans::from(Span::styled(
+/// Text of the banner for a broadcast, subscription or donation.
+pub fn banner(event: &Event) -> Option<String> {
+    let (text, message) = match event {
+        Event::Broadcast(broadcast) => (broadcast.data.to_owned(), ""),
+        Event::Subscription(sub) => {
+            let mut text = format!(
+                "{} subscribed at {}",
+                sub.nick,
+                tier_label(sub.tier, &sub.tierlabel)
+            );
+            if sub.streak > 1 {
+                text.push_str(&format!(", {} months in a row", sub.streak));
+            }
+            (text, sub.data.as_str())
+        }
+        Event::GiftSub(gift) => (
+            format!(
+                "{} gifted {} a {} subscription",
+                gift.nick,
+                gift.giftee,
+                tier_label(gift.tier, &gift.tierlabel)
+            ),
+            gift.data.as_str(),
+        ),
+        Event::MassGift(gift) => (
+            format!(
+                "{} gifted {} {} subscriptions",
+                gift.nick,
+                gift.quantity,
+                tier_label(gift.tier, &gift.tierlabel)
+            ),
+            gift.data.as_str(),
+        ),
+        Event::Donation(donation) => (
+            format!(
+                "{} donated ${}.{:02}",
+                donation.nick,
+                donation.amount / 100,
+                donation.amount % 100
+            ),
+            donation.data.as_str(),
+        ),
+        _ => return None,
+    };
+
+    if message.is_empty() {
+        Some(text)
+    } else {
+        Some(format!("{}: {}", text, message))
+    }
+}
+
+/// The theme's style for the banner of `event`.
+fn banner_style(event: &Event, theme: &Theme) -> Style {
+    match event {
+        Event::Subscription(sub) => theme.tier(sub.tier),
+        Event::GiftSub(gift) => theme.tier(gift.tier),
+        Event::MassGift(gift) => theme.tier(gift.tier),
+        Event::Donation(_) => theme.donation,
+        _ => theme.broadcast,
+    }
+}
+
+fn tier_label(tier: u8, label: &str) -> String {
+    if label.is_empty() {
+        format!("Tier {}", tier)
+    } else {
+        label.to_string()
+    }
+}
+
+/// A banner spanning the whole width of the message list.
+pub fn format_banner(event: &Event, width: u16, theme: &Theme) -> Option<ListItem<'static>> {
+    let text = banner(event)?;
+    let wrap_options = Options::new(width.saturating_sub(4).max(1) as usize)
+        .word_splitter(textwrap::WordSplitter::NoHyphenation);
+    let lines: Vec<Spans> = textwrap::wrap(&text, wrap_options)
+        .iter()
+        .map(|line| Spans::from(format!(" {}", line)))
+        .collect();
+
+    Some(ListItem::new(lines).style(banner_style(event, theme)))
+}
+
+/// The theme's nick style in the color of the nick's flair, if it has one.
+fn nick_style(features: &[String], flairs: &Flairs, theme: &Theme) -> Style {
+    match flairs.color(features) {
+        Some(color) => theme.nick.fg(color),
+        None => theme.nick,
+    }
+}
+
+pub fn format_user(
+    user: &User,
+    connections: Option<u16>,
+    flairs: &Flairs,
+    theme: &Theme,
+) -> Spans<'static> {
+    let mut spans = vec![Span::styled(
         format!("{} ", user.nick),
-        Style::default()
-            .fg(Color::from_tier(get_tier(user.features.to_owned())))
-            .add_modifier(Modifier::BOLD),
-    ))
+        nick_style(&user.features, flairs, theme),
+    )];
+    let labels = flairs.labels(&user.features);
+    if !labels.is_empty() {
+        spans.push(Span::styled(format!("{} ", labels.join(", ")), theme.muted));
+    }
+    if let Some(connections @ 2..) = connections {
+        spans.push(Span::styled(
+            format!("({} connections)", connections),
+            theme.connections,
+        ));
+    }
+    Spans::from(spans)
 }
 
-pub fn wrap_message(msg: &ParsedMessage, width: u16) -> Vec<String> {
-    let cloned_msg: String = msg.data.to_owned();
+/// Width left for the text of a message in a list `width` wide.
+pub fn message_width(msg: &ParsedMessage, width: u16) -> u16 {
+    width
+        .saturating_sub(5)
+        .saturating_sub(msg.nick.width() as u16)
+        .max(1)
+}
+
+/// Emotes shown as images become no-break spaces as wide as their image,
+/// for the image to be drawn over.
+const EMOTE_CELL: char = '\u{a0}';
+
+/// The emote `word` stands for and the columns its image covers, if it's shown
+/// as an image. Until the scaled image has been made the emote stays text.
+fn image_emote<'a>(word: &str, emotes: &'a Emotes) -> Option<(u16, &'a EmoteInfo)> {
+    let emote = emotes.word(word)?;
+    emotes.image(emote)?;
+    Some((emotes.columns(emote)?, emote))
+}
+
+pub fn wrap_message(msg: &ParsedMessage, width: u16, emotes: Option<&Emotes>) -> Vec<String> {
+    let cloned_msg: String = match emotes {
+        Some(emotes) => msg
+            .data
+            .split(' ')
+            .map(|word| match image_emote(word, emotes) {
+                Some((columns, _)) => EMOTE_CELL.to_string().repeat(columns as usize),
+                None => word.to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join(" "),
+        None => msg.data.to_owned(),
+    };
 
     let wrap_options = Options::new(width as usize)
         .break_words(false)
@@ -167,70 +252,85 @@ pub fn wrap_message(msg: &ParsedMessage, width: u16) -> Vec<String> {
         .word_separator(textwrap::WordSeparator::AsciiSpace);
     let cow_lines = textwrap::wrap(&cloned_msg, wrap_options);
 
-    let lines = cow_lines
+    cow_lines
         .iter()
         .map(|line| match line {
             Borrowed(text) => text.to_string(),
             Owned(text) => text.to_string(),
         })
-        .collect::<Vec<String>>();
-
-    lines
-}
-
-pub fn get_users(names: String) -> Users {
-    let users_plain: JSON_Result<Users> = parse_users(&names[5..]);
-    let users: Users = users_plain.unwrap();
-    users
+        .collect::<Vec<String>>()
 }
 
-pub fn get_tier(features: Vec<String>) -> i8 {
-    let mut tiers: Vec<u8> = vec![0];
-
-    for feature in features {
-        let temp_f: &str = &feature;
-        match temp_f {
-            "subscriber" => tiers.push(1),
-            "flair3" => tiers.push(2),
-            "flair1" => tiers.push(3),
-            "flair8" => tiers.push(4),
-            "admin" => tiers.push(6),
-            _ => (),
+/// Where the emote images go in the lines `wrap_message` made of `msg`: the
+/// line, the column in it counting the nick in front of the first, and the
+/// emote.
+pub fn emote_cells<'a>(
+    msg: &ParsedMessage,
+    lines: &[String],
+    emotes: &'a Emotes,
+) -> Vec<(usize, u16, &'a EmoteInfo)> {
+    let mut shown = msg
+        .data
+        .split(' ')
+        .filter_map(|word| image_emote(word, emotes))
+        .peekable();
+
+    let mut cells = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        let mut column = if i == 0 {
+            format!("<{}> ", msg.nick).width() as u16
+        } else {
+            0
+        };
+        for word in line.split(' ') {
+            if let Some(&(columns, emote)) = shown.peek() {
+                if word.chars().count() == columns as usize && word.chars().all(|c| c == EMOTE_CELL)
+                {
+                    cells.push((i, column, emote));
+                    shown.next();
+                }
+            }
+            column += word.width() as u16 + 1;
         }
     }
-    *tiers.iter().max().unwrap() as i8
+    cells
 }
 
 pub fn get_suggestions(
     input: String,
     mut autocomplete: Autocomplete,
-    users: Users,
-    emotes: Vec<String>,
+    users: &[User],
+    emotes: &[String],
+    commands: &[&str],
 ) -> Autocomplete {
-    let mut last_word: String = String::new();
-
-    match input.split(' ').last() {
-        Some(input_last) => last_word = input_last.to_string(),
-        None => last_word = "".to_string(),
-    }
+    let last_word: String = match input.split(' ').next_back() {
+        Some(input_last) => input_last.to_string(),
+        None => "".to_string(),
+    };
 
-    if last_word.len() > 0 {
+    if last_word.starts_with('/') && last_word == input {
+        autocomplete.suggestions = commands
+            .iter()
+            .filter(|command| command.starts_with(&last_word.to_lowercase()))
+            .map(|command| command.to_string())
+            .collect();
+    } else if !last_word.is_empty() {
         let mut names: Vec<String> = Vec::new();
         let mut matching_emotes: Vec<String> = Vec::new();
 
-        for user in users.users.to_owned() {
+        for user in users {
             if user
                 .nick
                 .to_lowercase()
                 .starts_with(&last_word.to_lowercase())
             {
-                names.push(user.nick)
+                names.push(user.nick.to_owned())
             }
         }
 
-        for emote in emotes.to_owned() {
+        for emote in emotes {
             if emote.to_lowercase().starts_with(&last_word.to_lowercase()) {
-                matching_emotes.push(emote)
+                matching_emotes.push(emote.to_owned())
             }
         }
 
@@ -245,20 +345,104 @@ pub fn get_suggestions(
     autocomplete
 }
 
-trait FromTier {
-    fn from_tier(tier: i8) -> Color;
+/// `HH:MM:SS` in UTC for a timestamp in milliseconds.
+pub fn format_time(timestamp: u64) -> String {
+    let seconds = timestamp / 1000;
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// `YYYY-MM-DD` in UTC for a timestamp in milliseconds.
+pub fn format_date(timestamp: u64) -> String {
+    // Howard Hinnant's days to civil date conversion
+    let days = (timestamp / 1000 / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Milliseconds since the epoch, the way the chat server timestamps things.
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// What's wrong with a regex, on one line. The full error draws a caret under
+/// the pattern over several.
+pub fn regex_error(error: &regex::Error) -> String {
+    let message = error.to_string();
+    message
+        .lines()
+        .last()
+        .unwrap_or_default()
+        .trim_start_matches("error: ")
+        .to_string()
 }
 
-impl FromTier for Color {
-    fn from_tier(tier: i8) -> Color {
-        match tier {
-            0 => Color::White,
-            1 => Color::Blue,
-            2 => Color::Green,
-            3 => Color::Cyan,
-            4 => Color::Magenta,
-            6 => Color::Yellow,
-            _ => Color::White,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::CellSize;
+    use std::path::Path;
+
+    fn message(nick: &str, data: &str) -> ParsedMessage {
+        ParsedMessage {
+            nick: nick.to_string(),
+            data: data.to_string(),
+            ..Default::default()
         }
     }
+
+    #[test]
+    fn message_width_never_underflows() {
+        let msg = message("Bob", "hi");
+        assert_eq!(message_width(&msg, 40), 32);
+        assert_eq!(message_width(&msg, 8), 1);
+        assert_eq!(message_width(&msg, 3), 1);
+        assert_eq!(message_width(&message(&"x".repeat(300), "hi"), 20), 1);
+    }
+
+    #[test]
+    fn message_width_counts_columns_not_bytes() {
+        assert_eq!(message_width(&message("Zoë", "hi"), 40), 32);
+        // Wide characters take two columns each.
+        assert_eq!(message_width(&message("日本", "hi"), 40), 31);
+    }
+
+    #[test]
+    fn emotes_stay_text_until_their_image_is_ready() {
+        let cell = CellSize {
+            width: 8,
+            height: 16,
+        };
+        let mut emotes = Emotes::for_tests(&["AYAYA"], Path::new("/cache"), cell);
+        let msg = message("Bob", "hi AYAYA there");
+
+        let lines = wrap_message(&msg, 40, Some(&emotes));
+        assert_eq!(lines, vec!["hi AYAYA there"]);
+        assert!(emote_cells(&msg, &lines, &emotes).is_empty());
+
+        emotes.mark_ready("AYAYA");
+
+        let lines = wrap_message(&msg, 40, Some(&emotes));
+        assert_eq!(lines, vec!["hi \u{a0}\u{a0} there"]);
+        let cells = emote_cells(&msg, &lines, &emotes);
+        assert_eq!(cells.len(), 1);
+        // After "<Bob> hi "
+        assert_eq!((cells[0].0, cells[0].1), (0, 9));
+    }
 }
@@ -0,0 +1,46 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::config;
+use crate::protocol::PrivateMessage;
+
+/// How much of a conversation is loaded back when its tab is opened.
+const HISTORY_LENGTH: usize = 500;
+
+fn conversation_path(nick: &str) -> std::io::Result<PathBuf> {
+    let mut path = config::data_folder("whispers")?;
+    path.push(format!("{}.jsonl", nick.to_lowercase()));
+    Ok(path)
+}
+
+/// The human readable copy of the conversation with `nick`.
+pub fn text_path(nick: &str) -> std::io::Result<PathBuf> {
+    let mut path = config::data_folder("whispers")?;
+    path.push(format!("{}.txt", nick.to_lowercase()));
+    Ok(path)
+}
+
+/// Whispers exchanged with `nick` in earlier sessions, oldest first.
+pub fn load(nick: &str) -> Vec<PrivateMessage> {
+    let contents = match conversation_path(nick).and_then(fs::read_to_string) {
+        Ok(contents) => contents,
+        Err(_) => return vec![],
+    };
+
+    let messages: Vec<PrivateMessage> = contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    let skip = messages.len().saturating_sub(HISTORY_LENGTH);
+    messages.into_iter().skip(skip).collect()
+}
+
+/// Appends a whisper to the conversation with `nick`.
+pub fn save(nick: &str, message: &PrivateMessage) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(conversation_path(nick)?)?;
+    writeln!(file, "{}", serde_json::to_string(message)?)
+}
@@ -0,0 +1,430 @@
+//! Runs the real client in a pseudo terminal against mockdgg and checks what
+//! ends up on screen and what reaches the server.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long anything is waited for before the test fails.
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+const FAST_RECONNECT: &str = "[reconnect]\ninitial_delay_ms = 100\nmax_delay_ms = 200\n";
+
+struct Mock {
+    child: Child,
+    port: u16,
+    /// Frames the client sent, as JSON lines
+    record: PathBuf,
+}
+
+impl Mock {
+    fn start(dir: &Path, script: &[&str], args: &[&str]) -> Mock {
+        let port = free_port();
+        let record = dir.join("record.jsonl");
+        let script_path = dir.join("script.jsonl");
+        let steps: Vec<String> = script
+            .iter()
+            .map(|frame| serde_json::json!({ "delay_ms": 100, "frame": frame }).to_string())
+            .collect();
+        fs::write(&script_path, steps.join("\n")).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_mockdgg"))
+            .arg("--port")
+            .arg(port.to_string())
+            .arg("--record")
+            .arg(&record)
+            .arg("--script")
+            .arg(&script_path)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(started.elapsed() < TIMEOUT, "mockdgg never started");
+            thread::sleep(Duration::from_millis(20));
+        }
+        Mock {
+            child,
+            port,
+            record,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    fn received(&self) -> String {
+        fs::read_to_string(&self.record).unwrap_or_default()
+    }
+}
+
+impl Drop for Mock {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Just enough of a terminal to follow what tui draws: cursor moves, clears
+/// and text. Everything else is skipped.
+struct Screen {
+    rows: Vec<Vec<char>>,
+    row: usize,
+    col: usize,
+    /// Bytes of a character split between two reads
+    partial: Vec<u8>,
+    /// What the screen looked like after each read, for things only shown briefly
+    seen: String,
+}
+
+impl Screen {
+    fn new(rows: u16, cols: u16) -> Screen {
+        Screen {
+            rows: vec![vec![' '; cols as usize]; rows as usize],
+            row: 0,
+            col: 0,
+            partial: vec![],
+            seen: String::new(),
+        }
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        self.partial.extend_from_slice(bytes);
+        let valid = match std::str::from_utf8(&self.partial) {
+            Ok(text) => text.len(),
+            Err(e) => e.valid_up_to(),
+        };
+        let text = String::from_utf8_lossy(&self.partial[..valid]).to_string();
+        self.partial.drain(..valid);
+
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\x1b' => match chars.next() {
+                    Some('[') => {
+                        let mut params = String::new();
+                        for c in chars.by_ref() {
+                            if ('@'..='~').contains(&c) {
+                                self.csi(&params, c);
+                                break;
+                            }
+                            params.push(c);
+                        }
+                    }
+                    // OSC, APC and DCS run until BEL or ST.
+                    Some(']') | Some('_') | Some('P') => {
+                        while let Some(c) = chars.next() {
+                            if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                                break;
+                            }
+                        }
+                    }
+                    _ => {}
+                },
+                '\r' => self.col = 0,
+                '\n' => self.row += 1,
+                c if c.is_control() => {}
+                c => {
+                    if let Some(cell) = self
+                        .rows
+                        .get_mut(self.row)
+                        .and_then(|r| r.get_mut(self.col))
+                    {
+                        *cell = c;
+                    }
+                    self.col += 1;
+                }
+            }
+        }
+        let text = self.text();
+        self.seen.push_str(&text);
+    }
+
+    fn csi(&mut self, params: &str, command: char) {
+        let numbers: Vec<usize> = params
+            .trim_start_matches('?')
+            .split(';')
+            .map(|n| n.parse().unwrap_or(0))
+            .collect();
+        let first = numbers.first().copied().unwrap_or(0);
+        match command {
+            'H' => {
+                self.row = first.max(1) - 1;
+                self.col = numbers.get(1).copied().unwrap_or(1).max(1) - 1;
+            }
+            'C' => self.col += first.max(1),
+            'J' if first == 2 => {
+                for row in &mut self.rows {
+                    row.fill(' ');
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn text(&self) -> String {
+        self.rows
+            .iter()
+            .map(|row| row.iter().collect::<String>() + "\n")
+            .collect()
+    }
+}
+
+/// dggtui running in a pseudo terminal.
+struct Client {
+    child: Child,
+    terminal: File,
+    screen: Arc<Mutex<Screen>>,
+}
+
+impl Client {
+    fn start(dir: &Path, mock: &Mock, config: &str) -> Client {
+        let home = dir.join("home");
+        fs::create_dir_all(home.join(".config/dggtui")).unwrap();
+        fs::write(home.join(".config/dggtui/dggtui.toml"), config).unwrap();
+
+        let size = libc::winsize {
+            ws_row: 40,
+            ws_col: 120,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let (mut master, mut slave) = (0, 0);
+        // SAFETY: openpty only writes the two descriptors.
+        let opened = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            )
+        };
+        assert_eq!(opened, 0, "could not open a pseudo terminal");
+        // SAFETY: both descriptors were just opened and nothing else owns them.
+        let (terminal, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+
+        let child = Command::new(env!("CARGO_BIN_EXE_dggtui"))
+            .arg("--ws-url")
+            .arg(format!("ws://127.0.0.1:{}/ws", mock.port))
+            .arg("--history-url")
+            .arg(mock.url("/api/chat/history"))
+            .arg("--emotes-url")
+            .arg(mock.url("/emotes/emotes.json"))
+            .arg("--flairs-url")
+            .arg(mock.url("/flairs/flairs.json"))
+            .arg("--cdn-url")
+            .arg(mock.url(""))
+            .env("HOME", &home)
+            .env("XDG_CONFIG_HOME", home.join(".config"))
+            .env("XDG_DATA_HOME", home.join(".local/share"))
+            .env("XDG_CACHE_HOME", home.join(".cache"))
+            .env("TERM", "xterm-256color")
+            .stdin(slave.try_clone().unwrap())
+            .stdout(slave.try_clone().unwrap())
+            .stderr(slave)
+            .spawn()
+            .unwrap();
+
+        let screen = Arc::new(Mutex::new(Screen::new(size.ws_row, size.ws_col)));
+        let mut reader = terminal.try_clone().unwrap();
+        let read_into = screen.clone();
+        thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            // Ends with EIO once the client has exited.
+            while let Ok(n @ 1..) = reader.read(&mut buffer) {
+                read_into.lock().unwrap().feed(&buffer[..n]);
+            }
+        });
+
+        Client {
+            child,
+            terminal,
+            screen,
+        }
+    }
+
+    fn screen(&self) -> String {
+        self.screen.lock().unwrap().text()
+    }
+
+    /// Waits for `text` to be on screen.
+    fn wait_for_screen(&self, text: &str) {
+        wait_until(
+            || self.screen().contains(text),
+            || {
+                format!(
+                    "{:?} never showed up, the screen has:\n{}",
+                    text,
+                    self.screen()
+                )
+            },
+        );
+    }
+
+    /// Waits for `text` to have been on screen at some point, even if it's
+    /// gone again.
+    fn wait_for_seen(&self, text: &str) {
+        wait_until(
+            || self.screen.lock().unwrap().seen.contains(text),
+            || {
+                format!(
+                    "{:?} never showed up, the screen has:\n{}",
+                    text,
+                    self.screen()
+                )
+            },
+        );
+    }
+
+    fn type_keys(&mut self, keys: &str) {
+        for key in keys.bytes() {
+            self.terminal.write_all(&[key]).unwrap();
+            self.terminal.flush().unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Types a line into the input box and sends it.
+    fn send(&mut self, line: &str) {
+        self.type_keys("e");
+        self.type_keys(line);
+        self.type_keys("\r");
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A fresh folder for one test.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("dggtui-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn wait_until(mut done: impl FnMut() -> bool, failure: impl Fn() -> String) {
+    let started = Instant::now();
+    while !done() {
+        assert!(started.elapsed() < TIMEOUT, "{}", failure());
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn connects_and_shows_chat() {
+    let dir = test_dir("connect");
+    let mock = Mock::start(
+        &dir,
+        &[
+            r#"NAMES {"connectioncount":1,"users":[{"nick":"Bob","features":[]}]}"#,
+            r#"MSG {"nick":"Bob","features":[],"timestamp":1650000000000,"data":"hello from the test"}"#,
+        ],
+        &[],
+    );
+    let client = Client::start(&dir, &mock, "");
+
+    client.wait_for_screen("Connected");
+    client.wait_for_screen("history message 3");
+    client.wait_for_screen("hello from the test");
+}
+
+#[test]
+fn sends_messages() {
+    let dir = test_dir("send");
+    let mock = Mock::start(&dir, &[], &["--nick", "tester"]);
+    // The client learns its nick from the server.
+    let mut client = Client::start(&dir, &mock, "");
+    client.wait_for_screen("Connected");
+
+    client.send("hi there");
+
+    wait_until(
+        || mock.received().contains(r#"MSG {\"data\":\"hi there\"}"#),
+        || format!("the server got:\n{}", mock.received()),
+    );
+    client.wait_for_screen("<tester> hi there");
+    // The echo replaces the pending line instead of showing up next to it.
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.screen().matches("hi there").count(), 1);
+}
+
+#[test]
+fn reconnects_when_refused() {
+    let dir = test_dir("reconnect");
+    let mock = Mock::start(
+        &dir,
+        &[r#"MSG {"nick":"Bob","features":[],"timestamp":1650000000000,"data":"made it"}"#],
+        &["--reject", "2"],
+    );
+    let client = Client::start(&dir, &mock, FAST_RECONNECT);
+
+    client.wait_for_seen("Reconnecting (attempt 1)");
+    client.wait_for_seen("Reconnecting (attempt 2)");
+    client.wait_for_screen("Connected");
+    client.wait_for_screen("made it");
+}
+
+#[test]
+fn shows_server_errors() {
+    let dir = test_dir("error");
+    let mock = Mock::start(&dir, &[], &["--reply-err", "muted"]);
+    let mut client = Client::start(&dir, &mock, "");
+    client.wait_for_screen("Connected");
+
+    client.send("can anyone hear me");
+
+    wait_until(
+        || mock.received().contains("can anyone hear me"),
+        || format!("the server got:\n{}", mock.received()),
+    );
+    client.wait_for_screen("You are muted");
+}
+
+#[test]
+fn keeps_whispers_with_logging_off() {
+    let dir = test_dir("whispers");
+    let mock = Mock::start(
+        &dir,
+        &[r#"PRIVMSG {"messageid":1,"nick":"Bob","timestamp":1650000000000,"data":"psst"}"#],
+        &[],
+    );
+    let client = Client::start(&dir, &mock, "[log]\nenabled = false\n");
+    client.wait_for_screen("Connected");
+
+    let data = dir.join("home/.local/share/dggtui");
+    let conversation = data.join("whispers/bob.jsonl");
+    wait_until(
+        || {
+            fs::read_to_string(&conversation)
+                .unwrap_or_default()
+                .contains("psst")
+        },
+        || format!("{:?} never got the whisper", conversation),
+    );
+    assert!(!data.join("logs").exists());
+    assert!(!data.join("whispers/bob.txt").exists());
+}